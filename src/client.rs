@@ -1,68 +1,377 @@
-use std::sync::{Arc};
+use std::future::Future;
+use std::sync::{Arc, Weak};
 use std::time::{Duration, Instant};
-use futures_util::stream::{SplitSink, SplitStream};
-use futures_util::{SinkExt, StreamExt};
 use paris::{error, info};
 use prost::Message;
 use rand_core::RngCore;
-use tokio::net::TcpStream;
-use tokio::sync::Mutex;
-use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
-use tokio_tungstenite::tungstenite::client::IntoClientRequest;
-use tokio_tungstenite::tungstenite::{ClientRequestBuilder};
+use tokio::sync::{broadcast, Mutex};
+use tokio::task::JoinHandle;
+use crate::connection::{ConnectionState, DisconnectAction};
 use crate::constant;
 use crate::device::Device;
+use crate::events::{Event, EventBus};
+use crate::identity::TrustPolicy;
 use crate::proto::whatsapp::handshake_message::{ClientFinish, ClientHello};
 use crate::proto::whatsapp::HandshakeMessage;
-use crate::request::InfoQuery;
+use crate::request::{InfoQuery, IqError, ResponseWaiters};
 use crate::socket::frame_socket::{FrameSocket, FrameSocketState};
 use crate::socket::noise_socket::NoiseSocket;
+use crate::socket::transport::{Transport, TransportError, TransportReceiver, TransportSender};
+use crate::socket::websocket::WebSocketTransport;
+use crate::store::device::{DeviceStore, FileDeviceStore};
+use crate::store::identity::{FileIdentityStore, IdentityStore};
+use crate::store::pre_key::{FilePreKeyStore, PreKeyStore};
+use crate::store::sender_key::{FileSenderKeyStore, SenderKeyStore};
+use crate::store::session::{FileSessionStore, SessionStore};
+use crate::store::StoreError;
 use crate::types::jid::JID;
-use crate::utils::decoder::{BinaryDecoder, Node};
+use crate::utils::decoder::BinaryDecoder;
 use crate::utils::gcm;
-use crate::utils::noise_handshake::NoiseHandShake;
+use crate::utils::pair_code::PhoneLinking;
+use crate::utils::noise_handshake::{verify_server_cert, HandshakeError, NoiseHandShake};
+
+pub struct Config {
+    pub transport: Box<dyn Transport>,
+    pub store: Box<dyn DeviceStore>,
+    pub session_store: Box<dyn SessionStore>,
+    pub pre_key_store: Box<dyn PreKeyStore>,
+    pub sender_key_store: Box<dyn SenderKeyStore>,
+    pub identity_store: Box<dyn IdentityStore>,
+    pub trust_policy: TrustPolicy,
+    pub compression_threshold: Option<usize>,
+    pub max_decompressed_size: usize,
+    pub qr_first_timeout: Duration,
+    pub qr_timeout: Duration,
+    pub auto_reconnect: bool,
+    pub reconnect_base_delay: Duration,
+    pub reconnect_max_delay: Duration,
+    pub handshake_timeout: Duration,
+    pub keepalive_interval_min: Duration,
+    pub keepalive_interval_max: Duration,
+    pub keepalive_response_deadline: Duration,
+    pub keepalive_max_fail_time: Duration,
+    pub cert_root_key: [u8; 32]
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            transport: Box::new(WebSocketTransport::default()),
+            store: Box::new(FileDeviceStore::new(constant::DEVICE_STORE_PATH)),
+            session_store: Box::new(FileSessionStore::new(constant::SESSION_STORE_PATH)),
+            pre_key_store: Box::new(FilePreKeyStore::new(constant::PRE_KEY_STORE_PATH)),
+            sender_key_store: Box::new(FileSenderKeyStore::new(constant::SENDER_KEY_STORE_PATH)),
+            identity_store: Box::new(FileIdentityStore::new(constant::IDENTITY_STORE_PATH)),
+            trust_policy: TrustPolicy::TrustOnFirstUse,
+            compression_threshold: None,
+            max_decompressed_size: constant::MAX_DECOMPRESSED_SIZE,
+            qr_first_timeout: constant::QR_FIRST_TIMEOUT,
+            qr_timeout: constant::QR_TIMEOUT,
+            auto_reconnect: true,
+            reconnect_base_delay: constant::RECONNECT_BASE_DELAY,
+            reconnect_max_delay: constant::RECONNECT_MAX_DELAY,
+            handshake_timeout: constant::HANDSHAKE_TIMEOUT,
+            keepalive_interval_min: constant::KEEPALIVE_INTERVAL_MIN,
+            keepalive_interval_max: constant::KEEPALIVE_INTERVAL_MAX,
+            keepalive_response_deadline: constant::KEEPALIVE_RESPONSE_DEADLINE,
+            keepalive_max_fail_time: constant::KEEPALIVE_MAX_FAIL_TIME,
+            cert_root_key: constant::CERT_ROOT_KEY
+        }
+    }
+}
 
 pub struct Client {
-    pub write: SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, tokio_tungstenite::tungstenite::Message>,
+    pub this: Weak<Mutex<Client>>,
+    pub transport: Box<dyn Transport>,
+    pub store: Box<dyn DeviceStore>,
+    pub session_store: Box<dyn SessionStore>,
+    pub pre_key_store: Box<dyn PreKeyStore>,
+    pub sender_key_store: Box<dyn SenderKeyStore>,
+    pub identity_store: Box<dyn IdentityStore>,
+    pub trust_policy: TrustPolicy,
+    pub write: Option<Box<dyn TransportSender>>,
     pub fs: FrameSocket,
     pub ns: Option<NoiseSocket>,
     pub unique_id: String,
     pub device: Device,
-    pub handle: Option<Box<dyn Events>>,
-    pub id_counter: usize
+    pub event_bus: EventBus,
+    pub id_counter: usize,
+    pub response_waiters: ResponseWaiters,
+    pub compression_threshold: Option<usize>,
+    pub max_decompressed_size: usize,
+    pub qr_first_timeout: Duration,
+    pub qr_timeout: Duration,
+    pub qr_task: Option<JoinHandle<()>>,
+    pub phone_linking: Option<PhoneLinking>,
+    pub state: ConnectionState,
+    pub on_disconnect: DisconnectAction,
+    pub auto_reconnect: bool,
+    pub reconnect_base_delay: Duration,
+    pub reconnect_max_delay: Duration,
+    pub handshake_timeout: Duration,
+    pub keepalive_interval_min: Duration,
+    pub keepalive_interval_max: Duration,
+    pub keepalive_response_deadline: Duration,
+    pub keepalive_max_fail_time: Duration,
+    pub cert_root_key: [u8; 32],
+    pub read_task: Option<JoinHandle<()>>,
+    pub keepalive_task: Option<JoinHandle<()>>
 }
 
 impl Client {
-    pub async fn process(&mut self, node: &Node) {
-        match node.tag.as_str() {
-            "iq" => self.handle_qr(node).await,
-            _ => error!("Node not handled: {}", node.tag)
+    pub fn new(config: Config) -> Result<Arc<Mutex<Client>>, StoreError> {
+        let mut unique_ids = [0u8; 2];
+        rand_core::OsRng.fill_bytes(&mut unique_ids);
+
+        let device = match config.store.load()? {
+            Some(device) => {
+                info!("Loaded stored device {}", device.jid.as_ref().map(|jid| jid.to_string()).unwrap_or_default());
+                device
+            }
+            None => Device::new(),
+        };
+
+        Ok(Arc::new_cyclic(|this| Mutex::new(Client {
+            this: this.clone(),
+            transport: config.transport,
+            store: config.store,
+            session_store: config.session_store,
+            pre_key_store: config.pre_key_store,
+            sender_key_store: config.sender_key_store,
+            identity_store: config.identity_store,
+            trust_policy: config.trust_policy,
+            write: None,
+            fs: FrameSocket::new(),
+            ns: None,
+            unique_id: format!("{}.{}-", unique_ids[0], unique_ids[1]),
+            device,
+            event_bus: EventBus::new(),
+            id_counter: 0,
+            response_waiters: Default::default(),
+            compression_threshold: config.compression_threshold,
+            max_decompressed_size: config.max_decompressed_size,
+            qr_first_timeout: config.qr_first_timeout,
+            qr_timeout: config.qr_timeout,
+            qr_task: None,
+            phone_linking: None,
+            state: ConnectionState::Disconnected,
+            on_disconnect: DisconnectAction::Reconnect,
+            auto_reconnect: config.auto_reconnect,
+            reconnect_base_delay: config.reconnect_base_delay,
+            reconnect_max_delay: config.reconnect_max_delay,
+            handshake_timeout: config.handshake_timeout,
+            keepalive_interval_min: config.keepalive_interval_min,
+            keepalive_interval_max: config.keepalive_interval_max,
+            keepalive_response_deadline: config.keepalive_response_deadline,
+            keepalive_max_fail_time: config.keepalive_max_fail_time,
+            cert_root_key: config.cert_root_key,
+            read_task: None,
+            keepalive_task: None
+        })))
+    }
+
+    pub async fn connect(client: &Arc<Mutex<Client>>) -> Result<(), HandshakeError> {
+        let mut guard = client.lock().await;
+        guard.state = ConnectionState::Connecting;
+        guard.on_disconnect = DisconnectAction::Reconnect;
+        guard.fs = FrameSocket::new();
+        guard.ns = None;
+
+        // A server that accepts the socket but never answers would otherwise hold the lock forever
+        let timeout = guard.handshake_timeout;
+        let handshake = tokio::time::timeout(timeout, async {
+            let (write, mut read) = guard.transport.connect().await?;
+            guard.write = Some(write);
+            guard.do_handshake(&mut read).await?;
+            Ok(read)
+        }).await;
+        let read = match handshake.unwrap_or(Err(HandshakeError::Timeout(timeout))) {
+            Ok(read) => read,
+            Err(e) => {
+                guard.write = None;
+                guard.state = ConnectionState::Disconnected;
+                return Err(e);
+            }
+        };
+        guard.state = ConnectionState::Authenticating;
+
+        info!("Message processor started");
+        guard.read_task = Some(tokio::spawn(Client::read_messages(Arc::clone(client), read)));
+        guard.keepalive_task = Some(tokio::spawn(Client::keep_alive(Arc::clone(client))));
+        Ok(())
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.event_bus.subscribe()
+    }
+
+    pub fn add_event_handler<F, Fut>(&mut self, handler: F) -> usize
+    where
+        F: Fn(Event) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.event_bus.add_handler(handler)
+    }
+
+    pub fn remove_event_handler(&mut self, id: usize) -> bool {
+        self.event_bus.remove_handler(id)
+    }
+
+    pub(crate) fn dispatch_event(&self, event: Event) {
+        self.event_bus.dispatch(event);
+    }
+
+    pub async fn disconnect(&mut self) {
+        self.on_disconnect = DisconnectAction::Stop;
+        self.disconnect_transport().await;
+    }
+
+    pub(crate) async fn disconnect_transport(&mut self) {
+        if let Some(task) = self.qr_task.take() {
+            task.abort();
+        }
+        if let Some(mut write) = self.write.take()
+            && let Err(e) = write.close().await {
+            error!("Failed to close transport: {}", e);
         }
     }
 
+    async fn read_messages(client: Arc<Mutex<Client>>, mut read: Box<dyn TransportReceiver>) {
+        while let Some(message) = read.receive().await {
+            match message {
+                Ok(data) => {
+                    let mut client = client.lock().await;
+                    for frame in client.fs.process_data(data) {
+                        client.handle_frame(frame).await;
+                    }
+                }
+                Err(e) => {
+                    error!("Error: {}", e);
+                    break;
+                }
+            }
+        }
+        Client::connection_closed(client).await;
+    }
+
+    /// Tears down what belonged to the connection that just went away and
+    /// hands over to the reconnect loop unless the last close was final.
+    async fn connection_closed(client: Arc<Mutex<Client>>) {
+        let mut guard = client.lock().await;
+        guard.read_task = None;
+        guard.response_waiters.lock().unwrap().clear();
+        if let Some(task) = guard.keepalive_task.take() {
+            task.abort();
+        }
+        guard.write = None;
+        if guard.state != ConnectionState::LoggedOut {
+            guard.state = ConnectionState::Disconnected;
+        }
+        guard.dispatch_event(Event::Disconnected);
+
+        let action = guard.on_disconnect;
+        if guard.auto_reconnect && action != DisconnectAction::Stop {
+            drop(guard);
+            tokio::spawn(Client::reconnect(client, action == DisconnectAction::Restart));
+        }
+    }
+
+    async fn handle_frame(&mut self, frame: Vec<u8>) {
+        let Some(ns) = self.ns.as_mut() else {
+            error!("Received a frame before the handshake finished");
+            return;
+        };
+        let data = match ns.receive_encrypted_frame(&frame) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("Failed to decrypt frame: {}", e);
+                return;
+            }
+        };
+        let node = match BinaryDecoder::with_max_decompressed_size(data, self.max_decompressed_size).and_then(|mut decoder| decoder.decode()) {
+            Ok(node) => node,
+            Err(e) => {
+                error!("Failed to decode frame: {}", e);
+                return;
+            }
+        };
+        info!("Received node: {}", node.to_xml());
+        self.process(&node).await;
+    }
+
     pub async fn keep_alive(client: Arc<Mutex<Client>>) {
         let mut rng = rand_core::OsRng;
+        let mut last_success = Instant::now();
+        let mut error_count = 0;
         info!("Keep alive started");
         loop {
+            let (interval_min, interval_max) = {
+                let client = client.lock().await;
+                (client.keepalive_interval_min, client.keepalive_interval_max)
+            };
             let interval = rng.next_u64()
-                % (constant::KEEPALIVE_INTERVAL_MAX.as_millis() - constant::KEEPALIVE_INTERVAL_MIN.as_millis()) as u64
-                + constant::KEEPALIVE_INTERVAL_MIN.as_millis() as u64;
+                % ((interval_max - interval_min).as_millis() as u64).max(1)
+                + interval_min.as_millis() as u64;
 
             tokio::time::sleep(Duration::from_millis(interval)).await;
 
-            let iq = InfoQuery {
-                namespace: Some("w:p".into()),
-                r#type: Some("get".into()),
-                to: Some(JID::new(None, None, None, None, Some("s.whatsapp.net".into()))),
-                ..Default::default()
+            let response = {
+                let mut client = client.lock().await;
+                let iq = InfoQuery {
+                    namespace: Some("w:p".into()),
+                    r#type: Some("get".into()),
+                    to: Some(JID::new(None, None, None, None, Some("s.whatsapp.net".into()))),
+                    timeout: Some(client.keepalive_response_deadline),
+                    ..Default::default()
+                };
+                client.send_iq(iq).await
             };
 
-            let mut client = client.lock().await;
-            client.send_iq(iq).await;
+            match response.await {
+                // Any answer, even an error, proves the connection is alive
+                Ok(_) | Err(IqError::ServerError { .. }) => {
+                    if error_count > 0 {
+                        error_count = 0;
+                        info!("Keep alive restored");
+                        client.lock().await.dispatch_event(Event::KeepAliveRestored);
+                    }
+                    last_success = Instant::now();
+                }
+                Err(IqError::Timeout) => {
+                    error_count += 1;
+                    error!("Keep alive timed out ({} in a row)", error_count);
+                    let mut guard = client.lock().await;
+                    guard.dispatch_event(Event::KeepAliveTimeout { error_count, last_success });
+
+                    if guard.auto_reconnect && last_success.elapsed() > guard.keepalive_max_fail_time {
+                        error!("Forcing reconnect after {:?} without keep alive", last_success.elapsed());
+                        // A dead socket may never end the read loop, so stop it here and
+                        // leave this task running to completion instead of aborting it
+                        guard.keepalive_task = None;
+                        if let Some(task) = guard.read_task.take() {
+                            task.abort();
+                        }
+                        guard.on_disconnect = DisconnectAction::Reconnect;
+                        guard.disconnect_transport().await;
+                        drop(guard);
+                        Client::connection_closed(client).await;
+                        return;
+                    }
+                }
+                Err(e) => {
+                    error!("Keep alive stopped: {}", e);
+                    return;
+                }
+            }
         }
     }
 
-    async fn do_handshake(&mut self, read: &mut SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>) {
+    pub(crate) async fn send_frame(&mut self, data: Vec<u8>) -> Result<(), TransportError> {
+        let frame = self.fs.make_frame(data).map_err(|e| TransportError::Send(e.to_string()))?;
+        self.write.as_mut().ok_or(TransportError::Closed)?.send(frame).await
+    }
+
+    async fn do_handshake(&mut self, read: &mut Box<dyn TransportReceiver>) -> Result<(), HandshakeError> {
         let client_hello = HandshakeMessage {
             client_hello: Some(ClientHello {
                 ephemeral: Some(self.fs.key.public.to_bytes().to_vec()),
@@ -71,36 +380,46 @@ impl Client {
             ..Default::default()
         };
 
-        self.write.send(self.fs.make_frame(client_hello.encode_to_vec()).into()).await.unwrap();
+        self.send_frame(client_hello.encode_to_vec()).await?;
 
-        let message = if let Some(message) = read.next().await {
-            self.fs.process_data(Vec::from(message.expect("Failed to read message").into_data()))
-        } else {
-            panic!("No message received");
+        let message = loop {
+            let data = read.receive().await.ok_or(TransportError::Closed)??;
+            let mut frames = self.fs.process_data(data);
+            if !frames.is_empty() {
+                break frames.remove(0);
+            }
         };
 
         let mut nhs = NoiseHandShake::default();
         nhs.start(constant::CONN_HEADER.to_vec());
         nhs.authenticate(&self.fs.key.public.to_bytes().to_vec());
 
-        let message = HandshakeMessage::decode(&message[..]).unwrap();
-        let (server_ephemeral, server_static_cipher_text, certificate_cipher_text) = {
-            let server_hello = message.server_hello.unwrap();
-            (server_hello.ephemeral.unwrap(), server_hello.r#static.unwrap(), server_hello.payload.unwrap())
+        let server_hello = HandshakeMessage::decode(&message[..])
+            .map_err(|e| HandshakeError::Malformed(e.to_string()))?
+            .server_hello
+            .ok_or_else(|| HandshakeError::Malformed("missing server hello".into()))?;
+        let (Some(server_ephemeral), Some(server_static_cipher_text), Some(certificate_cipher_text)) =
+            (server_hello.ephemeral, server_hello.r#static, server_hello.payload) else {
+            return Err(HandshakeError::Malformed("incomplete server hello".into()));
         };
+        let server_ephemeral: [u8; 32] = server_ephemeral.try_into()
+            .map_err(|_| HandshakeError::Malformed("invalid ephemeral key".into()))?;
 
-        nhs.authenticate(&server_ephemeral);
-        nhs.mix_shared_secret(self.fs.key.private.to_bytes(), server_ephemeral.clone().try_into().unwrap());
+        nhs.authenticate(&server_ephemeral.to_vec());
+        nhs.mix_shared_secret(self.fs.key.private.to_bytes(), server_ephemeral);
 
-        let static_decrypted = nhs.decrypt(&server_static_cipher_text);
-        nhs.mix_shared_secret(self.fs.key.private.to_bytes(), static_decrypted.clone().try_into().unwrap());
+        let static_decrypted = nhs.decrypt(&server_static_cipher_text).map_err(|_| HandshakeError::Decrypt)?;
+        let server_static: [u8; 32] = static_decrypted.as_slice().try_into()
+            .map_err(|_| HandshakeError::Malformed("invalid static key".into()))?;
+        nhs.mix_shared_secret(self.fs.key.private.to_bytes(), server_static);
 
-        nhs.decrypt(&certificate_cipher_text);
+        let certificate = nhs.decrypt(&certificate_cipher_text).map_err(|_| HandshakeError::Decrypt)?;
+        verify_server_cert(&certificate, &static_decrypted, &self.cert_root_key)?;
 
         let encrypted_pubkey = nhs.encrypt(&self.device.noise_key.public.as_bytes().to_vec());
-        nhs.mix_shared_secret(self.device.noise_key.private.to_bytes(), server_ephemeral.try_into().unwrap());
+        nhs.mix_shared_secret(self.device.noise_key.private.to_bytes(), server_ephemeral);
 
-        let encrypted_client_payload = nhs.encrypt(&self.device.create_register_payload().encode_to_vec());
+        let encrypted_client_payload = nhs.encrypt(&self.device.create_client_payload().encode_to_vec());
         let client_finish = HandshakeMessage {
             client_finish: Some(ClientFinish {
                 payload: Some(encrypted_client_payload),
@@ -110,76 +429,130 @@ impl Client {
         };
 
         self.fs.state = FrameSocketState::Authenticated;
-        self.write.send(self.fs.make_frame(client_finish.encode_to_vec()).into()).await.expect("Failed to send handshake message");
+        self.send_frame(client_finish.encode_to_vec()).await?;
 
         let (write_key, read_key) = nhs.extract_and_expand(None);
         let write_key = gcm::prepare(write_key);
         let read_key = gcm::prepare(read_key);
         self.ns = Some(NoiseSocket::new(write_key, read_key));
+        Ok(())
     }
 }
 
-pub async fn connect<E: Events + 'static>(handle: E) -> Arc<Mutex<Client>> {
-    info!("Dialing {}", constant::WS_URL);
-    let request = ClientRequestBuilder::new(constant::WS_URL.parse().unwrap())
-        .with_header("Origin", constant::ORIGIN)
-        .into_client_request().unwrap();
+/// Connects with `config`. The receiver is subscribed before the handshake,
+/// so it doesn't miss the first QR code.
+pub async fn connect<F, Fut>(config: Config, handler: F) -> (Arc<Mutex<Client>>, broadcast::Receiver<Event>)
+where
+    F: Fn(Event) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let client = Client::new(config).expect("Can't load device store");
+    let events = {
+        let mut client = client.lock().await;
+        client.add_event_handler(handler);
+        client.subscribe()
+    };
+    Client::connect(&client).await.expect("Can't connect to whatsapp");
+    (client, events)
+}
 
-    let (ws_stream, _) = connect_async(request).await.expect("Can't connect to whatsapp");
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
 
-    let (write, mut read) = ws_stream.split();
+    use crate::connection::ConnectionState;
+    use crate::events::Event;
+    use crate::testing::mock_server::MockServer;
+    use crate::testing::next_event;
+    use crate::utils::noise_handshake::HandshakeError;
 
-    let mut unique_ids = [0u8; 2];
-    rand_core::OsRng.fill_bytes(&mut unique_ids);
+    use super::{Client, Config};
 
-    let client = Arc::new(Mutex::new(Client {
-        write,
-        fs: FrameSocket::new(),
-        ns: None,
-        unique_id: format!("{}.{}-", unique_ids[0], unique_ids[1]),
-        device: Device::new(),
-        handle: Some(Box::new(handle)),
-        id_counter: 0
-    }));
+    #[tokio::test]
+    async fn completes_handshake_with_register_payload() {
+        let mut server = MockServer::start().await;
+        let client = Client::new(server.config()).unwrap();
 
-    {
-        let mut client = client.lock().await;
-        client.do_handshake(&mut read).await;
+        let (connected, conn) = tokio::join!(Client::connect(&client), server.accept());
+        connected.unwrap();
+
+        let client = client.lock().await;
+        let pairing = conn.client_payload.device_pairing_data.as_ref().unwrap();
+        assert_eq!(pairing.e_ident.as_deref(), Some(&client.device.identity_key.public.as_bytes()[..]));
+        assert_eq!(&conn.client_static, client.device.noise_key.public.as_bytes());
     }
 
-    let client_clone = Arc::clone(&client);
+    #[tokio::test]
+    async fn gives_up_on_servers_that_never_answer() {
+        let server = MockServer::start().await;
+        let config = Config { handshake_timeout: Duration::from_millis(100), ..server.config() };
+        let client = Client::new(config).unwrap();
 
-    info!("Message processor started");
-    tokio::spawn(async move {
-        let mut read = read;
-        let client = client_clone;
-        while let Some(message) = read.next().await {
-            match message {
-                Ok(msg) => {
-                    let data = {
-                        let mut client = client.lock().await;
-                        let data = client.fs.process_data(Vec::from(msg.into_data()));
-                        client.ns.as_mut().unwrap().receive_encrypted_frame(&data)
-                    };
-                    let node = BinaryDecoder::new(data).decode();
-                    info!("Received node: {}", node.to_xml());
-                    {
-                        client.lock().await.process(&node).await;
-                    }
-                }
-                Err(e) => {
-                    error!("Error: {}", e);
-                    break;
+        // The socket is accepted but the server never sends its hello
+        let error = Client::connect(&client).await.unwrap_err();
+        assert!(matches!(error, HandshakeError::Timeout(timeout) if timeout == Duration::from_millis(100)));
+        let client = client.lock().await;
+        assert_eq!(client.state, ConnectionState::Disconnected);
+        assert!(client.write.is_none());
+    }
+
+    #[tokio::test]
+    async fn restores_keepalive_after_missed_ping() {
+        let mut server = MockServer::start().await;
+        let config = Config {
+            keepalive_interval_min: Duration::from_millis(10),
+            keepalive_interval_max: Duration::from_millis(20),
+            keepalive_response_deadline: Duration::from_millis(50),
+            ..server.config()
+        };
+        let client = Client::new(config).unwrap();
+        let mut events = client.lock().await.subscribe();
+        let (connected, mut conn) = tokio::join!(Client::connect(&client), server.accept());
+        connected.unwrap();
+
+        let ping = conn.expect_node("iq").await;
+        assert_eq!(ping.get_attr("xmlns"), Some("w:p"));
+        assert!(matches!(next_event(&mut events).await, Event::KeepAliveTimeout { error_count: 1, .. }));
+
+        let ping = conn.expect_node("iq").await;
+        conn.send_iq_result(&ping, None).await;
+        assert!(matches!(next_event(&mut events).await, Event::KeepAliveRestored));
+    }
+
+    #[tokio::test]
+    async fn forces_reconnect_when_keepalive_keeps_failing() {
+        let mut server = MockServer::start().await;
+        let config = Config {
+            keepalive_interval_min: Duration::from_millis(10),
+            keepalive_interval_max: Duration::from_millis(20),
+            keepalive_response_deadline: Duration::from_millis(20),
+            keepalive_max_fail_time: Duration::from_millis(100),
+            reconnect_base_delay: Duration::from_millis(10),
+            ..server.config()
+        };
+        let client = Client::new(config).unwrap();
+        let mut events = client.lock().await.subscribe();
+        // The server stays connected but never answers a ping
+        let (connected, _conn) = tokio::join!(Client::connect(&client), server.accept());
+        connected.unwrap();
+
+        let mut timeouts = 0;
+        loop {
+            match next_event(&mut events).await {
+                Event::KeepAliveTimeout { error_count, .. } => {
+                    timeouts += 1;
+                    assert_eq!(error_count, timeouts);
                 }
+                Event::Disconnected => break,
+                other => panic!("unexpected event: {:?}", other),
             }
         }
-    });
-
-    tokio::spawn(Client::keep_alive(Arc::clone(&client)));
+        assert!(timeouts > 1);
+        assert!(matches!(next_event(&mut events).await, Event::Reconnecting { attempt: 1, .. }));
 
-    client
+        let mut conn = server.accept().await;
+        conn.send_success().await;
+        conn.expect_node("iq").await;
+        assert!(matches!(next_event(&mut events).await, Event::Connected));
+    }
 }
-
-pub trait Events: Send {
-    fn on_qr(&self, qr: &str);
-}
\ No newline at end of file
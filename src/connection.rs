@@ -0,0 +1,391 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use paris::{error, info};
+use rand_core::{OsRng, RngCore};
+use tokio::sync::Mutex;
+
+use crate::client::Client;
+use crate::device::Device;
+use crate::events::Event;
+use crate::request::InfoQuery;
+use crate::types::jid::{self, JID};
+use crate::utils::decoder::{Node, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionState {
+    Disconnected,
+    Connecting,
+    Authenticating,
+    Connected,
+    LoggedOut,
+}
+
+/// What to do once the socket closes, decided by the last node that
+/// explained why the server is dropping us.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisconnectAction {
+    Reconnect,
+    Restart,
+    Stop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FailureReason {
+    LoggedOut,
+    TempBanned,
+    Banned,
+    ClientOutdated,
+    UnknownLogout,
+    ServiceUnavailable,
+    Other(u16),
+}
+
+impl FailureReason {
+    pub fn from_code(code: u16) -> Self {
+        match code {
+            401 => FailureReason::LoggedOut,
+            402 => FailureReason::TempBanned,
+            403 => FailureReason::Banned,
+            405 => FailureReason::ClientOutdated,
+            406 => FailureReason::UnknownLogout,
+            503 => FailureReason::ServiceUnavailable,
+            code => FailureReason::Other(code),
+        }
+    }
+
+    pub fn code(&self) -> u16 {
+        match self {
+            FailureReason::LoggedOut => 401,
+            FailureReason::TempBanned => 402,
+            FailureReason::Banned => 403,
+            FailureReason::ClientOutdated => 405,
+            FailureReason::UnknownLogout => 406,
+            FailureReason::ServiceUnavailable => 503,
+            FailureReason::Other(code) => *code,
+        }
+    }
+
+    pub fn is_logged_out(&self) -> bool {
+        matches!(self, FailureReason::LoggedOut | FailureReason::UnknownLogout)
+    }
+
+    fn should_reconnect(&self) -> bool {
+        !matches!(self, FailureReason::TempBanned | FailureReason::Banned | FailureReason::ClientOutdated) && !self.is_logged_out()
+    }
+}
+
+impl fmt::Display for FailureReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailureReason::LoggedOut => write!(f, "logged out"),
+            FailureReason::TempBanned => write!(f, "temporarily banned"),
+            FailureReason::Banned => write!(f, "banned"),
+            FailureReason::ClientOutdated => write!(f, "client outdated"),
+            FailureReason::UnknownLogout => write!(f, "logged out for unknown reason"),
+            FailureReason::ServiceUnavailable => write!(f, "service unavailable"),
+            FailureReason::Other(code) => write!(f, "failure {}", code),
+        }
+    }
+}
+
+impl Client {
+    pub fn is_connected(&self) -> bool {
+        self.state == ConnectionState::Connected
+    }
+
+    pub async fn handle_success(&mut self) {
+        info!("Logged in as {}", self.device.jid.as_ref().map(|jid| jid.to_string()).unwrap_or_default());
+        self.state = ConnectionState::Connected;
+        self.on_disconnect = DisconnectAction::Reconnect;
+
+        let response = self.send_iq(InfoQuery {
+            namespace: Some("passive".into()),
+            r#type: Some("set".into()),
+            to: Some(JID::new(None, None, None, None, Some(jid::DEFAULT_USER_SERVER.into()))),
+            content: Some(Value::List(vec![Node::new("active".to_string(), HashMap::new(), None)])),
+            ..Default::default()
+        }).await;
+        tokio::spawn(async move {
+            if let Err(e) = response.await {
+                error!("Failed to set passive mode off: {}", e);
+            }
+        });
+
+        if let Some(client) = self.this.upgrade() {
+            tokio::spawn(Client::refresh_pre_keys(client));
+        }
+        self.dispatch_event(Event::Connected);
+    }
+
+    pub async fn handle_failure(&mut self, node: &Node) {
+        let code = node.get_attr("reason").and_then(|reason| reason.parse().ok()).unwrap_or(0);
+        let reason = FailureReason::from_code(code);
+        let message = node.get_attr("message").unwrap_or_default().to_string();
+        error!("Connection failed: {} {}", reason, message);
+
+        if reason.is_logged_out() {
+            self.log_out(true, reason);
+        } else {
+            self.on_disconnect = match reason.should_reconnect() {
+                true => DisconnectAction::Reconnect,
+                false => DisconnectAction::Stop,
+            };
+            self.dispatch_event(Event::ConnectFailure { reason, message });
+        }
+        self.disconnect_transport().await;
+    }
+
+    pub async fn handle_stream_error(&mut self, node: &Node) {
+        let code = node.get_attr("code").unwrap_or_default().to_string();
+        let conflict = node.get_child("conflict").and_then(|conflict| conflict.get_attr("type"));
+        error!("Stream error {} {}", code, conflict.unwrap_or_default());
+
+        match (code.as_str(), conflict) {
+            ("515", _) => self.on_disconnect = DisconnectAction::Restart,
+            ("401", Some("device_removed")) => self.log_out(false, FailureReason::LoggedOut),
+            (_, Some("replaced")) => {
+                self.on_disconnect = DisconnectAction::Stop;
+                self.dispatch_event(Event::StreamReplaced);
+            }
+            _ => {
+                self.on_disconnect = DisconnectAction::Reconnect;
+                self.dispatch_event(Event::StreamError { code });
+            }
+        }
+        self.disconnect_transport().await;
+    }
+
+    /// Keeps dialing with exponential backoff until a connection is up,
+    /// giving up once auto reconnect gets disabled or someone else connected.
+    pub(crate) fn reconnect(client: Arc<Mutex<Client>>, immediately: bool) -> BoxFuture<'static, ()> {
+        async move {
+            let mut attempt = 0;
+            loop {
+                let delay = match immediately && attempt == 0 {
+                    true => Duration::ZERO,
+                    false => client.lock().await.reconnect_delay(attempt),
+                };
+                attempt += 1;
+                info!("Reconnecting in {:?} (attempt {})", delay, attempt);
+                client.lock().await.dispatch_event(Event::Reconnecting { attempt, delay });
+                tokio::time::sleep(delay).await;
+
+                {
+                    let client = client.lock().await;
+                    if !client.auto_reconnect || client.state != ConnectionState::Disconnected {
+                        return;
+                    }
+                }
+                match Client::connect(&client).await {
+                    Ok(()) => return,
+                    Err(e) => error!("Reconnect failed: {}", e),
+                }
+            }
+        }.boxed()
+    }
+
+    fn reconnect_delay(&self, attempt: u32) -> Duration {
+        let delay = self.reconnect_base_delay
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.reconnect_max_delay);
+        // Spread clients that dropped together over the upper half of the window
+        let jitter = OsRng.next_u64() % (delay.as_millis() as u64 / 2 + 1);
+        delay / 2 + Duration::from_millis(jitter)
+    }
+
+    fn log_out(&mut self, on_connect: bool, reason: FailureReason) {
+        self.state = ConnectionState::LoggedOut;
+        self.on_disconnect = DisconnectAction::Stop;
+        if let Err(e) = self.store.delete() {
+            error!("Failed to delete device: {}", e);
+        }
+        if let Err(e) = self.session_store.delete_all_sessions() {
+            error!("Failed to delete sessions: {}", e);
+        }
+        if let Err(e) = self.pre_key_store.delete_all_pre_keys() {
+            error!("Failed to delete pre-keys: {}", e);
+        }
+        if let Err(e) = self.sender_key_store.delete_all_sender_keys() {
+            error!("Failed to delete sender keys: {}", e);
+        }
+        if let Err(e) = self.identity_store.delete_all_identities() {
+            error!("Failed to delete identities: {}", e);
+        }
+        self.device = Device::new();
+        self.dispatch_event(Event::LoggedOut { on_connect, reason });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use ed25519_dalek::SigningKey;
+
+    use crate::client::{Client, Config};
+    use crate::device::Device;
+    use crate::events::Event;
+    use crate::store::device::{DeviceStore, memory::MemoryDeviceStore};
+    use crate::testing::mock_server::{MockServer, signed_device_identity};
+    use crate::testing::next_event;
+    use crate::types::jid::JID;
+
+    use super::{ConnectionState, DisconnectAction, FailureReason};
+
+    #[tokio::test]
+    async fn goes_active_on_success() {
+        let mut server = MockServer::start().await;
+        let client = Client::new(server.config()).unwrap();
+        let mut events = client.lock().await.subscribe();
+        let (connected, mut conn) = tokio::join!(Client::connect(&client), server.accept());
+        connected.unwrap();
+        assert_eq!(client.lock().await.state, ConnectionState::Authenticating);
+
+        conn.send_success().await;
+        let passive = conn.expect_node("iq").await;
+        assert_eq!(passive.get_attr("xmlns"), Some("passive"));
+        assert!(passive.get_child("active").is_some());
+        conn.send_iq_result(&passive, None).await;
+
+        assert!(matches!(next_event(&mut events).await, Event::Connected));
+        assert!(client.lock().await.is_connected());
+    }
+
+    #[tokio::test]
+    async fn wipes_device_when_logged_out() {
+        let mut server = MockServer::start().await;
+        let store = MemoryDeviceStore::default();
+        let mut device = Device::new();
+        device.jid = Some(JID::new_ad("6281234567890".into(), 0, 4));
+        store.save(&device).unwrap();
+
+        let client = Client::new(server.config_with_store(store.clone())).unwrap();
+        let mut events = client.lock().await.subscribe();
+        let (connected, mut conn) = tokio::join!(Client::connect(&client), server.accept());
+        connected.unwrap();
+
+        conn.send_failure(401).await;
+        assert!(matches!(next_event(&mut events).await, Event::LoggedOut { on_connect: true, reason: FailureReason::LoggedOut }));
+        while conn.receive_node().await.is_some() {}
+        assert!(matches!(next_event(&mut events).await, Event::Disconnected));
+
+        assert!(store.load().unwrap().is_none());
+        let client = client.lock().await;
+        assert_eq!(client.state, ConnectionState::LoggedOut);
+        assert_eq!(client.on_disconnect, DisconnectAction::Stop);
+        assert!(client.device.jid.is_none());
+    }
+
+    #[tokio::test]
+    async fn interprets_stream_errors() {
+        let mut server = MockServer::start().await;
+        for (code, conflict, action) in [("409", Some("replaced"), DisconnectAction::Stop), ("515", None, DisconnectAction::Restart)] {
+            let client = Client::new(Config { auto_reconnect: false, ..server.config() }).unwrap();
+            let mut events = client.lock().await.subscribe();
+            let (connected, mut conn) = tokio::join!(Client::connect(&client), server.accept());
+            connected.unwrap();
+
+            conn.send_stream_error(code, conflict).await;
+            while conn.receive_node().await.is_some() {}
+            if conflict.is_some() {
+                assert!(matches!(next_event(&mut events).await, Event::StreamReplaced));
+            }
+            assert!(matches!(next_event(&mut events).await, Event::Disconnected));
+            assert_eq!(client.lock().await.on_disconnect, action);
+        }
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_connection_drops() {
+        let mut server = MockServer::start().await;
+        let config = Config {
+            reconnect_base_delay: Duration::from_millis(10),
+            ..server.config()
+        };
+        let client = Client::new(config).unwrap();
+        let mut events = client.lock().await.subscribe();
+        let (connected, mut conn) = tokio::join!(Client::connect(&client), server.accept());
+        connected.unwrap();
+
+        conn.close().await;
+        assert!(matches!(next_event(&mut events).await, Event::Disconnected));
+        assert!(matches!(next_event(&mut events).await, Event::Reconnecting { attempt: 1, .. }));
+
+        let mut conn = server.accept().await;
+        conn.send_success().await;
+        conn.expect_node("iq").await;
+        assert!(matches!(next_event(&mut events).await, Event::Connected));
+    }
+
+    #[tokio::test]
+    async fn restarts_with_login_payload_after_pairing() {
+        let mut server = MockServer::start().await;
+        let client = Client::new(server.config()).unwrap();
+        let mut events = client.lock().await.subscribe();
+        let (connected, mut conn) = tokio::join!(Client::connect(&client), server.accept());
+        connected.unwrap();
+
+        let (identity_public, adv_secret_key) = {
+            let client = client.lock().await;
+            (*client.device.identity_key.public.as_bytes(), client.device.adv_secret_key)
+        };
+        let device_identity = signed_device_identity(&SigningKey::from_bytes(&[7; 32]), &identity_public, &adv_secret_key, 1);
+        conn.send_pair_success("pair-5", device_identity, JID::new_ad("6281234567890".into(), 0, 12), "", "smba").await;
+        conn.expect_node("iq").await;
+        assert!(matches!(next_event(&mut events).await, Event::PairSuccess { .. }));
+
+        conn.send_stream_error("515", None).await;
+        while conn.receive_node().await.is_some() {}
+        assert!(matches!(next_event(&mut events).await, Event::Disconnected));
+        assert!(matches!(next_event(&mut events).await, Event::Reconnecting { attempt: 1, delay } if delay.is_zero()));
+
+        let conn = server.accept().await;
+        assert_eq!(conn.client_payload.username, Some(6281234567890));
+        assert_eq!(conn.client_payload.device, Some(12));
+        assert!(conn.client_payload.device_pairing_data.is_none());
+    }
+
+    #[tokio::test]
+    async fn stays_disconnected_without_auto_reconnect() {
+        let mut server = MockServer::start().await;
+        let config = Config {
+            auto_reconnect: false,
+            reconnect_base_delay: Duration::from_millis(10),
+            ..server.config()
+        };
+        let client = Client::new(config).unwrap();
+        let mut events = client.lock().await.subscribe();
+        let (connected, mut conn) = tokio::join!(Client::connect(&client), server.accept());
+        connected.unwrap();
+
+        conn.close().await;
+        assert!(matches!(next_event(&mut events).await, Event::Disconnected));
+        assert!(tokio::time::timeout(Duration::from_millis(200), server.accept()).await.is_err());
+        assert_eq!(client.lock().await.state, ConnectionState::Disconnected);
+    }
+
+    #[tokio::test]
+    async fn reuses_stored_device() {
+        let mut server = MockServer::start().await;
+        let store = MemoryDeviceStore::default();
+        let mut device = Device::new();
+        device.jid = Some(JID::new_ad("6281234567890".into(), 0, 4));
+        store.save(&device).unwrap();
+
+        let client = Client::new(server.config_with_store(store)).unwrap();
+        let (connected, conn) = tokio::join!(Client::connect(&client), server.accept());
+        connected.unwrap();
+
+        assert_eq!(&conn.client_static, device.noise_key.public.as_bytes());
+        assert_eq!(client.lock().await.device.registration_id, device.registration_id);
+        assert_eq!(conn.client_payload.username, Some(6281234567890));
+        assert_eq!(conn.client_payload.device, Some(4));
+        assert_eq!(conn.client_payload.passive, Some(true));
+        assert!(conn.client_payload.device_pairing_data.is_none());
+    }
+}
@@ -2,12 +2,54 @@ use std::time::Duration;
 
 pub const WS_URL: &str = "wss://web.whatsapp.com/ws/chat";
 pub const ORIGIN: &str = "https://web.whatsapp.com";
+pub const DEVICE_STORE_PATH: &str = "device.json";
 pub const CONN_HEADER: [u8; 4] = [b'W', b'A', 6, 3]; // 6 and 3 not sure what it is
 pub const NOISE_PATTERN: &str = "Noise_XX_25519_AESGCM_SHA256\x00\x00\x00\x00";
-pub const FRAME_MAX_SIZE: usize = 2 << 23;
+pub const FRAME_MAX_SIZE: usize = (1 << 24) - 1; // The most a 3 byte length can hold
 pub const FRAME_LENGTH_SIZE: usize = 3;
+pub const MAX_DECOMPRESSED_SIZE: usize = 2 << 24;
+pub const MAX_NODE_DEPTH: usize = 64;
+pub const CERT_ISSUER_SERIAL: u32 = 0;
+pub const CERT_ROOT_KEY: [u8; 32] = [
+    0x14, 0x23, 0x75, 0x57, 0x4d, 0x0a, 0x58, 0x71, 0x66, 0xaa, 0xe7, 0x1e, 0xbe, 0x51, 0x64, 0x37,
+    0xc4, 0xa2, 0x8b, 0x73, 0xe3, 0x69, 0x5c, 0x6c, 0xe1, 0xf7, 0xf9, 0x54, 0x5d, 0xa8, 0xee, 0x6b,
+];
 
 pub const KEEPALIVE_RESPONSE_DEADLINE: Duration = Duration::from_secs(10);
 pub const KEEPALIVE_INTERVAL_MIN: Duration = Duration::from_secs(20);
 pub const KEEPALIVE_INTERVAL_MAX: Duration = Duration::from_secs(30);
 pub const KEEPALIVE_MAX_FAIL_TIME: Duration = Duration::from_secs(180);
+
+pub const IQ_TIMEOUT: Duration = Duration::from_secs(75);
+pub const QR_FIRST_TIMEOUT: Duration = Duration::from_secs(60);
+pub const QR_TIMEOUT: Duration = Duration::from_secs(20);
+pub const EVENT_BUFFER_SIZE: usize = 256;
+pub const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(1);
+pub const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(20);
+
+pub const PAIR_CLIENT_PLATFORM_ID: &str = "1"; // Chrome
+pub const PAIR_CLIENT_DISPLAY_NAME: &str = "Chrome (Linux)";
+
+pub const ADV_PREFIX_ACCOUNT_SIGNATURE: [u8; 2] = [6, 0];
+pub const ADV_PREFIX_DEVICE_SIGNATURE: [u8; 2] = [6, 1];
+pub const ADV_HOSTED_PREFIX_ACCOUNT_SIGNATURE: [u8; 2] = [6, 5];
+pub const ADV_HOSTED_PREFIX_DEVICE_SIGNATURE: [u8; 2] = [6, 6];
+
+pub const SESSION_STORE_PATH: &str = "sessions";
+pub const PRE_KEY_STORE_PATH: &str = "pre_keys.json";
+pub const SENDER_KEY_STORE_PATH: &str = "sender_keys";
+pub const IDENTITY_STORE_PATH: &str = "identities.json";
+pub const SIGNAL_VERSION: u8 = 3;
+pub const SIGNAL_DJB_TYPE: u8 = 0x05;
+pub const SIGNAL_MAC_SIZE: usize = 8;
+pub const SIGNAL_MAX_FORWARD_JUMPS: u32 = 25_000;
+pub const SIGNAL_MAX_MESSAGE_KEYS: usize = 2000;
+pub const SIGNAL_MAX_RECEIVER_CHAINS: usize = 5;
+pub const SIGNAL_MAX_ARCHIVED_SESSIONS: usize = 40;
+pub const SIGNAL_MAX_SENDER_KEY_STATES: usize = 5;
+pub const FINGERPRINT_VERSION: u16 = 0;
+pub const FINGERPRINT_SCANNABLE_VERSION: u32 = 1;
+pub const FINGERPRINT_ITERATIONS: usize = 5200;
+pub const WANTED_PRE_KEY_COUNT: u32 = 50;
+pub const MIN_PRE_KEY_COUNT: u32 = 5;
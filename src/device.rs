@@ -1,8 +1,9 @@
 use prost::Message;
 use rand_core::{OsRng, RngCore};
 use crate::proto::whatsapp::client_payload::{user_agent, web_info, DevicePairingRegistrationData, UserAgent, WebInfo};
-use crate::proto::whatsapp::{client_payload, device_props, ClientPayload, DeviceProps};
+use crate::proto::whatsapp::{client_payload, device_props, AdvSignedDeviceIdentity, ClientPayload, DeviceProps};
 use crate::proto::whatsapp::client_payload::user_agent::AppVersion;
+use crate::types::jid::JID;
 use crate::utils::key::{Key, PreKey};
 
 pub struct Device {
@@ -10,7 +11,12 @@ pub struct Device {
     pub identity_key: Key,
     pub signed_pre_key: PreKey,
     pub registration_id: u32,
-    pub adv_secret_key: [u8; 32]
+    pub adv_secret_key: [u8; 32],
+    pub jid: Option<JID>,
+    pub platform: String,
+    pub push_name: String,
+    pub business_name: String,
+    pub account: Option<AdvSignedDeviceIdentity>
 }
 
 impl Device {
@@ -25,7 +31,30 @@ impl Device {
             identity_key,
             signed_pre_key,
             registration_id: OsRng.next_u32(),
-            adv_secret_key: random_byte
+            adv_secret_key: random_byte,
+            jid: None,
+            platform: String::new(),
+            push_name: String::new(),
+            business_name: String::new(),
+            account: None
+        }
+    }
+
+    pub fn create_client_payload(&self) -> ClientPayload {
+        match &self.jid {
+            Some(jid) => self.create_login_payload(jid),
+            None => self.create_register_payload(),
+        }
+    }
+
+    pub fn create_login_payload(&self, jid: &JID) -> ClientPayload {
+        ClientPayload {
+            username: jid.user.as_deref().and_then(|user| user.parse().ok()),
+            device: Some(jid.device.unwrap_or(0) as u32),
+            passive: Some(true),
+            pull: Some(true),
+            lc: Some(1),
+            ..base_payload()
         }
     }
 
@@ -34,31 +63,6 @@ impl Device {
         let pre_key_id: [u8; 4] = self.signed_pre_key.id.to_be_bytes();
 
         ClientPayload {
-            user_agent: Some(UserAgent {
-                platform: Some(user_agent::Platform::Web.into()),
-                release_channel: Some(user_agent::ReleaseChannel::Release.into()),
-                app_version: Some(AppVersion {
-                    primary: Some(2),
-                    secondary: Some(3000),
-                    tertiary: Some(1022419966),
-                    ..Default::default()
-                }),
-                mcc: Some("000".to_string()),
-                mnc: Some("000".to_string()),
-                os_version: Some("0.1.0".to_string()),
-                manufacturer: Some("".to_string()),
-                device: Some("Desktop".to_string()),
-                os_build_number: Some("0.1.0".to_string()),
-                locale_language_iso6391: Some("en".to_string()),
-                locale_country_iso31661_alpha2: Some("en".to_string()),
-                ..Default::default()
-            }),
-            web_info: Some(WebInfo {
-                web_sub_platform: Some(web_info::WebSubPlatform::WebBrowser.into()),
-                ..Default::default()
-            }),
-            connect_type: Some(client_payload::ConnectType::WifiUnknown.into()),
-            connect_reason: Some(client_payload::ConnectReason::UserActivated.into()),
             device_pairing_data: Some(DevicePairingRegistrationData {
                 e_regid: Some(reg_id.to_vec()),
                 e_keytype: Some(vec![0x05]),
@@ -83,11 +87,42 @@ impl Device {
             }),
             passive: Some(false),
             pull: Some(false),
-            ..Default::default()
+            ..base_payload()
         }
     }
 }
 
+fn base_payload() -> ClientPayload {
+    ClientPayload {
+        user_agent: Some(UserAgent {
+            platform: Some(user_agent::Platform::Web.into()),
+            release_channel: Some(user_agent::ReleaseChannel::Release.into()),
+            app_version: Some(AppVersion {
+                primary: Some(2),
+                secondary: Some(3000),
+                tertiary: Some(1022419966),
+                ..Default::default()
+            }),
+            mcc: Some("000".to_string()),
+            mnc: Some("000".to_string()),
+            os_version: Some("0.1.0".to_string()),
+            manufacturer: Some("".to_string()),
+            device: Some("Desktop".to_string()),
+            os_build_number: Some("0.1.0".to_string()),
+            locale_language_iso6391: Some("en".to_string()),
+            locale_country_iso31661_alpha2: Some("en".to_string()),
+            ..Default::default()
+        }),
+        web_info: Some(WebInfo {
+            web_sub_platform: Some(web_info::WebSubPlatform::WebBrowser.into()),
+            ..Default::default()
+        }),
+        connect_type: Some(client_payload::ConnectType::WifiUnknown.into()),
+        connect_reason: Some(client_payload::ConnectReason::UserActivated.into()),
+        ..Default::default()
+    }
+}
+
 fn calculate_wa_version_hash() -> [u8; 16] {
     let version = "2.3000.1022419966";
     let digest = md5::compute(version.as_bytes());
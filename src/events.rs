@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use paris::error;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+
+use crate::connection::FailureReason;
+use crate::constant;
+use crate::proto::whatsapp::Message as WaMessage;
+use crate::signal::SignalError;
+use crate::types::jid::JID;
+use crate::utils::decoder::Node;
+use crate::utils::handler::PairError;
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    Qr { code: String },
+    PairCode { code: String },
+    PairSuccess { jid: JID, business_name: String, platform: String },
+    PairError(Arc<PairError>),
+    PairTimeout,
+    Connected,
+    Disconnected,
+    Reconnecting { attempt: u32, delay: Duration },
+    LoggedOut { on_connect: bool, reason: FailureReason },
+    ConnectFailure { reason: FailureReason, message: String },
+    StreamReplaced,
+    StreamError { code: String },
+    KeepAliveTimeout { error_count: u32, last_success: Instant },
+    KeepAliveRestored,
+    Message(Arc<Node>),
+    DecryptedMessage { node: Arc<Node>, message: Arc<WaMessage> },
+    UndecryptableMessage { node: Arc<Node>, error: Arc<SignalError> },
+    IdentityChanged { jid: JID, previous: Option<[u8; 32]>, identity: [u8; 32], trusted: bool },
+    Receipt(Arc<Node>),
+    Notification(Arc<Node>),
+    Call(Arc<Node>),
+    Presence(Arc<Node>),
+}
+
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+    handlers: HashMap<usize, JoinHandle<()>>,
+    next_handler_id: usize,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(constant::EVENT_BUFFER_SIZE);
+        Self { sender, handlers: HashMap::new(), next_handler_id: 0 }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    pub fn sender(&self) -> broadcast::Sender<Event> {
+        self.sender.clone()
+    }
+
+    /// Runs `handler` for every event on its own task, one event at a time
+    /// and in the order they were dispatched.
+    pub fn add_handler<F, Fut>(&mut self, handler: F) -> usize
+    where
+        F: Fn(Event) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let mut receiver = self.sender.subscribe();
+        let task = tokio::spawn(async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => handler(event).await,
+                    Err(RecvError::Lagged(missed)) => error!("Event handler lagged behind, {} events dropped", missed),
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+
+        let id = self.next_handler_id;
+        self.next_handler_id += 1;
+        self.handlers.insert(id, task);
+        id
+    }
+
+    pub fn remove_handler(&mut self, id: usize) -> bool {
+        match self.handlers.remove(&id) {
+            Some(task) => {
+                task.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn dispatch(&self, event: Event) {
+        // No receivers is fine, nobody is listening yet
+        let _ = self.sender.send(event);
+    }
+}
+
+impl Drop for EventBus {
+    fn drop(&mut self) {
+        for task in self.handlers.values() {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::{Event, EventBus};
+
+    #[tokio::test]
+    async fn delivers_to_every_subscriber_until_removed() {
+        let mut bus = EventBus::new();
+        let mut subscriber = bus.subscribe();
+        let (sender, mut handled) = mpsc::unbounded_channel();
+        let first = bus.add_handler({
+            let sender = sender.clone();
+            move |event| {
+                let sender = sender.clone();
+                async move { let _ = sender.send((1, event)); }
+            }
+        });
+        bus.add_handler(move |event| {
+            let sender = sender.clone();
+            async move { let _ = sender.send((2, event)); }
+        });
+
+        bus.dispatch(Event::Qr { code: "ref-1".into() });
+        assert!(matches!(subscriber.recv().await, Ok(Event::Qr { code }) if code == "ref-1"));
+        let mut handlers = vec![handled.recv().await.unwrap().0, handled.recv().await.unwrap().0];
+        handlers.sort();
+        assert_eq!(handlers, [1, 2]);
+
+        assert!(bus.remove_handler(first));
+        assert!(!bus.remove_handler(first));
+        bus.dispatch(Event::PairTimeout);
+        assert!(matches!(handled.recv().await, Some((2, Event::PairTimeout))));
+        assert!(matches!(subscriber.recv().await, Ok(Event::PairTimeout)));
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use paris::error;
+use prost::Message;
+use tokio::sync::Mutex;
+
+use crate::client::Client;
+use crate::message::{enc_node, pad_message, SendMessageError};
+use crate::proto::whatsapp::message::SenderKeyDistributionMessage;
+use crate::proto::whatsapp::Message as WaMessage;
+use crate::signal::group::{self, SenderKeyDistribution};
+use crate::signal::SignalError;
+use crate::types::jid::{self, JID};
+use crate::utils::decoder::{Node, Value};
+
+impl Client {
+    /// Starts following the sender key `sender` uses in `group`.
+    pub fn process_sender_key_distribution(&mut self, group: &JID, sender: &JID, distribution: &[u8]) -> Result<(), SignalError> {
+        let distribution = SenderKeyDistribution::parse(distribution)?;
+        let (group, sender) = (group.to_string(), sender.signal_address());
+        let mut record = self.sender_key_store.load_sender_key(&group, &sender)?.unwrap_or_default();
+        group::process_distribution(&mut record, &distribution);
+        self.sender_key_store.store_sender_key(&group, &sender, &record)?;
+        Ok(())
+    }
+
+    /// Decrypts the content of an `skmsg` enc node `sender` sent to `group`.
+    pub fn decrypt_group(&mut self, group: &JID, sender: &JID, ciphertext: &[u8]) -> Result<Vec<u8>, SignalError> {
+        let (group, sender) = (group.to_string(), sender.signal_address());
+        let mut record = self.sender_key_store.load_sender_key(&group, &sender)?.ok_or(SignalError::NoSession)?;
+        let plaintext = group::decrypt(&mut record, ciphertext)?;
+        self.sender_key_store.store_sender_key(&group, &sender, &record)?;
+        Ok(plaintext)
+    }
+
+    /// Encrypts `message` for `group`, whose members own `devices`. Returns the
+    /// content of the message stanza: a `participants` node handing our sender
+    /// key to the devices that don't have it yet, then the `skmsg` enc node.
+    pub async fn encrypt_group_message(client: &Arc<Mutex<Client>>, group: &JID, devices: &[JID], message: &WaMessage) -> Result<Vec<Node>, SendMessageError> {
+        let undistributed: Vec<JID> = {
+            let mut client = client.lock().await;
+            let distributed = client.sender_key_store.load_distributed_devices(&group.to_string())?;
+            let addresses: Vec<String> = devices.iter().map(JID::signal_address).collect();
+            // Whoever left must not be able to read what comes next
+            if distributed.iter().any(|address| !addresses.contains(address)) {
+                client.rotate_sender_key(group)?;
+            }
+            let distributed = client.sender_key_store.load_distributed_devices(&group.to_string())?;
+            devices.iter().filter(|device| !distributed.contains(&device.signal_address())).cloned().collect()
+        };
+
+        Client::ensure_sessions(client, &undistributed).await?;
+        client.lock().await.build_group_message(group, &undistributed, message)
+    }
+
+    /// Drops our sender key for `group`, the next message goes out with a new one.
+    pub fn rotate_sender_key(&mut self, group: &JID) -> Result<(), SendMessageError> {
+        let group = group.to_string();
+        self.sender_key_store.delete_sender_key(&group, &self.own_signal_address()?)?;
+        self.sender_key_store.store_distributed_devices(&group, &[])?;
+        Ok(())
+    }
+
+    pub async fn handle_group_notification(&mut self, node: &Arc<Node>) {
+        let membership_shrunk = node.children().iter().any(|child| matches!(child.tag.as_str(), "remove" | "leave"));
+        if membership_shrunk
+            && let Some(group) = node.get_jid_attr("from")
+            && let Err(e) = self.rotate_sender_key(&group.clone()) {
+            error!("Failed to rotate sender key for {}: {}", group, e);
+        }
+    }
+
+    /// Follows the sender key that came with a message `sender` sent to `chat`.
+    /// The group comes from the stanza, never from what the sender claims.
+    pub fn handle_sender_key_distribution(&mut self, chat: &JID, sender: &JID, distribution: &SenderKeyDistributionMessage) {
+        if chat.server.as_deref() != Some(jid::GROUP_SERVER) {
+            error!("Ignoring sender key from {} outside a group chat: {}", sender, chat);
+            return;
+        }
+        if let Err(e) = self.process_sender_key_distribution(chat, sender, distribution.axolotl_sender_key_distribution_message()) {
+            error!("Failed to process sender key from {}: {}", sender, e);
+        }
+    }
+
+    /// Encrypts `message` with our sender key, handing the key to `undistributed` first.
+    fn build_group_message(&mut self, group: &JID, undistributed: &[JID], message: &WaMessage) -> Result<Vec<Node>, SendMessageError> {
+        let group_id = group.to_string();
+        let own = self.own_signal_address()?;
+        let mut record = self.sender_key_store.load_sender_key(&group_id, &own)?.unwrap_or_default();
+        let distribution = group::create_sender_key(&mut record)?;
+        let distribution = pad_message(&WaMessage {
+            sender_key_distribution_message: Some(SenderKeyDistributionMessage {
+                group_id: Some(group_id.clone()),
+                axolotl_sender_key_distribution_message: Some(distribution.serialize()),
+            }),
+            ..Default::default()
+        }.encode_to_vec());
+
+        let participants = self.encrypt_for_devices(undistributed, |_| &distribution);
+        let mut distributed = self.sender_key_store.load_distributed_devices(&group_id)?;
+        distributed.extend(participants.iter().filter_map(|to| to.get_jid_attr("jid")).map(JID::signal_address));
+
+        let ciphertext = group::encrypt(&mut record, &pad_message(&message.encode_to_vec()))?;
+        self.sender_key_store.store_sender_key(&group_id, &own, &record)?;
+        self.sender_key_store.store_distributed_devices(&group_id, &distributed)?;
+
+        let mut content = Vec::new();
+        if !participants.is_empty() {
+            content.push(Node::new("participants".to_string(), HashMap::new(), Some(Value::List(participants))));
+        }
+        content.push(enc_node("skmsg", ciphertext));
+        Ok(content)
+    }
+
+    fn own_signal_address(&self) -> Result<String, SendMessageError> {
+        self.device.jid.as_ref().map(JID::signal_address).ok_or(SendMessageError::NotLoggedIn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+
+    use crate::client::Client;
+    use crate::device::Device;
+    use crate::events::Event;
+    use crate::message::{pad_message, unpad_message};
+    use crate::proto::whatsapp::message::SenderKeyDistributionMessage;
+    use crate::proto::whatsapp::{Message as WaMessage, RecordStructure, SenderKeyRecordStructure};
+    use crate::signal::cipher::{self, LocalIdentity, PreKeyMessage, WhisperMessage};
+    use crate::signal::group::{self, SenderKeyDistribution};
+    use crate::store::device::{DeviceStore, memory::MemoryDeviceStore};
+    use crate::testing::mock_server::{child_bytes, content_bytes, MockServer, node, pre_key_bundle_node};
+    use crate::testing::next_event;
+    use crate::types::jid::{self, JID};
+    use crate::utils::decoder::Value;
+    use crate::utils::key::Key;
+
+    #[tokio::test]
+    async fn takes_the_group_from_the_stanza() {
+        let server = MockServer::start().await;
+        let client = Client::new(server.config()).unwrap();
+        let mut client = client.lock().await;
+        let group = JID::new(Some("120363000000000000".into()), None, None, None, Some(jid::GROUP_SERVER.into()));
+        let sender = JID::new_ad("6281234567890".into(), 0, 3);
+        let distribution = SenderKeyDistributionMessage {
+            group_id: Some("../../elsewhere@g.us".into()),
+            axolotl_sender_key_distribution_message: Some(group::create_sender_key(&mut SenderKeyRecordStructure::default()).unwrap().serialize()),
+        };
+
+        client.handle_sender_key_distribution(&sender.to_non_ad(), &sender, &distribution);
+        assert!(client.sender_key_store.load_sender_key(&sender.to_non_ad().to_string(), &sender.signal_address()).unwrap().is_none());
+
+        client.handle_sender_key_distribution(&group, &sender, &distribution);
+        assert!(client.sender_key_store.load_sender_key(&group.to_string(), &sender.signal_address()).unwrap().is_some());
+        assert!(client.sender_key_store.load_sender_key("../../elsewhere@g.us", &sender.signal_address()).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn exchanges_group_messages_with_sender_keys() {
+        let mut server = MockServer::start().await;
+        let store = MemoryDeviceStore::default();
+        let mut device = Device::new();
+        device.jid = Some(JID::new_ad("6289876543210".into(), 0, 2));
+        store.save(&device).unwrap();
+        let client = Client::new(server.config_with_store(store)).unwrap();
+        let mut events = client.lock().await.subscribe();
+        let (connected, mut conn) = tokio::join!(Client::connect(&client), server.accept());
+        connected.unwrap();
+
+        let group = JID::new(Some("120363000000000000".into()), None, None, None, Some("g.us".into()));
+        let peer = JID::new_ad("6281234567890".into(), 0, 3);
+        let peer_identity = Key::new();
+        let peer_signed_pre_key = peer_identity.create_signed_pre_key(9);
+        let peer_local = LocalIdentity { identity_key: &peer_identity, registration_id: 77 };
+        let mut peer_session = RecordStructure::default();
+        let mut peer_sender_keys = SenderKeyRecordStructure::default();
+        let hello = WaMessage { conversation: Some("hello group".into()), ..Default::default() };
+
+        // The first message fetches a bundle and hands our sender key over a new session
+        let (content, _) = tokio::join!(Client::encrypt_group_message(&client, &group, std::slice::from_ref(&peer), &hello), async {
+            let request = conn.expect_node("iq").await;
+            let bundle = pre_key_bundle_node(&peer, 77, &peer_identity, &peer_signed_pre_key);
+            conn.send_iq_result(&request, Some(Value::List(vec![node("list", &[], Some(Value::List(vec![bundle])))]))).await;
+        });
+        let content = content.unwrap();
+        let to = &content[0].children()[0];
+        assert_eq!(to.get_jid_attr("jid"), Some(&peer));
+        assert_eq!(to.children()[0].get_attr("type"), Some("pkmsg"));
+        let pre_key_message = PreKeyMessage::parse(&child_bytes(to, "enc")).unwrap();
+        let (plaintext, _) = cipher::decrypt_pre_key(&mut peer_session, &peer_local, Some(&peer_signed_pre_key.key), None, &pre_key_message).unwrap();
+        let distribution = WaMessage::decode(unpad_message(&plaintext).unwrap()).unwrap().sender_key_distribution_message.unwrap();
+        assert_eq!(distribution.group_id(), "120363000000000000@g.us");
+        let first_key = SenderKeyDistribution::parse(distribution.axolotl_sender_key_distribution_message()).unwrap();
+        group::process_distribution(&mut peer_sender_keys, &first_key);
+
+        assert_eq!(content[1].get_attr("type"), Some("skmsg"));
+        let plaintext = group::decrypt(&mut peer_sender_keys, &content_bytes(&content[1])).unwrap();
+        assert_eq!(WaMessage::decode(unpad_message(&plaintext).unwrap()).unwrap(), hello);
+
+        // Once handed out, only the skmsg goes out
+        let content = Client::encrypt_group_message(&client, &group, std::slice::from_ref(&peer), &hello).await.unwrap();
+        assert_eq!(content.len(), 1);
+        assert!(group::decrypt(&mut peer_sender_keys, &content_bytes(&content[0])).is_ok());
+
+        // The peer hands us its own sender key and writes to the group
+        let mut peer_own_key = SenderKeyRecordStructure::default();
+        let distribution = WaMessage {
+            sender_key_distribution_message: Some(SenderKeyDistributionMessage {
+                group_id: Some(group.to_string()),
+                axolotl_sender_key_distribution_message: Some(group::create_sender_key(&mut peer_own_key).unwrap().serialize()),
+            }),
+            ..Default::default()
+        };
+        let distribution = cipher::encrypt(&mut peer_session, &pad_message(&distribution.encode_to_vec())).unwrap();
+        let reply = WaMessage { conversation: Some("hi all".into()), ..Default::default() };
+        let reply = group::encrypt(&mut peer_own_key, &pad_message(&reply.encode_to_vec())).unwrap();
+        conn.send_node(&node("message", &[
+            ("id", Value::Str("message-1".into())),
+            ("from", Value::Jid(group.clone())),
+            ("participant", Value::Jid(peer.clone())),
+            ("type", Value::Str("text".into())),
+        ], Some(Value::List(vec![
+            node("enc", &[("v", Value::Str("2".into())), ("type", Value::Str(distribution.enc_type().into()))], Some(Value::Bytes(distribution.into_bytes()))),
+            node("enc", &[("v", Value::Str("2".into())), ("type", Value::Str("skmsg".into()))], Some(Value::Bytes(reply))),
+        ])))).await;
+        conn.expect_node("ack").await;
+        assert!(matches!(next_event(&mut events).await, Event::Message(_)));
+        assert!(matches!(next_event(&mut events).await, Event::DecryptedMessage { message, .. } if message.sender_key_distribution_message.is_some()));
+        match next_event(&mut events).await {
+            Event::DecryptedMessage { message, .. } => assert_eq!(message.conversation.as_deref(), Some("hi all")),
+            other => panic!("expected a decrypted group message, got {:?}", other),
+        }
+
+        // Someone leaving makes us start over with a new sender key
+        conn.send_node(&node("notification", &[
+            ("id", Value::Str("notification-1".into())),
+            ("from", Value::Jid(group.clone())),
+            ("type", Value::Str("w:gp2".into())),
+        ], Some(Value::List(vec![node("remove", &[], Some(Value::List(vec![
+            node("participant", &[("jid", Value::Jid(JID::new_ad("6285555555555".into(), 0, 0)))], None),
+        ])))])))).await;
+        conn.expect_node("ack").await;
+        assert!(matches!(next_event(&mut events).await, Event::Notification(_)));
+
+        let content = Client::encrypt_group_message(&client, &group, std::slice::from_ref(&peer), &hello).await.unwrap();
+        assert_eq!(content.len(), 2);
+        let to = &content[0].children()[0];
+        let plaintext = cipher::decrypt(&mut peer_session, &WhisperMessage::parse(&child_bytes(to, "enc")).unwrap()).unwrap();
+        let distribution = WaMessage::decode(unpad_message(&plaintext).unwrap()).unwrap().sender_key_distribution_message.unwrap();
+        let second_key = SenderKeyDistribution::parse(distribution.axolotl_sender_key_distribution_message()).unwrap();
+        assert_ne!(second_key.id, first_key.id);
+    }
+}
@@ -0,0 +1,186 @@
+use std::str::FromStr;
+
+use paris::warn;
+
+use crate::client::Client;
+use crate::events::Event;
+use crate::signal::fingerprint::{self, Fingerprint};
+use crate::signal::SignalError;
+use crate::store::StoreError;
+use crate::types::jid::JID;
+
+/// What to do with an identity key that doesn't match the one we know.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrustPolicy {
+    /// Trust the first key a contact uses, refuse a different one until
+    /// [`Client::trust_identity`] approves it.
+    TrustOnFirstUse,
+    /// Replace the stored key whenever it changes.
+    AlwaysTrust,
+    /// Only talk to keys approved with [`Client::trust_identity`], even the first one.
+    Block,
+}
+
+impl FromStr for TrustPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first-use" => Ok(TrustPolicy::TrustOnFirstUse),
+            "always" => Ok(TrustPolicy::AlwaysTrust),
+            "block" => Ok(TrustPolicy::Block),
+            other => Err(format!("unknown trust policy {}, expected first-use, always or block", other)),
+        }
+    }
+}
+
+impl Client {
+    /// Checks `identity` against the key we know for `device`, emitting
+    /// [`Event::IdentityChanged`] when they differ.
+    pub fn check_identity(&mut self, device: &JID, identity: &[u8; 32]) -> Result<(), SignalError> {
+        let address = device.signal_address();
+        let previous = self.identity_store.load_identity(&address)?;
+        if previous.as_ref() == Some(identity) {
+            return Ok(());
+        }
+
+        let trusted = matches!((previous, self.trust_policy), (None, TrustPolicy::TrustOnFirstUse) | (_, TrustPolicy::AlwaysTrust));
+        if previous.is_some() || !trusted {
+            warn!("Identity of {} changed, {}", device, if trusted { "trusting it" } else { "refusing it" });
+            self.dispatch_event(Event::IdentityChanged { jid: device.clone(), previous, identity: *identity, trusted });
+        }
+        if !trusted {
+            return Err(SignalError::UntrustedIdentity(address));
+        }
+        self.identity_store.store_identity(&address, identity)?;
+        Ok(())
+    }
+
+    /// Approves `identity` for `device`, replacing any key we knew before.
+    pub fn trust_identity(&mut self, device: &JID, identity: &[u8; 32]) -> Result<(), StoreError> {
+        self.identity_store.store_identity(&device.signal_address(), identity)
+    }
+
+    /// The safety number between us and `contact`, or `None` while we're
+    /// not logged in or haven't seen the contact's identity yet.
+    pub fn safety_number(&self, contact: &JID) -> Result<Option<Fingerprint>, StoreError> {
+        let Some(own) = self.device.jid.as_ref() else { return Ok(None) };
+        let Some(identity) = self.identity_store.load_identity(&contact.signal_address())? else { return Ok(None) };
+        Ok(Some(fingerprint::fingerprint(
+            own.user.as_deref().unwrap_or_default(),
+            self.device.identity_key.public.as_bytes(),
+            contact.user.as_deref().unwrap_or_default(),
+            &identity,
+        )))
+    }
+
+    /// Checks the code scanned off the screen of `contact` against our safety
+    /// number, `None` while there's nothing to compare with.
+    pub fn verify_safety_number(&self, contact: &JID, scanned: &[u8]) -> Result<Option<bool>, SignalError> {
+        let Some(ours) = self.safety_number(contact)? else { return Ok(None) };
+        fingerprint::compare_scannable(&ours.scannable, scanned).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+
+    use crate::client::Client;
+    use crate::device::Device;
+    use crate::events::Event;
+    use crate::message::pad_message;
+    use crate::proto::whatsapp::{Message as WaMessage, RecordStructure};
+    use crate::signal::cipher::{self, LocalIdentity};
+    use crate::signal::{fingerprint, SignalError};
+    use crate::store::device::{DeviceStore, memory::MemoryDeviceStore};
+    use crate::testing::mock_server::{MockServer, node};
+    use crate::testing::{device_bundle, next_event};
+    use crate::types::jid::JID;
+    use crate::utils::decoder::Value;
+    use crate::utils::key::Key;
+
+    use super::TrustPolicy;
+
+    #[tokio::test]
+    async fn enforces_the_identity_trust_policy() {
+        let mut server = MockServer::start().await;
+        let store = MemoryDeviceStore::default();
+        let mut device = Device::new();
+        device.jid = Some(JID::new_ad("6289876543210".into(), 0, 0));
+        store.save(&device).unwrap();
+        let client = Client::new(server.config_with_store(store)).unwrap();
+        let mut events = client.lock().await.subscribe();
+        let (connected, mut conn) = tokio::join!(Client::connect(&client), server.accept());
+        connected.unwrap();
+
+        let peer = JID::new_ad("6281234567890".into(), 0, 0);
+        let bundle = device_bundle(&client.lock().await.device, None);
+        let pre_key_message = |identity: &Key, id: &str| {
+            let mut record = RecordStructure::default();
+            cipher::process_bundle(&mut record, &LocalIdentity { identity_key: identity, registration_id: 77 }, &bundle).unwrap();
+            let message = WaMessage { conversation: Some("hello".into()), ..Default::default() };
+            let message = cipher::encrypt(&mut record, &pad_message(&message.encode_to_vec())).unwrap();
+            node("message", &[
+                ("id", Value::Str(id.into())),
+                ("from", Value::Jid(peer.clone())),
+                ("type", Value::Str("text".into())),
+            ], Some(Value::List(vec![node("enc", &[
+                ("v", Value::Str("2".into())),
+                ("type", Value::Str(message.enc_type().into())),
+            ], Some(Value::Bytes(message.into_bytes())))])))
+        };
+
+        // The first identity is trusted and remembered
+        let first_identity = Key::new();
+        conn.send_node(&pre_key_message(&first_identity, "message-1")).await;
+        conn.expect_node("ack").await;
+        assert!(matches!(next_event(&mut events).await, Event::Message(_)));
+        assert!(matches!(next_event(&mut events).await, Event::DecryptedMessage { .. }));
+        let safety_number = client.lock().await.safety_number(&peer).unwrap().unwrap();
+        assert_eq!(safety_number.display.len(), 60);
+        let own_identity = client.lock().await.device.identity_key.public.to_bytes();
+        let peer_view = fingerprint::fingerprint("6281234567890", &first_identity.public.to_bytes(), "6289876543210", &own_identity);
+        assert_eq!(peer_view.display, safety_number.display);
+        assert_eq!(client.lock().await.verify_safety_number(&peer, &peer_view.scannable).unwrap(), Some(true));
+
+        // A reinstall comes with a new identity, which has to be approved first
+        let second_identity = Key::new();
+        let message = pre_key_message(&second_identity, "message-2");
+        conn.send_node(&message).await;
+        conn.expect_node("ack").await;
+        assert!(matches!(next_event(&mut events).await, Event::Message(_)));
+        match next_event(&mut events).await {
+            Event::IdentityChanged { jid, previous, identity, trusted } => {
+                assert_eq!(jid.to_string(), peer.to_string());
+                assert_eq!(previous, Some(first_identity.public.to_bytes()));
+                assert_eq!(identity, second_identity.public.to_bytes());
+                assert!(!trusted);
+            }
+            other => panic!("expected an identity change, got {:?}", other),
+        }
+        assert!(matches!(next_event(&mut events).await, Event::UndecryptableMessage { error, .. } if matches!(*error, SignalError::UntrustedIdentity(_))));
+
+        client.lock().await.trust_identity(&peer, &second_identity.public.to_bytes()).unwrap();
+        conn.send_node(&message).await;
+        conn.expect_node("ack").await;
+        assert!(matches!(next_event(&mut events).await, Event::Message(_)));
+        assert!(matches!(next_event(&mut events).await, Event::DecryptedMessage { .. }));
+        assert_ne!(client.lock().await.safety_number(&peer).unwrap().unwrap(), safety_number);
+
+        let mut client = client.lock().await;
+        let third_identity = Key::new().public.to_bytes();
+        assert_eq!(client.verify_safety_number(&peer, &peer_view.scannable).unwrap(), Some(false));
+        client.trust_policy = "always".parse().unwrap();
+        client.check_identity(&peer, &third_identity).unwrap();
+        assert!(matches!(next_event(&mut events).await, Event::IdentityChanged { trusted: true, .. }));
+
+        client.trust_policy = "block".parse().unwrap();
+        assert!("sometimes".parse::<TrustPolicy>().is_err());
+        let stranger = JID::new_ad("6285555555555".into(), 0, 0);
+        assert!(matches!(client.check_identity(&stranger, &[7; 32]), Err(SignalError::UntrustedIdentity(_))));
+        assert!(matches!(next_event(&mut events).await, Event::IdentityChanged { previous: None, trusted: false, .. }));
+        assert!(client.safety_number(&stranger).unwrap().is_none());
+        client.check_identity(&peer, &third_identity).unwrap();
+    }
+}
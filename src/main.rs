@@ -1,25 +1,85 @@
-use crate::client::{connect, Events};
+use std::sync::Arc;
+
+use paris::error;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::Mutex;
+
+use crate::client::{connect, Client, Config};
+use crate::events::Event;
+use crate::identity::TrustPolicy;
+use crate::utils::qr;
 
 mod proto;
 mod constant;
 mod client;
+mod connection;
+mod router;
+mod message;
+mod pre_key;
+mod group;
+mod identity;
+mod signal;
+mod events;
 mod utils;
 mod socket;
 mod device;
 mod types;
 mod request;
+mod store;
+#[cfg(test)]
+mod testing;
 
-struct MyClient {}
+async fn handle_event(event: Event, show_qr: bool) {
+    match event {
+        Event::Qr { code } if show_qr => {
+            match qr::render_terminal(&code) {
+                Ok(rendered) => println!("{}", rendered),
+                Err(_) => println!("QR Code: {}", code),
+            }
+            if let Ok(png) = qr::render_png(&code) {
+                let _ = std::fs::write("qr.png", png);
+            }
+            if let Ok(svg) = qr::render_svg(&code) {
+                let _ = std::fs::write("qr.svg", svg);
+            }
+        }
+        Event::PairCode { code } => println!("Enter {} on your phone under Linked devices > Link with phone number", code),
+        _ => {}
+    }
+}
 
-impl Events for MyClient {
-    fn on_qr(&self, qr: &str) {
-        println!("QR Code: {}", qr);
+/// Asks for a pairing code once the server is ready to pair, which it
+/// signals with the first QR code. The code arrives as [`Event::PairCode`].
+async fn pair_phone(client: Arc<Mutex<Client>>, mut events: broadcast::Receiver<Event>, phone: String) {
+    loop {
+        match events.recv().await {
+            Ok(Event::Qr { .. }) => break,
+            Ok(_) | Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return,
+        }
+    }
+    if let Err(e) = Client::pair_phone(&client, &phone, true).await {
+        error!("Failed to request a pairing code: {}", e);
     }
 }
 
 #[tokio::main]
 async fn main() {
-    let client = connect(MyClient{}).await;
+    // Passing a phone number links with a pairing code instead of the QR code
+    let phone = std::env::args().nth(1);
+    let trust_policy = match std::env::var("WHATSRUSTY_TRUST_POLICY") {
+        Ok(policy) => policy.parse().unwrap_or_else(|e| {
+            error!("Invalid WHATSRUSTY_TRUST_POLICY: {}, trusting on first use", e);
+            TrustPolicy::TrustOnFirstUse
+        }),
+        Err(_) => TrustPolicy::TrustOnFirstUse,
+    };
+    let show_qr = phone.is_none();
+    let config = Config { trust_policy, ..Default::default() };
+    let (client, events) = connect(config, move |event| handle_event(event, show_qr)).await;
+    if let Some(phone) = phone {
+        tokio::spawn(pair_phone(client, events, phone));
+    }
 
-    loop {}
+    std::future::pending::<()>().await
 }
@@ -0,0 +1,541 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use paris::error;
+use prost::Message;
+use rand_core::{OsRng, RngCore};
+use tokio::sync::Mutex;
+
+use crate::client::Client;
+use crate::events::Event;
+use crate::proto::whatsapp::message::DeviceSentMessage;
+use crate::proto::whatsapp::{Message as WaMessage, PreKeyRecordStructure};
+use crate::request::{InfoQuery, IqError, SendError};
+use crate::signal::cipher::{self, CiphertextMessage, LocalIdentity, PreKeyBundle, PreKeyMessage, WhisperMessage};
+use crate::signal::SignalError;
+use crate::store::StoreError;
+use crate::types::jid::{self, JID};
+use crate::utils::decoder::{Node, Value};
+use crate::utils::key::Key;
+
+#[derive(Debug)]
+pub enum SendMessageError {
+    NotLoggedIn,
+    Signal(SignalError),
+    Iq(IqError),
+    Send(SendError),
+}
+
+impl fmt::Display for SendMessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendMessageError::NotLoggedIn => write!(f, "not logged in"),
+            SendMessageError::Signal(e) => write!(f, "{}", e),
+            SendMessageError::Iq(e) => write!(f, "failed to fetch pre-keys: {}", e),
+            SendMessageError::Send(e) => write!(f, "failed to send message: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SendMessageError {}
+
+impl From<SignalError> for SendMessageError {
+    fn from(e: SignalError) -> Self {
+        SendMessageError::Signal(e)
+    }
+}
+
+impl From<StoreError> for SendMessageError {
+    fn from(e: StoreError) -> Self {
+        SendMessageError::Signal(SignalError::Store(e))
+    }
+}
+
+impl From<IqError> for SendMessageError {
+    fn from(e: IqError) -> Self {
+        SendMessageError::Iq(e)
+    }
+}
+
+impl From<SendError> for SendMessageError {
+    fn from(e: SendError) -> Self {
+        SendMessageError::Send(e)
+    }
+}
+
+impl Client {
+    /// Encrypts `message` for every device in `devices` and sends it to `to`,
+    /// returning the message id. `devices` lists the devices of the recipient,
+    /// or of every group member, and our other devices.
+    pub async fn send_message(client: &Arc<Mutex<Client>>, to: &JID, devices: &[JID], message: &WaMessage) -> Result<String, SendMessageError> {
+        let own = client.lock().await.device.jid.clone().ok_or(SendMessageError::NotLoggedIn)?;
+        let devices: Vec<JID> = devices.iter().filter(|device| device.signal_address() != own.signal_address()).cloned().collect();
+        let content = match to.server.as_deref() {
+            Some(jid::GROUP_SERVER) => Client::encrypt_group_message(client, to, &devices, message).await?,
+            _ => Client::encrypt_direct_message(client, to, &own, &devices, message).await?,
+        };
+
+        let id = generate_message_id();
+        let mut client = client.lock().await;
+        let node = client.message_node(&id, to, message, content);
+        client.send_node(node).await?;
+        Ok(id)
+    }
+
+    /// Encrypts `message` for each device on its own, our devices get it
+    /// wrapped so they know which chat it belongs to.
+    async fn encrypt_direct_message(client: &Arc<Mutex<Client>>, to: &JID, own: &JID, devices: &[JID], message: &WaMessage) -> Result<Vec<Node>, SendMessageError> {
+        Client::ensure_sessions(client, devices).await?;
+
+        let plaintext = pad_message(&message.encode_to_vec());
+        let own_copy = pad_message(&WaMessage {
+            device_sent_message: Some(Box::new(DeviceSentMessage {
+                destination_jid: Some(to.to_string()),
+                message: Some(Box::new(message.clone())),
+                phash: None,
+            })),
+            ..Default::default()
+        }.encode_to_vec());
+        let participants = client.lock().await.encrypt_for_devices(devices, |device| match device.user == own.user {
+            true => &own_copy,
+            false => &plaintext,
+        });
+        Ok(vec![Node::new("participants".to_string(), HashMap::new(), Some(Value::List(participants)))])
+    }
+
+    /// Starts a session with every device in `devices` we don't have one with yet.
+    /// Devices without pre-keys are only logged, encrypting for them fails later.
+    pub(crate) async fn ensure_sessions(client: &Arc<Mutex<Client>>, devices: &[JID]) -> Result<(), SendMessageError> {
+        let missing_sessions = {
+            let client = client.lock().await;
+            let mut missing_sessions = Vec::new();
+            for device in devices {
+                let session = client.session_store.load_session(&device.signal_address())?;
+                if session.is_none_or(|record| record.current_session.is_none()) {
+                    missing_sessions.push(device.clone());
+                }
+            }
+            missing_sessions
+        };
+        if missing_sessions.is_empty() {
+            return Ok(());
+        }
+
+        let bundles = Client::fetch_pre_key_bundles(client, &missing_sessions).await?;
+        let mut client = client.lock().await;
+        for (device, bundle) in bundles {
+            if let Err(e) = bundle.and_then(|bundle| client.process_pre_key_bundle(&device, &bundle)) {
+                error!("Failed to start a session with {}: {}", device, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Asks the server for a pre-key bundle of every device in `devices`,
+    /// pairing each device with its bundle or the reason there is none.
+    pub async fn fetch_pre_key_bundles(client: &Arc<Mutex<Client>>, devices: &[JID]) -> Result<Vec<(JID, Result<PreKeyBundle, SignalError>)>, IqError> {
+        let users = devices.iter().map(|device| {
+            let mut attr = HashMap::new();
+            attr.insert("jid".to_string(), Value::Jid(device.clone()));
+            attr.insert("reason".to_string(), Value::Str("identity".to_string()));
+            Node::new("user".to_string(), attr, None)
+        }).collect();
+
+        let response = client.lock().await.send_iq(InfoQuery {
+            namespace: Some("encrypt".into()),
+            r#type: Some("get".into()),
+            to: Some(JID::new(None, None, None, None, Some(jid::DEFAULT_USER_SERVER.into()))),
+            content: Some(Value::List(vec![Node::new("key".to_string(), HashMap::new(), Some(Value::List(users)))])),
+            ..Default::default()
+        }).await;
+        let response = response.await?;
+
+        let Some(list) = response.get_child("list") else { return Ok(Vec::new()) };
+        Ok(list.children().iter()
+            .filter(|user| user.tag == "user")
+            .filter_map(|user| Some((user.get_jid_attr("jid")?.clone(), parse_pre_key_bundle(user))))
+            .collect())
+    }
+
+    pub fn process_pre_key_bundle(&mut self, device: &JID, bundle: &PreKeyBundle) -> Result<(), SignalError> {
+        let address = device.signal_address();
+        self.check_identity(device, &bundle.identity_key)?;
+        let mut record = self.session_store.load_session(&address)?.unwrap_or_default();
+        cipher::process_bundle(&mut record, &self.local_identity(), bundle)?;
+        self.session_store.store_session(&address, &record)?;
+        Ok(())
+    }
+
+    pub fn encrypt_for(&mut self, device: &JID, plaintext: &[u8]) -> Result<CiphertextMessage, SignalError> {
+        let address = device.signal_address();
+        let mut record = self.session_store.load_session(&address)?.ok_or(SignalError::NoSession)?;
+        let message = cipher::encrypt(&mut record, plaintext)?;
+        self.session_store.store_session(&address, &record)?;
+        Ok(message)
+    }
+
+    /// Decrypts the content of a `pkmsg` or `msg` enc node sent by `device`.
+    pub fn decrypt_from(&mut self, device: &JID, enc_type: &str, ciphertext: &[u8]) -> Result<Vec<u8>, SignalError> {
+        let address = device.signal_address();
+        let mut record = self.session_store.load_session(&address)?.unwrap_or_default();
+        let (plaintext, used_pre_key) = match enc_type {
+            "pkmsg" => {
+                let message = PreKeyMessage::parse(ciphertext)?;
+                self.check_identity(device, &message.identity_key)?;
+                let signed_pre_key = &self.device.signed_pre_key;
+                let signed_pre_key = (signed_pre_key.id == message.signed_pre_key_id).then_some(&signed_pre_key.key);
+                let one_time_pre_key = match message.pre_key_id {
+                    Some(id) => self.pre_key_store.load_pre_key(id)?.map(|pre_key| pre_key_from_record(&pre_key)).transpose()?,
+                    None => None,
+                };
+                cipher::decrypt_pre_key(&mut record, &self.local_identity(), signed_pre_key, one_time_pre_key.as_ref(), &message)?
+            }
+            "msg" => (cipher::decrypt(&mut record, &WhisperMessage::parse(ciphertext)?)?, None),
+            other => return Err(SignalError::InvalidMessage(format!("unsupported enc type {}", other))),
+        };
+        self.session_store.store_session(&address, &record)?;
+        if let Some(id) = used_pre_key {
+            self.pre_key_store.remove_pre_key(id)?;
+        }
+        Ok(plaintext)
+    }
+
+    pub async fn handle_encrypted_message(&mut self, node: &Arc<Node>) {
+        let Some(sender) = node.get_jid_attr("participant").or_else(|| node.get_jid_attr("from")).cloned() else {
+            error!("Message without sender: {}", node.to_xml());
+            return;
+        };
+
+        for enc in node.children().iter().filter(|child| child.tag == "enc") {
+            let enc_type = enc.get_attr("type").unwrap_or_default();
+            let Some(Value::Bytes(ciphertext)) = &enc.content else { continue };
+            let plaintext = match (enc_type, node.get_jid_attr("from")) {
+                ("pkmsg" | "msg", _) => self.decrypt_from(&sender, enc_type, ciphertext),
+                ("skmsg", Some(group)) => self.decrypt_group(&group.clone(), &sender, ciphertext),
+                _ => continue,
+            };
+
+            let message = plaintext
+                .and_then(|plaintext| {
+                    let plaintext = unpad_message(&plaintext)?;
+                    WaMessage::decode(plaintext).map_err(|e| SignalError::InvalidMessage(e.to_string()))
+                });
+            match message {
+                Ok(message) => {
+                    if let (Some(distribution), Some(chat)) = (&message.sender_key_distribution_message, node.get_jid_attr("from")) {
+                        self.handle_sender_key_distribution(&chat.clone(), &sender, distribution);
+                    }
+                    self.dispatch_event(Event::DecryptedMessage { node: Arc::clone(node), message: Arc::new(message) })
+                }
+                Err(e) => {
+                    error!("Failed to decrypt {} from {}: {}", enc_type, sender, e);
+                    self.dispatch_event(Event::UndecryptableMessage { node: Arc::clone(node), error: Arc::new(e) });
+                }
+            }
+        }
+    }
+
+    /// Encrypts a plaintext for each device into a `to` node, skipping the
+    /// devices we can't encrypt for.
+    pub(crate) fn encrypt_for_devices<'a>(&mut self, devices: &[JID], plaintext: impl Fn(&JID) -> &'a [u8]) -> Vec<Node> {
+        let mut participants = Vec::new();
+        for device in devices {
+            match self.encrypt_for(device, plaintext(device)) {
+                Ok(ciphertext) => {
+                    let mut attr = HashMap::new();
+                    attr.insert("jid".to_string(), Value::Jid(device.clone()));
+                    participants.push(Node::new("to".to_string(), attr, Some(Value::List(vec![enc_node(ciphertext.enc_type(), ciphertext.into_bytes())]))));
+                }
+                Err(e) => error!("Failed to encrypt for {}: {}", device, e),
+            }
+        }
+        participants
+    }
+
+    /// Wraps encrypted `content` in a message stanza. Whoever gets a `pkmsg`
+    /// also needs our signed device identity to accept the new session.
+    pub(crate) fn message_node(&self, id: &str, to: &JID, message: &WaMessage, mut content: Vec<Node>) -> Node {
+        let has_pre_key_message = content.iter()
+            .flat_map(|child| child.children().iter())
+            .any(|to| to.children().iter().any(|enc| enc.get_attr("type") == Some("pkmsg")));
+        if let (true, Some(account)) = (has_pre_key_message, &self.device.account) {
+            content.push(Node::new("device-identity".to_string(), HashMap::new(), Some(Value::Bytes(account.encode_to_vec()))));
+        }
+
+        let mut attr = HashMap::new();
+        attr.insert("id".to_string(), Value::Str(id.to_string()));
+        attr.insert("to".to_string(), Value::Jid(to.clone()));
+        attr.insert("type".to_string(), Value::Str(message_type(message).to_string()));
+        Node::new("message".to_string(), attr, Some(Value::List(content)))
+    }
+
+    fn local_identity(&self) -> LocalIdentity<'_> {
+        LocalIdentity {
+            identity_key: &self.device.identity_key,
+            registration_id: self.device.registration_id,
+        }
+    }
+}
+
+pub(crate) fn enc_node(enc_type: &str, ciphertext: Vec<u8>) -> Node {
+    let mut attr = HashMap::new();
+    attr.insert("v".to_string(), Value::Str("2".to_string()));
+    attr.insert("type".to_string(), Value::Str(enc_type.to_string()));
+    Node::new("enc".to_string(), attr, Some(Value::Bytes(ciphertext)))
+}
+
+/// Message ids look like the ones WhatsApp Web makes up.
+fn generate_message_id() -> String {
+    let mut random = [0u8; 8];
+    OsRng.fill_bytes(&mut random);
+    format!("3EB0{}", hex::encode_upper(random))
+}
+
+fn message_type(message: &WaMessage) -> &'static str {
+    if message.reaction_message.is_some() {
+        "reaction"
+    } else if message.image_message.is_some() || message.video_message.is_some() || message.audio_message.is_some()
+        || message.document_message.is_some() || message.sticker_message.is_some() {
+        "media"
+    } else {
+        "text"
+    }
+}
+
+fn parse_pre_key_bundle(user: &Node) -> Result<PreKeyBundle, SignalError> {
+    if let Some(error) = user.get_child("error") {
+        return Err(SignalError::InvalidMessage(format!("no pre-keys: {} {}", error.get_attr("code").unwrap_or_default(), error.get_attr("text").unwrap_or_default())));
+    }
+    let registration_id = fixed_bytes::<4>(user, "registration")?;
+    let identity_key = fixed_bytes::<32>(user, "identity")?;
+    let signed_pre_key = user.get_child("skey").ok_or_else(|| missing("skey"))?;
+    let pre_key = match user.get_child("key") {
+        Some(key) => Some((key_id(key)?, fixed_bytes::<32>(key, "value")?)),
+        None => None,
+    };
+
+    Ok(PreKeyBundle {
+        registration_id: u32::from_be_bytes(registration_id),
+        identity_key,
+        signed_pre_key_id: key_id(signed_pre_key)?,
+        signed_pre_key: fixed_bytes::<32>(signed_pre_key, "value")?,
+        signed_pre_key_signature: fixed_bytes::<64>(signed_pre_key, "signature")?,
+        pre_key,
+    })
+}
+
+/// Key ids are sent as 3 byte big endian integers.
+fn key_id(key: &Node) -> Result<u32, SignalError> {
+    let id = fixed_bytes::<3>(key, "id")?;
+    Ok(u32::from_be_bytes([0, id[0], id[1], id[2]]))
+}
+
+fn fixed_bytes<const N: usize>(node: &Node, tag: &str) -> Result<[u8; N], SignalError> {
+    match node.get_child(tag).and_then(|child| child.content.as_ref()) {
+        Some(Value::Bytes(bytes)) => bytes.as_slice().try_into()
+            .map_err(|_| SignalError::InvalidMessage(format!("{} should be {} bytes, got {}", tag, N, bytes.len()))),
+        _ => Err(missing(tag)),
+    }
+}
+
+fn pre_key_from_record(record: &PreKeyRecordStructure) -> Result<Key, SignalError> {
+    let private = record.private_key().try_into()
+        .map_err(|_| SignalError::InvalidKey(format!("pre-key {} has no private key", record.id())))?;
+    Ok(Key::from_private(private))
+}
+
+fn missing(tag: &str) -> SignalError {
+    SignalError::InvalidMessage(format!("missing {}", tag))
+}
+
+/// Pads a serialized message with 1 to 16 bytes, each holding the pad length.
+pub fn pad_message(plaintext: &[u8]) -> Vec<u8> {
+    let pad = (OsRng.next_u32() % 16 + 1) as u8;
+    let mut padded = plaintext.to_vec();
+    padded.resize(plaintext.len() + pad as usize, pad);
+    padded
+}
+
+pub fn unpad_message(plaintext: &[u8]) -> Result<&[u8], SignalError> {
+    let pad = *plaintext.last().ok_or_else(|| SignalError::InvalidMessage("empty plaintext".into()))? as usize;
+    if pad == 0 || pad > plaintext.len() || plaintext[plaintext.len() - pad..].iter().any(|byte| *byte as usize != pad) {
+        return Err(SignalError::InvalidMessage("invalid padding".into()));
+    }
+    Ok(&plaintext[..plaintext.len() - pad])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use prost::Message;
+
+    use crate::client::Client;
+    use crate::device::Device;
+    use crate::events::Event;
+    use crate::proto::whatsapp::{AdvSignedDeviceIdentity, Message as WaMessage, RecordStructure};
+    use crate::signal::cipher::{self, LocalIdentity, PreKeyMessage, WhisperMessage};
+    use crate::store::device::{DeviceStore, memory::MemoryDeviceStore};
+    use crate::testing::mock_server::{child_bytes, MockServer, node, pre_key_bundle_node};
+    use crate::testing::next_event;
+    use crate::types::jid::{self, JID};
+    use crate::utils::decoder::{Node, Value};
+    use crate::utils::key::{Key, PreKey};
+
+    use super::{pad_message, unpad_message};
+
+    struct Peer {
+        jid: JID,
+        identity: Key,
+        signed_pre_key: PreKey,
+        session: RecordStructure,
+    }
+
+    impl Peer {
+        fn new(jid: JID) -> Self {
+            let identity = Key::new();
+            let signed_pre_key = identity.create_signed_pre_key(9);
+            Peer { jid, identity, signed_pre_key, session: RecordStructure::default() }
+        }
+
+        fn decrypt(&mut self, to: &Node) -> WaMessage {
+            let enc = to.get_child("enc").unwrap();
+            let ciphertext = child_bytes(to, "enc");
+            let plaintext = match enc.get_attr("type") {
+                Some("pkmsg") => {
+                    let local = LocalIdentity { identity_key: &self.identity, registration_id: 77 };
+                    let message = PreKeyMessage::parse(&ciphertext).unwrap();
+                    cipher::decrypt_pre_key(&mut self.session, &local, Some(&self.signed_pre_key.key), None, &message).unwrap().0
+                }
+                _ => cipher::decrypt(&mut self.session, &WhisperMessage::parse(&ciphertext).unwrap()).unwrap(),
+            };
+            WaMessage::decode(unpad_message(&plaintext).unwrap()).unwrap()
+        }
+    }
+
+    fn participant<'a>(message: &'a Node, device: &JID) -> &'a Node {
+        message.get_child("participants").unwrap().children().iter()
+            .find(|to| to.get_jid_attr("jid").map(JID::to_string) == Some(device.to_string()))
+            .unwrap_or_else(|| panic!("nothing for {}", device))
+    }
+
+    #[tokio::test]
+    async fn sends_messages_to_every_device() {
+        let mut server = MockServer::start().await;
+        let store = MemoryDeviceStore::default();
+        let mut device = Device::new();
+        let own = JID::new_ad("6289876543210".into(), 0, 2);
+        device.jid = Some(own.clone());
+        device.account = Some(AdvSignedDeviceIdentity { details: Some(vec![1, 2, 3]), ..Default::default() });
+        store.save(&device).unwrap();
+        let client = Client::new(server.config_with_store(store)).unwrap();
+        let (connected, mut conn) = tokio::join!(Client::connect(&client), server.accept());
+        connected.unwrap();
+
+        let to = JID::new(Some("6281234567890".into()), None, None, None, Some(jid::DEFAULT_USER_SERVER.into()));
+        let mut peer = Peer::new(JID::new_ad("6281234567890".into(), 0, 0));
+        let mut own_phone = Peer::new(JID::new_ad("6289876543210".into(), 0, 0));
+        let devices = vec![peer.jid.clone(), own_phone.jid.clone(), own];
+        let hello = WaMessage { conversation: Some("hello".into()), ..Default::default() };
+
+        let sending = tokio::spawn({
+            let (client, to, devices, hello) = (Arc::clone(&client), to.clone(), devices.clone(), hello.clone());
+            async move { Client::send_message(&client, &to, &devices, &hello).await }
+        });
+        let request = conn.expect_node("iq").await;
+        assert_eq!(request.get_child("key").unwrap().children().len(), 2);
+        let bundles = [&peer, &own_phone].map(|device| pre_key_bundle_node(&device.jid, 77, &device.identity, &device.signed_pre_key));
+        conn.send_iq_result(&request, Some(Value::List(vec![node("list", &[], Some(Value::List(bundles.to_vec())))]))).await;
+        let id = sending.await.unwrap().unwrap();
+
+        let sent = conn.expect_node("message").await;
+        assert_eq!(sent.get_attr("id"), Some(id.as_str()));
+        assert_eq!(sent.get_attr("type"), Some("text"));
+        assert_eq!(sent.get_jid_attr("to").map(JID::to_string), Some(to.to_string()));
+        assert_eq!(child_bytes(&sent, "device-identity"), device.account.unwrap().encode_to_vec());
+        assert_eq!(sent.get_child("participants").unwrap().children().len(), 2);
+        assert_eq!(peer.decrypt(participant(&sent, &peer.jid)), hello);
+        let own_copy = own_phone.decrypt(participant(&sent, &own_phone.jid)).device_sent_message.unwrap();
+        assert_eq!(own_copy.destination_jid.as_deref(), Some("6281234567890@s.whatsapp.net"));
+        assert_eq!(own_copy.message.as_deref(), Some(&hello));
+
+        // With the sessions in place the next message goes out right away,
+        // still as a pkmsg until the peer answers
+        Client::send_message(&client, &to, &devices, &hello).await.unwrap();
+        let sent = conn.receive_node().await.unwrap();
+        assert_eq!(sent.tag, "message");
+        assert!(sent.get_child("device-identity").is_some());
+        assert_eq!(peer.decrypt(participant(&sent, &peer.jid)), hello);
+    }
+
+    #[tokio::test]
+    async fn exchanges_signal_messages_with_a_peer() {
+        let mut server = MockServer::start().await;
+        let client = Client::new(server.config()).unwrap();
+        let mut events = client.lock().await.subscribe();
+        let (connected, mut conn) = tokio::join!(Client::connect(&client), server.accept());
+        connected.unwrap();
+
+        let peer = JID::new_ad("6281234567890".into(), 0, 3);
+        let peer_identity = Key::new();
+        let peer_signed_pre_key = peer_identity.create_signed_pre_key(9);
+        let bundle = pre_key_bundle_node(&peer, 77, &peer_identity, &peer_signed_pre_key);
+
+        let (bundles, request) = tokio::join!(Client::fetch_pre_key_bundles(&client, std::slice::from_ref(&peer)), async {
+            let request = conn.expect_node("iq").await;
+            conn.send_iq_result(&request, Some(Value::List(vec![node("list", &[], Some(Value::List(vec![bundle])))]))).await;
+            request
+        });
+        assert_eq!(request.get_attr("xmlns"), Some("encrypt"));
+        assert_eq!(request.get_attr("type"), Some("get"));
+        let mut bundles = bundles.unwrap();
+        let (jid, bundle) = bundles.pop().unwrap();
+        assert_eq!(jid, peer);
+
+        let ciphertext = {
+            let mut client = client.lock().await;
+            client.process_pre_key_bundle(&peer, &bundle.unwrap()).unwrap();
+            let message = WaMessage { conversation: Some("hello".into()), ..Default::default() };
+            client.encrypt_for(&peer, &pad_message(&message.encode_to_vec())).unwrap()
+        };
+        assert_eq!(ciphertext.enc_type(), "pkmsg");
+
+        let mut peer_record = RecordStructure::default();
+        let peer_local = LocalIdentity { identity_key: &peer_identity, registration_id: 77 };
+        let pre_key_message = PreKeyMessage::parse(&ciphertext.into_bytes()).unwrap();
+        let (plaintext, _) = cipher::decrypt_pre_key(&mut peer_record, &peer_local, Some(&peer_signed_pre_key.key), None, &pre_key_message).unwrap();
+        let message = WaMessage::decode(unpad_message(&plaintext).unwrap()).unwrap();
+        assert_eq!(message.conversation.as_deref(), Some("hello"));
+
+        let reply = WaMessage { conversation: Some("hi".into()), ..Default::default() };
+        let reply = cipher::encrypt(&mut peer_record, &pad_message(&reply.encode_to_vec())).unwrap();
+        assert_eq!(reply.enc_type(), "msg");
+        conn.send_node(&node("message", &[
+            ("id", Value::Str("message-1".into())),
+            ("from", Value::Jid(peer.clone())),
+            ("type", Value::Str("text".into())),
+        ], Some(Value::List(vec![node("enc", &[
+            ("v", Value::Str("2".into())),
+            ("type", Value::Str(reply.enc_type().into())),
+        ], Some(Value::Bytes(reply.into_bytes())))])))).await;
+        conn.expect_node("ack").await;
+        assert!(matches!(next_event(&mut events).await, Event::Message(_)));
+        match next_event(&mut events).await {
+            Event::DecryptedMessage { message, .. } => assert_eq!(message.conversation.as_deref(), Some("hi")),
+            other => panic!("expected a decrypted message, got {:?}", other),
+        }
+
+        conn.send_node(&node("message", &[
+            ("id", Value::Str("message-2".into())),
+            ("from", Value::Jid(peer)),
+            ("type", Value::Str("text".into())),
+        ], Some(Value::List(vec![node("enc", &[
+            ("v", Value::Str("2".into())),
+            ("type", Value::Str("msg".into())),
+        ], Some(Value::Bytes(vec![0x33; 48])))])))).await;
+        conn.expect_node("ack").await;
+        assert!(matches!(next_event(&mut events).await, Event::Message(_)));
+        assert!(matches!(next_event(&mut events).await, Event::UndecryptableMessage { .. }));
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use paris::{error, info};
+use tokio::sync::Mutex;
+
+use crate::client::Client;
+use crate::constant;
+use crate::proto::whatsapp::PreKeyRecordStructure;
+use crate::request::{InfoQuery, IqError};
+use crate::store::StoreError;
+use crate::types::jid::{self, JID};
+use crate::utils::decoder::{Node, Value};
+
+#[derive(Debug)]
+pub enum PreKeyError {
+    Store(StoreError),
+    Iq(IqError),
+}
+
+impl fmt::Display for PreKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PreKeyError::Store(e) => write!(f, "{}", e),
+            PreKeyError::Iq(e) => write!(f, "pre-key upload failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PreKeyError {}
+
+impl From<StoreError> for PreKeyError {
+    fn from(e: StoreError) -> Self {
+        PreKeyError::Store(e)
+    }
+}
+
+impl From<IqError> for PreKeyError {
+    fn from(e: IqError) -> Self {
+        PreKeyError::Iq(e)
+    }
+}
+
+impl Client {
+    /// How many of our one-time pre-keys the server still has to hand out.
+    pub async fn get_server_pre_key_count(client: &Arc<Mutex<Client>>) -> Result<u32, IqError> {
+        let response = client.lock().await.send_iq(encrypt_query("get", vec![
+            Node::new("count".to_string(), HashMap::new(), None),
+        ])).await;
+        let response = response.await?;
+        Ok(response.get_child("count")
+            .and_then(|count| count.get_attr("value"))
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0))
+    }
+
+    /// Uploads a batch of one-time pre-keys together with our identity and signed pre-key.
+    pub async fn upload_pre_keys(client: &Arc<Mutex<Client>>) -> Result<(), PreKeyError> {
+        let (response, pre_keys) = {
+            let mut client = client.lock().await;
+            let pre_keys = client.pre_key_store.get_or_gen_pre_keys(constant::WANTED_PRE_KEY_COUNT)?;
+            let query = client.pre_key_upload_query(&pre_keys);
+            (client.send_iq(query).await, pre_keys)
+        };
+        response.await?;
+
+        if let Some(last) = pre_keys.last() {
+            client.lock().await.pre_key_store.mark_pre_keys_uploaded(last.id())?;
+        }
+        info!("Uploaded {} pre-keys", pre_keys.len());
+        Ok(())
+    }
+
+    /// Uploads new pre-keys when the server is about to run out.
+    pub async fn refresh_pre_keys(client: Arc<Mutex<Client>>) {
+        let count = match Client::get_server_pre_key_count(&client).await {
+            Ok(count) => count,
+            Err(e) => {
+                error!("Failed to get pre-key count: {}", e);
+                return;
+            }
+        };
+        if count < constant::MIN_PRE_KEY_COUNT
+            && let Err(e) = Client::upload_pre_keys(&client).await {
+            error!("{}", e);
+        }
+    }
+
+    pub async fn handle_encrypt_notification(&mut self, node: &Arc<Node>) {
+        let Some(count) = node.get_child("count") else {
+            info!("Unhandled encrypt notification: {}", node.to_xml());
+            return;
+        };
+        let count: u32 = count.get_attr("value").and_then(|value| value.parse().ok()).unwrap_or(0);
+        info!("Server has {} pre-keys left", count);
+        if count >= constant::MIN_PRE_KEY_COUNT {
+            return;
+        }
+        if let Some(client) = self.this.upgrade() {
+            tokio::spawn(async move {
+                if let Err(e) = Client::upload_pre_keys(&client).await {
+                    error!("{}", e);
+                }
+            });
+        }
+    }
+
+    fn pre_key_upload_query(&self, pre_keys: &[PreKeyRecordStructure]) -> InfoQuery {
+        let signed_pre_key = &self.device.signed_pre_key;
+        encrypt_query("set", vec![
+            bytes_node("registration", self.device.registration_id.to_be_bytes().to_vec()),
+            bytes_node("type", vec![constant::SIGNAL_DJB_TYPE]),
+            bytes_node("identity", self.device.identity_key.public.to_bytes().to_vec()),
+            Node::new("list".to_string(), HashMap::new(), Some(Value::List(pre_keys.iter().map(|pre_key| {
+                Node::new("key".to_string(), HashMap::new(), Some(Value::List(vec![
+                    bytes_node("id", encode_key_id(pre_key.id())),
+                    bytes_node("value", pre_key.public_key().to_vec()),
+                ])))
+            }).collect()))),
+            Node::new("skey".to_string(), HashMap::new(), Some(Value::List(vec![
+                bytes_node("id", encode_key_id(signed_pre_key.id)),
+                bytes_node("value", signed_pre_key.key.public.to_bytes().to_vec()),
+                bytes_node("signature", signed_pre_key.signature.to_vec()),
+            ]))),
+        ])
+    }
+}
+
+fn encrypt_query(r#type: &str, content: Vec<Node>) -> InfoQuery {
+    InfoQuery {
+        namespace: Some("encrypt".into()),
+        r#type: Some(r#type.into()),
+        to: Some(JID::new(None, None, None, None, Some(jid::DEFAULT_USER_SERVER.into()))),
+        content: Some(Value::List(content)),
+        ..Default::default()
+    }
+}
+
+fn bytes_node(tag: &str, bytes: Vec<u8>) -> Node {
+    Node::new(tag.to_string(), HashMap::new(), Some(Value::Bytes(bytes)))
+}
+
+/// Key ids are sent as 3 byte big endian integers.
+fn encode_key_id(id: u32) -> Vec<u8> {
+    id.to_be_bytes()[1..].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+
+    use crate::client::{Client, Config};
+    use crate::constant;
+    use crate::events::Event;
+    use crate::message::pad_message;
+    use crate::proto::whatsapp::{Message as WaMessage, RecordStructure};
+    use crate::signal::cipher::{self, LocalIdentity};
+    use crate::store::pre_key::{memory::MemoryPreKeyStore, PreKeyStore};
+    use crate::testing::mock_server::{child_bytes, MockServer, node, server_jid};
+    use crate::testing::{device_bundle, next_event, wait_for};
+    use crate::types::jid::JID;
+    use crate::utils::decoder::Value;
+    use crate::utils::key::Key;
+
+    #[tokio::test]
+    async fn uploads_and_replenishes_pre_keys() {
+        let mut server = MockServer::start().await;
+        let pre_keys = MemoryPreKeyStore::default();
+        let client = Client::new(Config { pre_key_store: Box::new(pre_keys.clone()), ..server.config() }).unwrap();
+        let mut events = client.lock().await.subscribe();
+        let (connected, mut conn) = tokio::join!(Client::connect(&client), server.accept());
+        connected.unwrap();
+
+        conn.send_success().await;
+        conn.expect_node("iq").await;
+        assert!(matches!(next_event(&mut events).await, Event::Connected));
+        let count = conn.expect_node("iq").await;
+        assert_eq!(count.get_attr("xmlns"), Some("encrypt"));
+        assert!(count.get_child("count").is_some());
+        conn.send_iq_result(&count, Some(Value::List(vec![node("count", &[("value", Value::Str("0".into()))], None)]))).await;
+
+        let upload = conn.expect_node("iq").await;
+        assert_eq!(upload.get_attr("type"), Some("set"));
+        let (identity, signed_pre_key) = {
+            let client = client.lock().await;
+            (client.device.identity_key.public.to_bytes(), client.device.signed_pre_key.key.public.to_bytes())
+        };
+        assert_eq!(child_bytes(&upload, "identity"), identity);
+        assert_eq!(child_bytes(&upload, "type"), vec![constant::SIGNAL_DJB_TYPE]);
+        assert_eq!(child_bytes(upload.get_child("skey").unwrap(), "value"), signed_pre_key);
+        let keys = upload.get_child("list").unwrap().children();
+        assert_eq!(keys.len(), constant::WANTED_PRE_KEY_COUNT as usize);
+        assert_eq!(child_bytes(&keys[0], "id"), vec![0, 0, 1]);
+        let first_pre_key: [u8; 32] = child_bytes(&keys[0], "value").try_into().unwrap();
+        conn.send_iq_result(&upload, None).await;
+        wait_for(|| pre_keys.uploaded_pre_key_count().unwrap() == constant::WANTED_PRE_KEY_COUNT).await;
+
+        // A peer starts a session with one of the uploaded pre-keys, which uses it up
+        let peer = JID::new_ad("6281234567890".into(), 0, 0);
+        let peer_identity = Key::new();
+        let mut peer_record = RecordStructure::default();
+        let bundle = device_bundle(&client.lock().await.device, Some((1, first_pre_key)));
+        cipher::process_bundle(&mut peer_record, &LocalIdentity { identity_key: &peer_identity, registration_id: 77 }, &bundle).unwrap();
+        let message = WaMessage { conversation: Some("hello".into()), ..Default::default() };
+        let message = cipher::encrypt(&mut peer_record, &pad_message(&message.encode_to_vec())).unwrap();
+        conn.send_node(&node("message", &[
+            ("id", Value::Str("message-1".into())),
+            ("from", Value::Jid(peer)),
+            ("type", Value::Str("text".into())),
+        ], Some(Value::List(vec![node("enc", &[
+            ("v", Value::Str("2".into())),
+            ("type", Value::Str(message.enc_type().into())),
+        ], Some(Value::Bytes(message.into_bytes())))])))).await;
+        conn.expect_node("ack").await;
+        assert!(matches!(next_event(&mut events).await, Event::Message(_)));
+        assert!(matches!(next_event(&mut events).await, Event::DecryptedMessage { .. }));
+        assert!(pre_keys.load_pre_key(1).unwrap().is_none());
+
+        conn.send_node(&node("notification", &[
+            ("id", Value::Str("notification-1".into())),
+            ("from", server_jid()),
+            ("type", Value::Str("encrypt".into())),
+        ], Some(Value::List(vec![node("count", &[("value", Value::Str("2".into()))], None)])))).await;
+        conn.expect_node("ack").await;
+        let upload = conn.expect_node("iq").await;
+        let keys = upload.get_child("list").unwrap().children();
+        assert_eq!(child_bytes(&keys[0], "id"), vec![0, 0, constant::WANTED_PRE_KEY_COUNT as u8 + 1]);
+        conn.send_iq_result(&upload, None).await;
+        wait_for(|| pre_keys.uploaded_pre_key_count().unwrap() == 2 * constant::WANTED_PRE_KEY_COUNT - 1).await;
+    }
+}
@@ -1,9 +1,18 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
 
-use futures_util::SinkExt;
 use paris::info;
+use tokio::sync::oneshot;
+use tokio::time::Sleep;
 
-use crate::{client::Client, types::jid::JID, utils::{decoder::{Node, Value}, encoder::BinaryEncoder}};
+use crate::{client::Client, constant, socket::transport::TransportError, types::jid::JID, utils::{decoder::{CodecError, Node, Value}, encoder::BinaryEncoder}};
+
+pub type ResponseWaiters = Arc<Mutex<HashMap<String, oneshot::Sender<Node>>>>;
 
 #[derive(Default)]
 pub struct InfoQuery {
@@ -12,13 +21,89 @@ pub struct InfoQuery {
     pub to: Option<JID>,
     pub target: Option<JID>,
     pub id: Option<String>,
-    pub content: Option<Value>
+    pub content: Option<Value>,
+    pub timeout: Option<Duration>
+}
+
+#[derive(Debug)]
+pub enum SendError {
+    NotConnected,
+    Codec(CodecError),
+    Transport(TransportError),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::NotConnected => write!(f, "not connected"),
+            SendError::Codec(e) => write!(f, "failed to encode node: {}", e),
+            SendError::Transport(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+#[derive(Debug)]
+pub enum IqError {
+    Timeout,
+    Disconnected,
+    Send(SendError),
+    ServerError { code: u16, text: String, node: Box<Node> },
+}
+
+impl fmt::Display for IqError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IqError::Timeout => write!(f, "info query timed out"),
+            IqError::Disconnected => write!(f, "disconnected before receiving a response"),
+            IqError::Send(e) => write!(f, "failed to send info query: {}", e),
+            IqError::ServerError { code, text, .. } => write!(f, "server returned error {}: {}", code, text),
+        }
+    }
+}
+
+impl std::error::Error for IqError {}
+
+/// The pending answer to an info query. Dropping it stops waiting and
+/// forgets the query, a late answer is then ignored.
+pub struct IqResponse {
+    id: String,
+    waiters: ResponseWaiters,
+    send_error: Option<SendError>,
+    receiver: oneshot::Receiver<Node>,
+    deadline: Pin<Box<Sleep>>,
+}
+
+impl Future for IqResponse {
+    type Output = Result<Node, IqError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(e) = self.send_error.take() {
+            return Poll::Ready(Err(IqError::Send(e)));
+        }
+        match Pin::new(&mut self.receiver).poll(cx) {
+            Poll::Ready(Ok(node)) => return Poll::Ready(parse_iq_response(node)),
+            Poll::Ready(Err(_)) => return Poll::Ready(Err(IqError::Disconnected)),
+            Poll::Pending => {}
+        }
+        self.deadline.as_mut().poll(cx).map(|_| Err(IqError::Timeout))
+    }
+}
+
+impl Drop for IqResponse {
+    fn drop(&mut self) {
+        self.waiters.lock().unwrap().remove(&self.id);
+    }
 }
 
 impl Client {
-    pub async fn send_iq(&mut self, query: InfoQuery) {
+    /// Sends the query right away, the returned future resolves with the answer.
+    /// Awaiting it doesn't need the client, so callers can let go of the lock first.
+    pub async fn send_iq(&mut self, query: InfoQuery) -> IqResponse {
+        let id = query.id.unwrap_or_else(|| self.generate_request_id());
         let mut attr = HashMap::new();
-        attr.insert("id".to_string(), Value::Str(self.generate_request_id()));
+        attr.insert("id".to_string(), Value::Str(id.clone()));
         attr.insert("xmlns".to_string(), Value::Str(query.namespace.unwrap()));
         attr.insert("type".to_string(), Value::Str(query.r#type.unwrap()));
 
@@ -30,14 +115,43 @@ impl Client {
             attr.insert("target".to_string(), Value::Jid(target));
         }
 
-        self.send_node_and_get_data(Node::new("iq".to_string(), attr, query.content)).await;
+        let (sender, receiver) = oneshot::channel();
+        self.response_waiters.lock().unwrap().insert(id.clone(), sender);
+        let mut response = IqResponse {
+            id,
+            waiters: Arc::clone(&self.response_waiters),
+            send_error: None,
+            receiver,
+            deadline: Box::pin(tokio::time::sleep(query.timeout.unwrap_or(constant::IQ_TIMEOUT))),
+        };
+        response.send_error = self.send_node(Node::new("iq".to_string(), attr, query.content)).await.err();
+        response
+    }
+
+    pub fn receive_response(&mut self, node: &Node) -> bool {
+        if node.tag != "iq" || !matches!(node.get_attr("type"), Some("result") | Some("error")) {
+            return false;
+        }
+        let Some(id) = node.get_attr("id") else { return false };
+
+        match self.response_waiters.lock().unwrap().remove(id) {
+            Some(waiter) => {
+                let _ = waiter.send(node.clone());
+                true
+            }
+            None => false
+        }
     }
 
-    pub async fn send_node_and_get_data(&mut self, node: Node) {
-        let data = BinaryEncoder::new().write_node(&node);
+    pub async fn send_node(&mut self, node: Node) -> Result<(), SendError> {
+        let mut encoder = match self.compression_threshold {
+            Some(threshold) => BinaryEncoder::with_compression(threshold),
+            None => BinaryEncoder::new(),
+        };
+        let data = encoder.write_node(&node).map_err(SendError::Codec)?;
         info!("Sending node: {}", node.to_xml());
-        let frame = self.ns.as_mut().expect("Noise socket not initialized").make_frame(data.clone());
-        self.write.send(self.fs.make_frame(frame).into()).await.unwrap();
+        let frame = self.ns.as_mut().ok_or(SendError::NotConnected)?.make_frame(data);
+        self.send_frame(frame).await.map_err(SendError::Transport)
     }
 
     pub fn generate_request_id(&mut self) -> String {
@@ -45,4 +159,108 @@ impl Client {
         let id = format!("{}{}", self.unique_id, self.id_counter);
         id
     }
-}
\ No newline at end of file
+}
+
+fn parse_iq_response(node: Node) -> Result<Node, IqError> {
+    if node.get_attr("type") != Some("error") {
+        return Ok(node);
+    }
+
+    let (code, text) = match node.get_child("error") {
+        Some(error) => (
+            error.get_attr("code").and_then(|code| code.parse().ok()).unwrap_or(0),
+            error.get_attr("text").unwrap_or("").to_string()
+        ),
+        None => (0, String::new())
+    };
+    Err(IqError::ServerError { code, text, node: Box::new(node) })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::client::Client;
+    use crate::testing::count_query;
+    use crate::testing::mock_server::{node, MockServer};
+    use crate::utils::decoder::Value;
+
+    use super::{InfoQuery, IqError};
+
+    fn count_result(value: &str) -> Option<Value> {
+        Some(Value::List(vec![node("count", &[("value", Value::Str(value.into()))], None)]))
+    }
+
+    #[tokio::test]
+    async fn resolves_iq_results_and_errors() {
+        let mut server = MockServer::start().await;
+        let client = Client::new(server.config()).unwrap();
+        let (connected, mut conn) = tokio::join!(Client::connect(&client), server.accept());
+        connected.unwrap();
+
+        let response = client.lock().await.send_iq(count_query()).await;
+        let request = conn.expect_node("iq").await;
+        conn.send_iq_result(&request, count_result("42")).await;
+        let result = response.await.unwrap();
+        assert_eq!(result.get_child("count").and_then(|count| count.get_attr("value")), Some("42"));
+
+        let response = client.lock().await.send_iq(count_query()).await;
+        let request = conn.expect_node("iq").await;
+        conn.send_iq_error(&request, 404, "item-not-found").await;
+        match response.await {
+            Err(IqError::ServerError { code, text, .. }) => {
+                assert_eq!(code, 404);
+                assert_eq!(text, "item-not-found");
+            }
+            other => panic!("unexpected response: {:?}", other.map(|node| node.to_xml())),
+        }
+    }
+
+    #[tokio::test]
+    async fn matches_responses_by_id() {
+        let mut server = MockServer::start().await;
+        let client = Client::new(server.config()).unwrap();
+        let (connected, mut conn) = tokio::join!(Client::connect(&client), server.accept());
+        connected.unwrap();
+
+        let first = client.lock().await.send_iq(count_query()).await;
+        let second = client.lock().await.send_iq(count_query()).await;
+        let first_request = conn.expect_node("iq").await;
+        let second_request = conn.expect_node("iq").await;
+        assert_ne!(first_request.get_attr("id"), second_request.get_attr("id"));
+
+        conn.send_iq_result(&second_request, count_result("2")).await;
+        conn.send_iq_result(&first_request, count_result("1")).await;
+        let (first, second) = (first.await.unwrap(), second.await.unwrap());
+        assert_eq!(first.get_child("count").and_then(|count| count.get_attr("value")), Some("1"));
+        assert_eq!(second.get_child("count").and_then(|count| count.get_attr("value")), Some("2"));
+        assert!(client.lock().await.response_waiters.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn forgets_queries_that_time_out_or_are_dropped() {
+        let mut server = MockServer::start().await;
+        let client = Client::new(server.config()).unwrap();
+        let (connected, mut conn) = tokio::join!(Client::connect(&client), server.accept());
+        connected.unwrap();
+        let waiters = client.lock().await.response_waiters.clone();
+
+        let response = client.lock().await.send_iq(InfoQuery { timeout: Some(Duration::from_millis(50)), ..count_query() }).await;
+        let request = conn.expect_node("iq").await;
+        assert!(matches!(response.await, Err(IqError::Timeout)));
+        assert!(waiters.lock().unwrap().is_empty());
+
+        let response = client.lock().await.send_iq(count_query()).await;
+        conn.expect_node("iq").await;
+        assert_eq!(waiters.lock().unwrap().len(), 1);
+        drop(response);
+        assert!(waiters.lock().unwrap().is_empty());
+
+        // A late answer has nobody waiting for it, the client keeps going
+        conn.send_iq_result(&request, None).await;
+        let response = client.lock().await.send_iq(count_query()).await;
+        let request = conn.expect_node("iq").await;
+        conn.send_iq_result(&request, None).await;
+        assert!(response.await.is_ok());
+    }
+}
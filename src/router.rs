@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use paris::error;
+
+use crate::client::Client;
+use crate::events::Event;
+use crate::utils::decoder::{Node, Value};
+
+impl Client {
+    pub async fn process(&mut self, node: &Node) {
+        match node.tag.as_str() {
+            "iq" => self.handle_iq(node).await,
+            "success" => self.handle_success().await,
+            "failure" => self.handle_failure(node).await,
+            "stream:error" => self.handle_stream_error(node).await,
+            "message" | "receipt" | "notification" | "call" => {
+                self.send_ack(node).await;
+                self.handle_stanza(node).await
+            }
+            "presence" => self.dispatch_event(Event::Presence(Arc::new(node.clone()))),
+            _ => error!("Node not handled: {}", node.tag)
+        }
+    }
+
+    async fn handle_iq(&mut self, node: &Node) {
+        if self.receive_response(node) {
+            return;
+        }
+        match (node.get_attr("type"), node.get_attr("xmlns")) {
+            (Some("get"), Some("urn:xmpp:ping")) => self.send_pong(node).await,
+            (Some("set"), _) if node.get_child("pair-device").is_some() || node.get_child("pair-success").is_some() => {
+                self.handle_qr(node).await
+            }
+            _ => error!("Iq not handled: {}", node.to_xml())
+        }
+    }
+
+    async fn handle_stanza(&mut self, node: &Node) {
+        let node = Arc::new(node.clone());
+        match node.tag.as_str() {
+            "message" => {
+                self.dispatch_event(Event::Message(Arc::clone(&node)));
+                self.handle_encrypted_message(&node).await
+            }
+            "receipt" => self.dispatch_event(Event::Receipt(node)),
+            "call" => self.dispatch_event(Event::Call(node)),
+            _ => match node.get_attr("type") {
+                Some("link_code_companion_reg") => self.handle_link_code_notification(&node).await,
+                Some("encrypt") => self.handle_encrypt_notification(&node).await,
+                Some("w:gp2") => {
+                    self.handle_group_notification(&node).await;
+                    self.dispatch_event(Event::Notification(node))
+                }
+                _ => self.dispatch_event(Event::Notification(node)),
+            }
+        }
+    }
+
+    async fn send_pong(&mut self, node: &Node) {
+        let mut attr = HashMap::new();
+        copy_attr(node, &mut attr, "id", "id");
+        copy_attr(node, &mut attr, "from", "to");
+        attr.insert("type".to_string(), Value::Str("result".to_string()));
+
+        if let Err(e) = self.send_node(Node::new("iq".to_string(), attr, None)).await {
+            error!("Failed to answer ping: {}", e);
+        }
+    }
+
+    /// Tells the server a stanza arrived so it stops redelivering it.
+    async fn send_ack(&mut self, node: &Node) {
+        let mut attr = HashMap::new();
+        attr.insert("class".to_string(), Value::Str(node.tag.clone()));
+        copy_attr(node, &mut attr, "id", "id");
+        copy_attr(node, &mut attr, "from", "to");
+        copy_attr(node, &mut attr, "participant", "participant");
+        copy_attr(node, &mut attr, "recipient", "recipient");
+        // Messages are acked without their type, everything else echoes it
+        if node.tag != "message" {
+            copy_attr(node, &mut attr, "type", "type");
+        }
+
+        if let Err(e) = self.send_node(Node::new("ack".to_string(), attr, None)).await {
+            error!("Failed to ack {}: {}", node.tag, e);
+        }
+    }
+}
+
+fn copy_attr(node: &Node, attr: &mut HashMap<String, Value>, from: &str, to: &str) {
+    if let Some(value) = node.attributes.get(from) {
+        attr.insert(to.to_string(), value.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::Client;
+    use crate::events::Event;
+    use crate::testing::mock_server::{MockServer, node, server_jid};
+    use crate::testing::{count_query, next_event};
+    use crate::types::jid::{self, JID};
+    use crate::utils::decoder::Value;
+
+    #[tokio::test]
+    async fn keeps_reading_after_unhandled_stanzas() {
+        let mut server = MockServer::start().await;
+        let client = Client::new(server.config()).unwrap();
+        let (connected, mut conn) = tokio::join!(Client::connect(&client), server.accept());
+        connected.unwrap();
+
+        conn.send_node(&node("notification", &[
+            ("id", Value::Str("notification-1".into())),
+            ("from", server_jid()),
+            ("type", Value::Str("server_sync".into())),
+        ], None)).await;
+
+        let response = client.lock().await.send_iq(count_query()).await;
+        let request = conn.expect_node("iq").await;
+        conn.send_iq_result(&request, None).await;
+        assert!(response.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn answers_server_pings() {
+        let mut server = MockServer::start().await;
+        let client = Client::new(server.config()).unwrap();
+        let (connected, mut conn) = tokio::join!(Client::connect(&client), server.accept());
+        connected.unwrap();
+
+        // Unknown iqs are logged rather than taking the read loop down
+        conn.send_node(&node("iq", &[
+            ("id", Value::Str("unknown-1".into())),
+            ("from", server_jid()),
+            ("type", Value::Str("set".into())),
+        ], Some(Value::List(vec![node("dirty", &[], None)])))).await;
+        conn.send_node(&node("iq", &[
+            ("id", Value::Str("ping-1".into())),
+            ("from", server_jid()),
+            ("type", Value::Str("get".into())),
+            ("xmlns", Value::Str("urn:xmpp:ping".into())),
+        ], Some(Value::List(vec![node("ping", &[], None)])))).await;
+
+        let pong = conn.expect_node("iq").await;
+        assert_eq!(pong.get_attr("id"), Some("ping-1"));
+        assert_eq!(pong.get_attr("type"), Some("result"));
+        assert_eq!(pong.get_jid_attr("to").map(|jid| jid.to_string()), Some(jid::DEFAULT_USER_SERVER.to_string()));
+    }
+
+    #[tokio::test]
+    async fn acks_incoming_stanzas() {
+        let mut server = MockServer::start().await;
+        let client = Client::new(server.config()).unwrap();
+        let mut events = client.lock().await.subscribe();
+        let (connected, mut conn) = tokio::join!(Client::connect(&client), server.accept());
+        connected.unwrap();
+
+        let group = Value::Jid(JID::new(Some("120363000000000000".into()), None, None, None, Some("g.us".into())));
+        let participant = Value::Jid(JID::new(Some("6281234567890".into()), None, None, None, Some(jid::DEFAULT_USER_SERVER.into())));
+        conn.send_node(&node("message", &[
+            ("id", Value::Str("message-1".into())),
+            ("from", group),
+            ("participant", participant.clone()),
+            ("type", Value::Str("text".into())),
+        ], None)).await;
+        let ack = conn.expect_node("ack").await;
+        assert_eq!(ack.get_attr("class"), Some("message"));
+        assert_eq!(ack.get_attr("id"), Some("message-1"));
+        assert_eq!(ack.get_jid_attr("to").map(|jid| jid.to_string()), Some("120363000000000000@g.us".to_string()));
+        assert_eq!(ack.get_jid_attr("participant").map(|jid| jid.to_string()), Some("6281234567890@s.whatsapp.net".to_string()));
+        assert_eq!(ack.get_attr("type"), None);
+        assert!(matches!(next_event(&mut events).await, Event::Message(message) if message.get_attr("id") == Some("message-1")));
+
+        conn.send_node(&node("receipt", &[
+            ("id", Value::Str("receipt-1".into())),
+            ("from", participant.clone()),
+            ("type", Value::Str("read".into())),
+        ], None)).await;
+        let ack = conn.expect_node("ack").await;
+        assert_eq!(ack.get_attr("class"), Some("receipt"));
+        assert_eq!(ack.get_attr("type"), Some("read"));
+        assert!(matches!(next_event(&mut events).await, Event::Receipt(_)));
+
+        conn.send_node(&node("notification", &[
+            ("id", Value::Str("notification-1".into())),
+            ("from", server_jid()),
+            ("type", Value::Str("server_sync".into())),
+        ], None)).await;
+        let ack = conn.expect_node("ack").await;
+        assert_eq!(ack.get_attr("class"), Some("notification"));
+        assert_eq!(ack.get_attr("type"), Some("server_sync"));
+        assert!(matches!(next_event(&mut events).await, Event::Notification(_)));
+
+        conn.send_node(&node("call", &[
+            ("id", Value::Str("call-1".into())),
+            ("from", participant),
+        ], Some(Value::List(vec![node("offer", &[], None)])))).await;
+        let ack = conn.expect_node("ack").await;
+        assert_eq!(ack.get_attr("class"), Some("call"));
+        assert!(matches!(next_event(&mut events).await, Event::Call(_)));
+    }
+}
@@ -0,0 +1,479 @@
+use hmac::{Hmac, Mac};
+use prost::Message;
+use sha2::Sha256;
+
+use crate::constant;
+use crate::proto::whatsapp::{PreKeySignalMessage, RecordStructure, SessionStructure, SignalMessage};
+use crate::utils::aes_cbc;
+use crate::utils::key::{self, Key};
+
+use super::ratchet::{ChainKey, MessageKeys};
+use super::session::{initialize_alice_session, initialize_bob_session, AliceParameters, BobParameters};
+use super::{parse_public, serialize_public, versioned_body, SignalError, VERSION_BYTE};
+
+/// Our long term identity as the other side sees it.
+pub struct LocalIdentity<'a> {
+    pub identity_key: &'a Key,
+    pub registration_id: u32,
+}
+
+pub struct PreKeyBundle {
+    pub registration_id: u32,
+    pub identity_key: [u8; 32],
+    pub signed_pre_key_id: u32,
+    pub signed_pre_key: [u8; 32],
+    pub signed_pre_key_signature: [u8; 64],
+    pub pre_key: Option<(u32, [u8; 32])>,
+}
+
+pub enum CiphertextMessage {
+    PreKey(Vec<u8>),
+    Whisper(Vec<u8>),
+}
+
+impl CiphertextMessage {
+    /// The `type` attribute of the `enc` node carrying this message.
+    pub fn enc_type(&self) -> &'static str {
+        match self {
+            CiphertextMessage::PreKey(_) => "pkmsg",
+            CiphertextMessage::Whisper(_) => "msg",
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        match self {
+            CiphertextMessage::PreKey(bytes) | CiphertextMessage::Whisper(bytes) => bytes,
+        }
+    }
+}
+
+pub struct WhisperMessage {
+    pub ratchet_key: [u8; 32],
+    pub counter: u32,
+    pub previous_counter: u32,
+    pub ciphertext: Vec<u8>,
+    serialized: Vec<u8>,
+}
+
+pub struct PreKeyMessage {
+    pub registration_id: u32,
+    pub pre_key_id: Option<u32>,
+    pub signed_pre_key_id: u32,
+    pub base_key: [u8; 32],
+    pub identity_key: [u8; 32],
+    pub message: WhisperMessage,
+}
+
+impl WhisperMessage {
+    fn new(
+        mac_key: &[u8; 32],
+        sender_identity: &[u8; 32],
+        receiver_identity: &[u8; 32],
+        ratchet_key: [u8; 32],
+        counter: u32,
+        previous_counter: u32,
+        ciphertext: Vec<u8>,
+    ) -> Self {
+        let message = SignalMessage {
+            ratchet_key: Some(serialize_public(&ratchet_key)),
+            counter: Some(counter),
+            previous_counter: Some(previous_counter),
+            ciphertext: Some(ciphertext.clone()),
+        };
+        let mut serialized = [&[VERSION_BYTE][..], &message.encode_to_vec()].concat();
+        let mac = message_mac(mac_key, sender_identity, receiver_identity, &serialized).finalize().into_bytes();
+        serialized.extend_from_slice(&mac[..constant::SIGNAL_MAC_SIZE]);
+
+        Self { ratchet_key, counter, previous_counter, ciphertext, serialized }
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, SignalError> {
+        let body = versioned_body(bytes, constant::SIGNAL_MAC_SIZE)?;
+        let message = SignalMessage::decode(body).map_err(|e| SignalError::InvalidMessage(e.to_string()))?;
+        let (Some(ratchet_key), Some(counter), Some(ciphertext)) = (message.ratchet_key, message.counter, message.ciphertext) else {
+            return Err(SignalError::InvalidMessage("incomplete message".into()));
+        };
+
+        Ok(Self {
+            ratchet_key: parse_public(&ratchet_key)?,
+            counter,
+            previous_counter: message.previous_counter.unwrap_or(0),
+            ciphertext,
+            serialized: bytes.to_vec(),
+        })
+    }
+
+    fn verify_mac(&self, mac_key: &[u8; 32], sender_identity: &[u8; 32], receiver_identity: &[u8; 32]) -> bool {
+        let (content, mac) = self.serialized.split_at(self.serialized.len() - constant::SIGNAL_MAC_SIZE);
+        message_mac(mac_key, sender_identity, receiver_identity, content).verify_truncated_left(mac).is_ok()
+    }
+}
+
+impl PreKeyMessage {
+    pub fn parse(bytes: &[u8]) -> Result<Self, SignalError> {
+        let body = versioned_body(bytes, 0)?;
+        let message = PreKeySignalMessage::decode(body).map_err(|e| SignalError::InvalidMessage(e.to_string()))?;
+        let (Some(signed_pre_key_id), Some(base_key), Some(identity_key), Some(inner)) =
+            (message.signed_pre_key_id, message.base_key, message.identity_key, message.message) else {
+            return Err(SignalError::InvalidMessage("incomplete pre-key message".into()));
+        };
+
+        Ok(Self {
+            registration_id: message.registration_id.unwrap_or(0),
+            pre_key_id: message.pre_key_id,
+            signed_pre_key_id,
+            base_key: parse_public(&base_key)?,
+            identity_key: parse_public(&identity_key)?,
+            message: WhisperMessage::parse(&inner)?,
+        })
+    }
+}
+
+/// Starts a session from a fetched bundle, the first messages we send on
+/// it are pre-key messages so the other side can build its half.
+pub fn process_bundle(record: &mut RecordStructure, local: &LocalIdentity, bundle: &PreKeyBundle) -> Result<(), SignalError> {
+    let signed_pre_key = serialize_public(&bundle.signed_pre_key);
+    if !key::verify_signature(&bundle.identity_key, &signed_pre_key, &bundle.signed_pre_key_signature) {
+        return Err(SignalError::InvalidSignature);
+    }
+
+    let base_key = Key::new();
+    let mut session = initialize_alice_session(&AliceParameters {
+        our_identity: local.identity_key,
+        our_base_key: &base_key,
+        their_identity: bundle.identity_key,
+        their_signed_pre_key: bundle.signed_pre_key,
+        their_one_time_pre_key: bundle.pre_key.map(|(_, key)| key),
+    });
+    session.set_unacknowledged_pre_key(bundle.pre_key.map(|(id, _)| id), bundle.signed_pre_key_id, base_key.public.as_bytes());
+    session.local_registration_id = Some(local.registration_id);
+    session.remote_registration_id = Some(bundle.registration_id);
+    session.alice_base_key = Some(serialize_public(base_key.public.as_bytes()));
+    record.promote_state(session);
+    Ok(())
+}
+
+pub fn encrypt(record: &mut RecordStructure, plaintext: &[u8]) -> Result<CiphertextMessage, SignalError> {
+    let session = record.current_session.as_mut().ok_or(SignalError::NoSession)?;
+    let chain_key = session.sender_chain_key()?;
+    let keys = chain_key.message_keys();
+    let message = WhisperMessage::new(
+        &keys.mac_key,
+        &session.local_identity_key()?,
+        &session.remote_identity_key()?,
+        *session.sender_ratchet()?.public.as_bytes(),
+        chain_key.index,
+        session.previous_counter(),
+        aes_cbc::encrypt(&keys.cipher_key, &keys.iv, plaintext),
+    );
+
+    let message = match &session.pending_pre_key {
+        Some(pending) => {
+            let pre_key_message = PreKeySignalMessage {
+                registration_id: Some(session.local_registration_id()),
+                pre_key_id: pending.pre_key_id,
+                signed_pre_key_id: pending.signed_pre_key_id.map(|id| id as u32),
+                base_key: pending.base_key.clone(),
+                identity_key: session.local_identity_public.clone(),
+                message: Some(message.serialized),
+            };
+            CiphertextMessage::PreKey([&[VERSION_BYTE][..], &pre_key_message.encode_to_vec()].concat())
+        }
+        None => CiphertextMessage::Whisper(message.serialized),
+    };
+    session.set_sender_chain_key(&chain_key.next());
+    Ok(message)
+}
+
+/// Decrypts with whichever of our sessions the sender used, making it the
+/// current one if it was an older session.
+pub fn decrypt(record: &mut RecordStructure, message: &WhisperMessage) -> Result<Vec<u8>, SignalError> {
+    let mut first_error = None;
+    if let Some(current) = &record.current_session {
+        let mut session = current.clone();
+        match decrypt_with_session(&mut session, message) {
+            Ok(plaintext) => {
+                record.current_session = Some(session);
+                return Ok(plaintext);
+            }
+            Err(e) => first_error = Some(e),
+        }
+    }
+
+    for i in 0..record.previous_sessions.len() {
+        let mut session = record.previous_sessions[i].clone();
+        if let Ok(plaintext) = decrypt_with_session(&mut session, message) {
+            record.previous_sessions.remove(i);
+            record.promote_state(session);
+            return Ok(plaintext);
+        }
+    }
+    Err(first_error.unwrap_or(SignalError::NoSession))
+}
+
+/// Builds our half of the session the sender started, unless we already
+/// did for an earlier copy of this message, then decrypts it. Returns the
+/// one-time pre-key that got used up, if any.
+pub fn decrypt_pre_key(
+    record: &mut RecordStructure,
+    local: &LocalIdentity,
+    signed_pre_key: Option<&Key>,
+    one_time_pre_key: Option<&Key>,
+    message: &PreKeyMessage,
+) -> Result<(Vec<u8>, Option<u32>), SignalError> {
+    if record.has_session_with_base_key(&message.base_key) {
+        return Ok((decrypt(record, &message.message)?, None));
+    }
+
+    let signed_pre_key = signed_pre_key.ok_or(SignalError::MissingSignedPreKey(message.signed_pre_key_id))?;
+    let one_time_pre_key = match (message.pre_key_id, one_time_pre_key) {
+        (Some(id), None) => return Err(SignalError::MissingPreKey(id)),
+        (None, _) => None,
+        (Some(_), key) => key,
+    };
+    let mut session = initialize_bob_session(&BobParameters {
+        our_identity: local.identity_key,
+        our_signed_pre_key: signed_pre_key,
+        our_one_time_pre_key: one_time_pre_key,
+        their_identity: message.identity_key,
+        their_base_key: message.base_key,
+    });
+    session.local_registration_id = Some(local.registration_id);
+    session.remote_registration_id = Some(message.registration_id);
+    session.alice_base_key = Some(serialize_public(&message.base_key));
+
+    // Only keep the new session once the message proves it works
+    let mut updated = record.clone();
+    updated.promote_state(session);
+    let plaintext = decrypt(&mut updated, &message.message)?;
+    *record = updated;
+    Ok((plaintext, message.pre_key_id))
+}
+
+fn decrypt_with_session(session: &mut SessionStructure, message: &WhisperMessage) -> Result<Vec<u8>, SignalError> {
+    if session.sender_chain.is_none() {
+        return Err(SignalError::NoSession);
+    }
+    let chain_key = receiver_chain_key(session, &message.ratchet_key)?;
+    let keys = message_keys(session, &message.ratchet_key, chain_key, message.counter)?;
+
+    if !message.verify_mac(&keys.mac_key, &session.remote_identity_key()?, &session.local_identity_key()?) {
+        return Err(SignalError::InvalidMac);
+    }
+    let plaintext = aes_cbc::decrypt(&keys.cipher_key, &keys.iv, &message.ciphertext)
+        .ok_or_else(|| SignalError::InvalidMessage("bad padding".into()))?;
+
+    // They answered, so they have the session and stop needing the pre-key
+    session.pending_pre_key = None;
+    Ok(plaintext)
+}
+
+/// Returns the receiving chain for `their_ratchet_key`, stepping the DH
+/// ratchet first when it is a key we have not seen yet.
+fn receiver_chain_key(session: &mut SessionStructure, their_ratchet_key: &[u8; 32]) -> Result<ChainKey, SignalError> {
+    if let Some(chain_key) = session.receiver_chain_key(their_ratchet_key)? {
+        return Ok(chain_key);
+    }
+
+    let our_ratchet = session.sender_ratchet()?;
+    let (root_key, receiver_chain) = session.current_root_key()?.create_chain(their_ratchet_key, &our_ratchet.private);
+    let our_new_ratchet = Key::new();
+    let (root_key, sender_chain) = root_key.create_chain(their_ratchet_key, &our_new_ratchet.private);
+
+    let previous_counter = session.sender_chain_key()?.index.saturating_sub(1);
+    session.set_root_key(&root_key);
+    session.add_receiver_chain(their_ratchet_key, &receiver_chain);
+    session.previous_counter = Some(previous_counter);
+    session.set_sender_chain(&our_new_ratchet, &sender_chain);
+    Ok(receiver_chain)
+}
+
+/// Walks the chain up to `counter`, keeping the keys of skipped messages
+/// so they can still be decrypted when they show up late.
+fn message_keys(session: &mut SessionStructure, their_ratchet_key: &[u8; 32], mut chain_key: ChainKey, counter: u32) -> Result<MessageKeys, SignalError> {
+    if chain_key.index > counter {
+        return session.take_message_keys(their_ratchet_key, counter)
+            .ok_or(SignalError::DuplicateMessage { counter });
+    }
+    if counter - chain_key.index > constant::SIGNAL_MAX_FORWARD_JUMPS {
+        return Err(SignalError::TooFarInFuture { counter });
+    }
+
+    while chain_key.index < counter {
+        session.store_message_keys(their_ratchet_key, &chain_key.message_keys());
+        chain_key = chain_key.next();
+    }
+    session.set_receiver_chain_key(their_ratchet_key, &chain_key.next());
+    Ok(chain_key.message_keys())
+}
+
+fn message_mac(mac_key: &[u8; 32], sender_identity: &[u8; 32], receiver_identity: &[u8; 32], content: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(mac_key).expect("HMAC accepts any key length");
+    mac.update(&serialize_public(sender_identity));
+    mac.update(&serialize_public(receiver_identity));
+    mac.update(content);
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+
+    use crate::proto::whatsapp::RecordStructure;
+    use crate::utils::key::{Key, PreKey};
+
+    use super::{decrypt, decrypt_pre_key, encrypt, process_bundle, CiphertextMessage, LocalIdentity, PreKeyBundle, PreKeyMessage, WhisperMessage};
+    use crate::signal::SignalError;
+
+    struct Party {
+        identity: Key,
+        registration_id: u32,
+        signed_pre_key: PreKey,
+        one_time_pre_key: Key,
+        record: RecordStructure,
+    }
+
+    impl Party {
+        fn new(registration_id: u32) -> Self {
+            let identity = Key::new();
+            let signed_pre_key = identity.create_signed_pre_key(7);
+            Self { identity, registration_id, signed_pre_key, one_time_pre_key: Key::new(), record: RecordStructure::default() }
+        }
+
+        fn local(&self) -> LocalIdentity<'_> {
+            LocalIdentity { identity_key: &self.identity, registration_id: self.registration_id }
+        }
+
+        fn bundle(&self, with_one_time_pre_key: bool) -> PreKeyBundle {
+            PreKeyBundle {
+                registration_id: self.registration_id,
+                identity_key: *self.identity.public.as_bytes(),
+                signed_pre_key_id: self.signed_pre_key.id,
+                signed_pre_key: *self.signed_pre_key.key.public.as_bytes(),
+                signed_pre_key_signature: self.signed_pre_key.signature,
+                pre_key: with_one_time_pre_key.then(|| (31, *self.one_time_pre_key.public.as_bytes())),
+            }
+        }
+
+        fn encrypt(&mut self, plaintext: &[u8]) -> CiphertextMessage {
+            encrypt(&mut self.record, plaintext).unwrap()
+        }
+
+        fn decrypt(&mut self, message: CiphertextMessage) -> Result<Vec<u8>, SignalError> {
+            // Go through the wire format every time, like a stored session would
+            self.record = RecordStructure::decode(&self.record.encode_to_vec()[..]).unwrap();
+            match message {
+                CiphertextMessage::PreKey(bytes) => {
+                    let message = PreKeyMessage::parse(&bytes)?;
+                    let local = LocalIdentity { identity_key: &self.identity, registration_id: self.registration_id };
+                    decrypt_pre_key(&mut self.record, &local, Some(&self.signed_pre_key.key), Some(&self.one_time_pre_key), &message)
+                        .map(|(plaintext, _)| plaintext)
+                }
+                CiphertextMessage::Whisper(bytes) => decrypt(&mut self.record, &WhisperMessage::parse(&bytes)?),
+            }
+        }
+    }
+
+    fn start_session(with_one_time_pre_key: bool) -> (Party, Party) {
+        let mut alice = Party::new(1);
+        let bob = Party::new(2);
+        let mut record = RecordStructure::default();
+        process_bundle(&mut record, &alice.local(), &bob.bundle(with_one_time_pre_key)).unwrap();
+        alice.record = record;
+        (alice, bob)
+    }
+
+    #[test]
+    fn exchanges_messages_both_ways() {
+        for with_one_time_pre_key in [true, false] {
+            let (mut alice, mut bob) = start_session(with_one_time_pre_key);
+
+            let first = alice.encrypt(b"hello bob");
+            assert_eq!(first.enc_type(), "pkmsg");
+            let CiphertextMessage::PreKey(bytes) = &first else { unreachable!() };
+            let parsed = PreKeyMessage::parse(bytes).unwrap();
+            assert_eq!(parsed.registration_id, 1);
+            assert_eq!(parsed.signed_pre_key_id, 7);
+            assert_eq!(parsed.pre_key_id, with_one_time_pre_key.then_some(31));
+            assert_eq!(bob.decrypt(first).unwrap(), b"hello bob");
+
+            // Alice keeps sending pre-key messages until Bob answers
+            assert_eq!(alice.encrypt(b"still there?").enc_type(), "pkmsg");
+            let reply = bob.encrypt(b"hello alice");
+            assert_eq!(reply.enc_type(), "msg");
+            assert_eq!(alice.decrypt(reply).unwrap(), b"hello alice");
+
+            for round in 0..5 {
+                let message = format!("ping {}", round);
+                let ciphertext = alice.encrypt(message.as_bytes());
+                assert_eq!(ciphertext.enc_type(), "msg");
+                assert_eq!(bob.decrypt(ciphertext).unwrap(), message.as_bytes());
+                assert_eq!(alice.decrypt(bob.encrypt(b"pong")).unwrap(), b"pong");
+            }
+        }
+    }
+
+    #[test]
+    fn decrypts_out_of_order_and_rejects_replays() {
+        let (mut alice, mut bob) = start_session(true);
+        assert_eq!(bob.decrypt(alice.encrypt(b"0")).unwrap(), b"0");
+        assert_eq!(alice.decrypt(bob.encrypt(b"ack")).unwrap(), b"ack");
+
+        let messages = (1..=4).map(|i| alice.encrypt(i.to_string().as_bytes()).into_bytes()).collect::<Vec<_>>();
+        for i in [3, 0, 2, 1] {
+            let plaintext = bob.decrypt(CiphertextMessage::Whisper(messages[i].clone())).unwrap();
+            assert_eq!(plaintext, (i + 1).to_string().as_bytes());
+        }
+        assert!(matches!(
+            bob.decrypt(CiphertextMessage::Whisper(messages[2].clone())),
+            Err(SignalError::DuplicateMessage { counter: 2 })
+        ));
+
+        // A late message from before Bob's ratchet turn still decrypts
+        let old = bob.encrypt(b"old chain").into_bytes();
+        assert_eq!(bob.decrypt(alice.encrypt(b"turn")).unwrap(), b"turn");
+        assert_eq!(alice.decrypt(bob.encrypt(b"new chain")).unwrap(), b"new chain");
+        assert_eq!(alice.decrypt(CiphertextMessage::Whisper(old)).unwrap(), b"old chain");
+    }
+
+    #[test]
+    fn rejects_tampered_messages_without_losing_state() {
+        let (mut alice, mut bob) = start_session(false);
+        assert_eq!(bob.decrypt(alice.encrypt(b"hi")).unwrap(), b"hi");
+
+        let mut tampered = bob.encrypt(b"secret").into_bytes();
+        let original = tampered.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 0x01;
+        assert!(matches!(alice.decrypt(CiphertextMessage::Whisper(tampered)), Err(SignalError::InvalidMac)));
+        assert_eq!(alice.decrypt(CiphertextMessage::Whisper(original)).unwrap(), b"secret");
+    }
+
+    #[test]
+    fn keeps_one_session_per_base_key() {
+        let (mut alice, mut bob) = start_session(true);
+        let first = alice.encrypt(b"first").into_bytes();
+        assert_eq!(bob.decrypt(CiphertextMessage::PreKey(first.clone())).unwrap(), b"first");
+        assert!(matches!(bob.decrypt(CiphertextMessage::PreKey(first)), Err(SignalError::DuplicateMessage { counter: 0 })));
+        assert!(bob.record.previous_sessions.is_empty());
+
+        // Without the one-time pre-key the session can't be rebuilt
+        let (mut alice, _) = start_session(true);
+        let message = PreKeyMessage::parse(&alice.encrypt(b"lost").into_bytes()).unwrap();
+        let local = LocalIdentity { identity_key: &bob.identity, registration_id: bob.registration_id };
+        assert!(matches!(
+            decrypt_pre_key(&mut RecordStructure::default(), &local, Some(&bob.signed_pre_key.key), None, &message),
+            Err(SignalError::MissingPreKey(31))
+        ));
+    }
+
+    #[test]
+    fn rejects_bundles_with_bad_signatures() {
+        let alice = Party::new(1);
+        let mut bundle = Party::new(2).bundle(true);
+        bundle.signed_pre_key_signature[0] ^= 0x01;
+        let mut record = RecordStructure::default();
+        assert!(matches!(process_bundle(&mut record, &alice.local(), &bundle), Err(SignalError::InvalidSignature)));
+        assert!(record.current_session.is_none());
+    }
+}
@@ -0,0 +1,122 @@
+use prost::Message;
+use sha2::{Digest, Sha512};
+
+use crate::constant;
+
+use super::{serialize_public, SignalError};
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct LogicalFingerprint {
+    #[prost(bytes = "vec", optional, tag = "1")]
+    content: Option<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct CombinedFingerprints {
+    #[prost(uint32, optional, tag = "1")]
+    version: Option<u32>,
+    #[prost(message, optional, tag = "2")]
+    local_fingerprint: Option<LogicalFingerprint>,
+    #[prost(message, optional, tag = "3")]
+    remote_fingerprint: Option<LogicalFingerprint>,
+}
+
+/// The safety number two contacts compare to make sure nobody sits between them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fingerprint {
+    /// 60 digits, the same on both sides.
+    pub display: String,
+    /// What goes in the QR code, the other side checks it with [`compare_scannable`].
+    pub scannable: Vec<u8>,
+}
+
+/// Derives the fingerprint of a contact pair, each side identified by a
+/// stable identifier like its phone number and its identity key.
+pub fn fingerprint(local_id: &str, local_key: &[u8; 32], remote_id: &str, remote_key: &[u8; 32]) -> Fingerprint {
+    let local = hash_identity(local_id, local_key);
+    let remote = hash_identity(remote_id, remote_key);
+
+    let (local_digits, remote_digits) = (display_digits(&local), display_digits(&remote));
+    let display = match local_digits <= remote_digits {
+        true => local_digits + &remote_digits,
+        false => remote_digits + &local_digits,
+    };
+    let scannable = CombinedFingerprints {
+        version: Some(constant::FINGERPRINT_SCANNABLE_VERSION),
+        local_fingerprint: Some(LogicalFingerprint { content: Some(local[..32].to_vec()) }),
+        remote_fingerprint: Some(LogicalFingerprint { content: Some(remote[..32].to_vec()) }),
+    }.encode_to_vec();
+
+    Fingerprint { display, scannable }
+}
+
+/// Checks the code scanned off the other side against ours, their local half is our remote one.
+pub fn compare_scannable(ours: &[u8], theirs: &[u8]) -> Result<bool, SignalError> {
+    let ours = CombinedFingerprints::decode(ours).map_err(|e| SignalError::InvalidMessage(e.to_string()))?;
+    let theirs = CombinedFingerprints::decode(theirs).map_err(|e| SignalError::InvalidMessage(e.to_string()))?;
+    if theirs.version != ours.version {
+        return Err(SignalError::UnsupportedVersion(theirs.version.unwrap_or(0) as u8));
+    }
+    Ok(theirs.local_fingerprint == ours.remote_fingerprint && theirs.remote_fingerprint == ours.local_fingerprint)
+}
+
+fn hash_identity(id: &str, identity_key: &[u8; 32]) -> [u8; 64] {
+    let public = serialize_public(identity_key);
+    let mut hash: Vec<u8> = [&constant::FINGERPRINT_VERSION.to_be_bytes()[..], &public, id.as_bytes()].concat();
+    for _ in 0..constant::FINGERPRINT_ITERATIONS {
+        hash = Sha512::new().chain_update(&hash).chain_update(&public).finalize().to_vec();
+    }
+    hash.try_into().unwrap()
+}
+
+/// Six chunks of five bytes, each shown as five digits.
+fn display_digits(hash: &[u8; 64]) -> String {
+    hash[..30].chunks(5).map(|chunk| {
+        let value = chunk.iter().fold(0u64, |value, byte| value << 8 | *byte as u64);
+        format!("{:05}", value % 100_000)
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::signal::parse_public;
+    use crate::utils::key::Key;
+
+    use super::{compare_scannable, fingerprint};
+
+    // From libsignal's NumericFingerprintGeneratorTest
+    const ALICE_IDENTITY: [u8; 33] = [
+        0x05, 0x06, 0x86, 0x3b, 0xc6, 0x6d, 0x02, 0xb4, 0x0d, 0x27, 0xb8, 0xd4, 0x9c, 0xa7, 0xc0, 0x9e, 0x92,
+        0x39, 0x23, 0x6f, 0x9d, 0x7d, 0x25, 0xd6, 0xfc, 0xca, 0x5c, 0xe1, 0x3c, 0x70, 0x64, 0xd8, 0x68,
+    ];
+    const BOB_IDENTITY: [u8; 33] = [
+        0x05, 0xf7, 0x81, 0xb6, 0xfb, 0x32, 0xfe, 0xd9, 0xba, 0x1c, 0xf2, 0xde, 0x97, 0x8d, 0x4d, 0x5d, 0xa2,
+        0x8d, 0xc3, 0x40, 0x46, 0xae, 0x81, 0x44, 0x02, 0xb5, 0xc0, 0xdb, 0xd9, 0x6f, 0xda, 0x90, 0x7b,
+    ];
+
+    #[test]
+    fn matches_libsignal_vector() {
+        let alice = parse_public(&ALICE_IDENTITY).unwrap();
+        let bob = parse_public(&BOB_IDENTITY).unwrap();
+        let alice_view = fingerprint("+14152222222", &alice, "+14153333333", &bob);
+        let bob_view = fingerprint("+14153333333", &bob, "+14152222222", &alice);
+
+        assert_eq!(alice_view.display, "300354477692869396892869876765458257569162576843440918079131");
+        assert_eq!(alice_view.display, bob_view.display);
+        assert_eq!(hex::encode(&alice_view.scannable), "080112220a201e301a0353dce3dbe7684cb8336e85136cdc0ee96219494ada305d62a7bd61df1a220a20d62cbf73a11592015b6b9f1682ac306fea3aaf3885b84d12bca631e9d4fb3a4d");
+        assert!(compare_scannable(&alice_view.scannable, &bob_view.scannable).unwrap());
+    }
+
+    #[test]
+    fn detects_mismatched_keys() {
+        let (alice, bob, mallory) = (Key::new(), Key::new(), Key::new());
+        let alice_view = fingerprint("6281111111111", alice.public.as_bytes(), "6282222222222", bob.public.as_bytes());
+        let bob_view = fingerprint("6282222222222", bob.public.as_bytes(), "6281111111111", mallory.public.as_bytes());
+
+        assert_eq!(alice_view.display.len(), 60);
+        assert!(alice_view.display.bytes().all(|digit| digit.is_ascii_digit()));
+        assert_ne!(alice_view.display, bob_view.display);
+        assert!(!compare_scannable(&alice_view.scannable, &bob_view.scannable).unwrap());
+        assert!(compare_scannable(&alice_view.scannable, b"\x08\x02").is_err());
+    }
+}
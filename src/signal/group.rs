@@ -0,0 +1,292 @@
+use hmac::{Hmac, Mac};
+use prost::Message;
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+
+use crate::constant;
+use crate::proto::whatsapp::sender_key_state_structure::{SenderChainKey, SenderMessageKey, SenderSigningKey};
+use crate::proto::whatsapp::{SenderKeyDistributionMessage, SenderKeyMessage, SenderKeyRecordStructure, SenderKeyStateStructure};
+use crate::utils::aes_cbc;
+use crate::utils::key::{self, Key};
+
+use super::ratchet::hkdf;
+use super::{parse_public, serialize_public, versioned_body, SignalError, VERSION_BYTE};
+
+const MESSAGE_KEY_SEED: u8 = 0x01;
+const CHAIN_KEY_SEED: u8 = 0x02;
+const SIGNATURE_SIZE: usize = 64;
+
+/// What a sender hands every group member, pairwise encrypted, so they can
+/// follow its sender key chain.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SenderKeyDistribution {
+    pub id: u32,
+    pub iteration: u32,
+    pub chain_key: [u8; 32],
+    pub signing_key: [u8; 32],
+}
+
+struct SenderMessageKeys {
+    cipher_key: [u8; 32],
+    iv: [u8; 16],
+}
+
+impl SenderKeyDistribution {
+    pub fn parse(bytes: &[u8]) -> Result<Self, SignalError> {
+        let body = versioned_body(bytes, 0)?;
+        let message = SenderKeyDistributionMessage::decode(body).map_err(|e| SignalError::InvalidMessage(e.to_string()))?;
+        let (Some(id), Some(iteration), Some(chain_key), Some(signing_key)) =
+            (message.id, message.iteration, message.chain_key, message.signing_key) else {
+            return Err(SignalError::InvalidMessage("incomplete sender key distribution".into()));
+        };
+
+        Ok(Self {
+            id,
+            iteration,
+            chain_key: chain_key.as_slice().try_into()
+                .map_err(|_| SignalError::InvalidKey(format!("chain key should be 32 bytes, got {}", chain_key.len())))?,
+            signing_key: parse_public(&signing_key)?,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let message = SenderKeyDistributionMessage {
+            id: Some(self.id),
+            iteration: Some(self.iteration),
+            chain_key: Some(self.chain_key.to_vec()),
+            signing_key: Some(serialize_public(&self.signing_key)),
+        };
+        [&[VERSION_BYTE][..], &message.encode_to_vec()].concat()
+    }
+}
+
+impl SenderKeyRecordStructure {
+    fn state_mut(&mut self, id: u32) -> Option<&mut SenderKeyStateStructure> {
+        self.sender_key_states.iter_mut().find(|state| state.sender_key_id == Some(id))
+    }
+
+    /// Newest state first, the oldest ones fall off.
+    fn add_state(&mut self, state: SenderKeyStateStructure) {
+        self.sender_key_states.insert(0, state);
+        self.sender_key_states.truncate(constant::SIGNAL_MAX_SENDER_KEY_STATES);
+    }
+}
+
+/// Returns the distribution for our own sender key, generating the key
+/// first if the record doesn't hold one we can send with.
+pub fn create_sender_key(record: &mut SenderKeyRecordStructure) -> Result<SenderKeyDistribution, SignalError> {
+    let can_send = record.sender_key_states.first()
+        .and_then(|state| state.sender_signing_key.as_ref())
+        .is_some_and(|signing_key| signing_key.private.is_some());
+    if !can_send {
+        let mut chain_key = [0u8; 32];
+        OsRng.fill_bytes(&mut chain_key);
+        let signing_key = Key::new();
+        record.add_state(SenderKeyStateStructure {
+            sender_key_id: Some(OsRng.next_u32() >> 1),
+            sender_chain_key: Some(SenderChainKey { iteration: Some(0), seed: Some(chain_key.to_vec()) }),
+            sender_signing_key: Some(SenderSigningKey {
+                public: Some(serialize_public(signing_key.public.as_bytes())),
+                private: Some(signing_key.private.to_bytes().to_vec()),
+            }),
+            sender_message_keys: Vec::new(),
+        });
+    }
+
+    let state = &record.sender_key_states[0];
+    let (iteration, chain_key) = chain_key(state)?;
+    Ok(SenderKeyDistribution {
+        id: state.sender_key_id(),
+        iteration,
+        chain_key,
+        signing_key: parse_public(state.sender_signing_key.as_ref().map(|key| key.public()).unwrap_or_default())?,
+    })
+}
+
+/// Starts following the chain of another member. Seeing the same
+/// distribution twice keeps the state we already advanced.
+pub fn process_distribution(record: &mut SenderKeyRecordStructure, distribution: &SenderKeyDistribution) {
+    let signing_key = serialize_public(&distribution.signing_key);
+    let known = record.sender_key_states.iter().any(|state| {
+        state.sender_key_id == Some(distribution.id)
+            && state.sender_signing_key.as_ref().is_some_and(|key| key.public() == signing_key)
+    });
+    if known {
+        return;
+    }
+
+    record.add_state(SenderKeyStateStructure {
+        sender_key_id: Some(distribution.id),
+        sender_chain_key: Some(SenderChainKey { iteration: Some(distribution.iteration), seed: Some(distribution.chain_key.to_vec()) }),
+        sender_signing_key: Some(SenderSigningKey { public: Some(signing_key), private: None }),
+        sender_message_keys: Vec::new(),
+    });
+}
+
+/// Encrypts with our own sender key, the result is the content of an `skmsg` enc node.
+pub fn encrypt(record: &mut SenderKeyRecordStructure, plaintext: &[u8]) -> Result<Vec<u8>, SignalError> {
+    let state = record.sender_key_states.first_mut().ok_or(SignalError::NoSession)?;
+    let signing_key: [u8; 32] = state.sender_signing_key.as_ref()
+        .and_then(|key| key.private.as_deref())
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| SignalError::InvalidKey("sender key can't sign".into()))?;
+    let (iteration, chain_key) = chain_key(state)?;
+    let keys = message_keys(&seed(&chain_key, MESSAGE_KEY_SEED));
+
+    let message = SenderKeyMessage {
+        id: state.sender_key_id,
+        iteration: Some(iteration),
+        ciphertext: Some(aes_cbc::encrypt(&keys.cipher_key, &keys.iv, plaintext)),
+    };
+    let mut serialized = [&[VERSION_BYTE][..], &message.encode_to_vec()].concat();
+    let signature = Key::from_private(signing_key).sign_message(&serialized);
+    serialized.extend_from_slice(&signature);
+
+    state.sender_chain_key = Some(SenderChainKey { iteration: Some(iteration + 1), seed: Some(seed(&chain_key, CHAIN_KEY_SEED).to_vec()) });
+    Ok(serialized)
+}
+
+pub fn decrypt(record: &mut SenderKeyRecordStructure, bytes: &[u8]) -> Result<Vec<u8>, SignalError> {
+    let body = versioned_body(bytes, SIGNATURE_SIZE)?;
+    let message = SenderKeyMessage::decode(body).map_err(|e| SignalError::InvalidMessage(e.to_string()))?;
+    let (Some(id), Some(iteration), Some(ciphertext)) = (message.id, message.iteration, message.ciphertext) else {
+        return Err(SignalError::InvalidMessage("incomplete sender key message".into()));
+    };
+
+    // Work on a copy so a message that fails to decrypt can't move the chain
+    let mut updated = record.clone();
+    let state = updated.state_mut(id).ok_or(SignalError::NoSession)?;
+    let signing_key = parse_public(state.sender_signing_key.as_ref().map(|key| key.public()).unwrap_or_default())?;
+    let (signed, signature) = bytes.split_at(bytes.len() - SIGNATURE_SIZE);
+    if !key::verify_signature(&signing_key, signed, signature.try_into().unwrap()) {
+        return Err(SignalError::InvalidSignature);
+    }
+
+    let keys = sender_message_keys(state, iteration)?;
+    let plaintext = aes_cbc::decrypt(&keys.cipher_key, &keys.iv, &ciphertext)
+        .ok_or_else(|| SignalError::InvalidMessage("bad padding".into()))?;
+    *record = updated;
+    Ok(plaintext)
+}
+
+fn sender_message_keys(state: &mut SenderKeyStateStructure, iteration: u32) -> Result<SenderMessageKeys, SignalError> {
+    let (current, mut chain_key) = chain_key(state)?;
+    if iteration < current {
+        let index = state.sender_message_keys.iter()
+            .position(|key| key.iteration() == iteration)
+            .ok_or(SignalError::DuplicateMessage { counter: iteration })?;
+        let key = state.sender_message_keys.remove(index);
+        return Ok(message_keys(key.seed()));
+    }
+    if iteration - current > constant::SIGNAL_MAX_MESSAGE_KEYS as u32 {
+        return Err(SignalError::TooFarInFuture { counter: iteration });
+    }
+
+    for skipped in current..iteration {
+        state.sender_message_keys.push(SenderMessageKey {
+            iteration: Some(skipped),
+            seed: Some(seed(&chain_key, MESSAGE_KEY_SEED).to_vec()),
+        });
+        chain_key = seed(&chain_key, CHAIN_KEY_SEED);
+    }
+    let excess = state.sender_message_keys.len().saturating_sub(constant::SIGNAL_MAX_MESSAGE_KEYS);
+    state.sender_message_keys.drain(..excess);
+
+    state.sender_chain_key = Some(SenderChainKey { iteration: Some(iteration + 1), seed: Some(seed(&chain_key, CHAIN_KEY_SEED).to_vec()) });
+    Ok(message_keys(&seed(&chain_key, MESSAGE_KEY_SEED)))
+}
+
+fn chain_key(state: &SenderKeyStateStructure) -> Result<(u32, [u8; 32]), SignalError> {
+    let chain = state.sender_chain_key.as_ref().ok_or(SignalError::NoSession)?;
+    let seed = chain.seed().try_into().map_err(|_| SignalError::InvalidKey("sender chain key should be 32 bytes".into()))?;
+    Ok((chain.iteration(), seed))
+}
+
+fn seed(chain_key: &[u8; 32], seed: u8) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(chain_key).expect("HMAC accepts any key length");
+    mac.update(&[seed]);
+    mac.finalize().into_bytes().into()
+}
+
+fn message_keys(seed: &[u8]) -> SenderMessageKeys {
+    let derived = hkdf::<48>(seed, None, b"WhisperGroup");
+    SenderMessageKeys {
+        iv: derived[..16].try_into().unwrap(),
+        cipher_key: derived[16..].try_into().unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+
+    use crate::proto::whatsapp::SenderKeyRecordStructure;
+    use crate::signal::SignalError;
+
+    use super::{create_sender_key, decrypt, encrypt, process_distribution, SenderKeyDistribution};
+
+    fn sender_and_member() -> (SenderKeyRecordStructure, SenderKeyRecordStructure) {
+        let mut sender = SenderKeyRecordStructure::default();
+        let distribution = create_sender_key(&mut sender).unwrap();
+        let distribution = SenderKeyDistribution::parse(&distribution.serialize()).unwrap();
+
+        let mut member = SenderKeyRecordStructure::default();
+        process_distribution(&mut member, &distribution);
+        (sender, member)
+    }
+
+    #[test]
+    fn decrypts_group_messages() {
+        let (mut sender, mut member) = sender_and_member();
+        for plaintext in [&b"first"[..], b"second", b"third"] {
+            let ciphertext = encrypt(&mut sender, plaintext).unwrap();
+            // Go through the wire format like a stored record would
+            member = SenderKeyRecordStructure::decode(&member.encode_to_vec()[..]).unwrap();
+            assert_eq!(decrypt(&mut member, &ciphertext).unwrap(), plaintext);
+        }
+        // Asking again hands out the same key, now further along its chain
+        let distribution = create_sender_key(&mut sender).unwrap();
+        assert_eq!(distribution.iteration, 3);
+        assert_eq!(sender.sender_key_states.len(), 1);
+    }
+
+    #[test]
+    fn decrypts_out_of_order() {
+        let (mut sender, mut member) = sender_and_member();
+        let messages: Vec<_> = (0..4u8).map(|i| encrypt(&mut sender, &[i]).unwrap()).collect();
+
+        assert_eq!(decrypt(&mut member, &messages[3]).unwrap(), [3]);
+        assert_eq!(decrypt(&mut member, &messages[1]).unwrap(), [1]);
+        assert_eq!(decrypt(&mut member, &messages[0]).unwrap(), [0]);
+        assert!(matches!(decrypt(&mut member, &messages[1]), Err(SignalError::DuplicateMessage { counter: 1 })));
+        assert_eq!(decrypt(&mut member, &messages[2]).unwrap(), [2]);
+    }
+
+    #[test]
+    fn rejects_forged_messages() {
+        let (mut sender, mut member) = sender_and_member();
+        let mut forged = encrypt(&mut sender, b"hello").unwrap();
+        let last = forged.len() - 1;
+        forged[last] ^= 1;
+        assert!(matches!(decrypt(&mut member, &forged), Err(SignalError::InvalidSignature)));
+
+        // Someone who only knows the distribution can't send as the sender
+        let mut impostor = member.clone();
+        assert!(matches!(encrypt(&mut impostor, b"hello"), Err(SignalError::InvalidKey(_))));
+
+        let mut stranger = SenderKeyRecordStructure::default();
+        let ciphertext = encrypt(&mut sender, b"hello").unwrap();
+        assert!(matches!(decrypt(&mut stranger, &ciphertext), Err(SignalError::NoSession)));
+    }
+
+    #[test]
+    fn keeps_old_keys_after_rotation() {
+        let (mut sender, mut member) = sender_and_member();
+        let before_rotation = encrypt(&mut sender, b"old").unwrap();
+
+        let mut rotated = SenderKeyRecordStructure::default();
+        process_distribution(&mut member, &create_sender_key(&mut rotated).unwrap());
+        assert_eq!(decrypt(&mut member, &encrypt(&mut rotated, b"new").unwrap()).unwrap(), b"new");
+        assert_eq!(decrypt(&mut member, &before_rotation).unwrap(), b"old");
+    }
+}
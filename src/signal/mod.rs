@@ -0,0 +1,80 @@
+use std::fmt;
+
+use crate::constant;
+use crate::store::StoreError;
+
+pub mod cipher;
+pub mod fingerprint;
+pub mod group;
+pub mod ratchet;
+pub mod session;
+
+pub const VERSION_BYTE: u8 = constant::SIGNAL_VERSION << 4 | constant::SIGNAL_VERSION;
+
+#[derive(Debug)]
+pub enum SignalError {
+    InvalidMessage(String),
+    InvalidKey(String),
+    InvalidMac,
+    InvalidSignature,
+    UnsupportedVersion(u8),
+    NoSession,
+    DuplicateMessage { counter: u32 },
+    TooFarInFuture { counter: u32 },
+    MissingPreKey(u32),
+    MissingSignedPreKey(u32),
+    UntrustedIdentity(String),
+    Store(StoreError),
+}
+
+impl fmt::Display for SignalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignalError::InvalidMessage(e) => write!(f, "invalid message: {}", e),
+            SignalError::InvalidKey(e) => write!(f, "invalid key: {}", e),
+            SignalError::InvalidMac => write!(f, "message mac mismatch"),
+            SignalError::InvalidSignature => write!(f, "invalid signature"),
+            SignalError::UnsupportedVersion(version) => write!(f, "unsupported message version {}", version),
+            SignalError::NoSession => write!(f, "no session to decrypt with"),
+            SignalError::DuplicateMessage { counter } => write!(f, "message {} was already decrypted", counter),
+            SignalError::TooFarInFuture { counter } => write!(f, "message {} is too far in the future", counter),
+            SignalError::MissingPreKey(id) => write!(f, "pre-key {} not found", id),
+            SignalError::MissingSignedPreKey(id) => write!(f, "signed pre-key {} not found", id),
+            SignalError::UntrustedIdentity(address) => write!(f, "identity of {} is not trusted", address),
+            SignalError::Store(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SignalError {}
+
+impl From<StoreError> for SignalError {
+    fn from(e: StoreError) -> Self {
+        SignalError::Store(e)
+    }
+}
+
+/// Public keys travel with a leading key type byte.
+pub fn serialize_public(key: &[u8; 32]) -> Vec<u8> {
+    [&[constant::SIGNAL_DJB_TYPE][..], key].concat()
+}
+
+pub fn parse_public(key: &[u8]) -> Result<[u8; 32], SignalError> {
+    let key = match key {
+        [constant::SIGNAL_DJB_TYPE, rest @ ..] if rest.len() == 32 => rest,
+        key => key,
+    };
+    key.try_into().map_err(|_| SignalError::InvalidKey(format!("expected 32 bytes, got {}", key.len())))
+}
+
+/// Strips the version byte and `suffix` trailing bytes (mac or signature) off a serialized message.
+pub fn versioned_body(bytes: &[u8], suffix: usize) -> Result<&[u8], SignalError> {
+    if bytes.len() < 1 + suffix {
+        return Err(SignalError::InvalidMessage("message too short".into()));
+    }
+    let version = bytes[0] >> 4;
+    if version != constant::SIGNAL_VERSION {
+        return Err(SignalError::UnsupportedVersion(version));
+    }
+    Ok(&bytes[1..bytes.len() - suffix])
+}
@@ -0,0 +1,76 @@
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const MESSAGE_KEY_SEED: u8 = 0x01;
+const CHAIN_KEY_SEED: u8 = 0x02;
+
+pub struct RootKey {
+    pub key: [u8; 32],
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChainKey {
+    pub key: [u8; 32],
+    pub index: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MessageKeys {
+    pub cipher_key: [u8; 32],
+    pub mac_key: [u8; 32],
+    pub iv: [u8; 16],
+    pub index: u32,
+}
+
+impl RootKey {
+    /// One step of the DH ratchet, mixing a fresh shared secret into the root.
+    pub fn create_chain(&self, their_ratchet_key: &[u8; 32], our_ratchet_key: &StaticSecret) -> (RootKey, ChainKey) {
+        let shared = our_ratchet_key.diffie_hellman(&PublicKey::from(*their_ratchet_key));
+        let derived = hkdf::<64>(shared.as_bytes(), Some(&self.key), b"WhisperRatchet");
+        split_keys(&derived)
+    }
+}
+
+impl ChainKey {
+    pub fn next(&self) -> ChainKey {
+        ChainKey { key: self.seed(CHAIN_KEY_SEED), index: self.index + 1 }
+    }
+
+    pub fn message_keys(&self) -> MessageKeys {
+        let derived = hkdf::<80>(&self.seed(MESSAGE_KEY_SEED), None, b"WhisperMessageKeys");
+        MessageKeys {
+            cipher_key: derived[..32].try_into().unwrap(),
+            mac_key: derived[32..64].try_into().unwrap(),
+            iv: derived[64..].try_into().unwrap(),
+            index: self.index,
+        }
+    }
+
+    fn seed(&self, seed: u8) -> [u8; 32] {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(&[seed]);
+        mac.finalize().into_bytes().into()
+    }
+}
+
+/// Turns the X3DH master secret into the first root and chain keys.
+pub fn derive_keys(master_secret: &[u8]) -> (RootKey, ChainKey) {
+    split_keys(&hkdf::<64>(master_secret, None, b"WhisperText"))
+}
+
+fn split_keys(derived: &[u8; 64]) -> (RootKey, ChainKey) {
+    (
+        RootKey { key: derived[..32].try_into().unwrap() },
+        ChainKey { key: derived[32..].try_into().unwrap(), index: 0 },
+    )
+}
+
+pub fn hkdf<const N: usize>(secret: &[u8], salt: Option<&[u8]>, info: &[u8]) -> [u8; N] {
+    let mut output = [0u8; N];
+    Hkdf::<Sha256>::new(salt, secret)
+        .expand(info, &mut output)
+        .expect("output is within the HKDF length limit");
+    output
+}
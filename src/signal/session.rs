@@ -0,0 +1,235 @@
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::constant;
+use crate::proto::whatsapp::session_structure::chain::{ChainKey as ChainKeyStructure, MessageKey};
+use crate::proto::whatsapp::session_structure::{Chain, PendingPreKey};
+use crate::proto::whatsapp::{RecordStructure, SessionStructure};
+use crate::utils::key::Key;
+
+use super::ratchet::{derive_keys, ChainKey, MessageKeys, RootKey};
+use super::{parse_public, serialize_public, SignalError};
+
+/// Keys the initiator combines with the pre-key bundle it fetched.
+pub struct AliceParameters<'a> {
+    pub our_identity: &'a Key,
+    pub our_base_key: &'a Key,
+    pub their_identity: [u8; 32],
+    pub their_signed_pre_key: [u8; 32],
+    pub their_one_time_pre_key: Option<[u8; 32]>,
+}
+
+/// Keys the responder combines with the first pre-key message it receives.
+pub struct BobParameters<'a> {
+    pub our_identity: &'a Key,
+    pub our_signed_pre_key: &'a Key,
+    pub our_one_time_pre_key: Option<&'a Key>,
+    pub their_identity: [u8; 32],
+    pub their_base_key: [u8; 32],
+}
+
+pub fn initialize_alice_session(params: &AliceParameters) -> SessionStructure {
+    let mut secrets = vec![0xff; 32];
+    secrets.extend(dh(&params.our_identity.private, &params.their_signed_pre_key));
+    secrets.extend(dh(&params.our_base_key.private, &params.their_identity));
+    secrets.extend(dh(&params.our_base_key.private, &params.their_signed_pre_key));
+    if let Some(one_time_pre_key) = &params.their_one_time_pre_key {
+        secrets.extend(dh(&params.our_base_key.private, one_time_pre_key));
+    }
+    let (root_key, chain_key) = derive_keys(&secrets);
+
+    // Their signed pre-key doubles as their first ratchet key
+    let sending_ratchet = Key::new();
+    let (root_key, sending_chain) = root_key.create_chain(&params.their_signed_pre_key, &sending_ratchet.private);
+
+    let mut session = new_session(params.our_identity, &params.their_identity);
+    session.set_root_key(&root_key);
+    session.add_receiver_chain(&params.their_signed_pre_key, &chain_key);
+    session.set_sender_chain(&sending_ratchet, &sending_chain);
+    session
+}
+
+pub fn initialize_bob_session(params: &BobParameters) -> SessionStructure {
+    let mut secrets = vec![0xff; 32];
+    secrets.extend(dh(&params.our_signed_pre_key.private, &params.their_identity));
+    secrets.extend(dh(&params.our_identity.private, &params.their_base_key));
+    secrets.extend(dh(&params.our_signed_pre_key.private, &params.their_base_key));
+    if let Some(one_time_pre_key) = params.our_one_time_pre_key {
+        secrets.extend(dh(&one_time_pre_key.private, &params.their_base_key));
+    }
+    let (root_key, chain_key) = derive_keys(&secrets);
+
+    let mut session = new_session(params.our_identity, &params.their_identity);
+    session.set_root_key(&root_key);
+    session.set_sender_chain(params.our_signed_pre_key, &chain_key);
+    session
+}
+
+fn new_session(our_identity: &Key, their_identity: &[u8; 32]) -> SessionStructure {
+    SessionStructure {
+        session_version: Some(constant::SIGNAL_VERSION as u32),
+        local_identity_public: Some(serialize_public(our_identity.public.as_bytes())),
+        remote_identity_public: Some(serialize_public(their_identity)),
+        ..Default::default()
+    }
+}
+
+fn dh(private: &StaticSecret, public: &[u8; 32]) -> [u8; 32] {
+    private.diffie_hellman(&PublicKey::from(*public)).to_bytes()
+}
+
+impl SessionStructure {
+    pub fn local_identity_key(&self) -> Result<[u8; 32], SignalError> {
+        parse_public(self.local_identity_public())
+    }
+
+    pub fn remote_identity_key(&self) -> Result<[u8; 32], SignalError> {
+        parse_public(self.remote_identity_public())
+    }
+
+    pub fn current_root_key(&self) -> Result<RootKey, SignalError> {
+        let key = self.root_key().try_into().map_err(|_| SignalError::InvalidKey("invalid root key".into()))?;
+        Ok(RootKey { key })
+    }
+
+    pub fn set_root_key(&mut self, root_key: &RootKey) {
+        self.root_key = Some(root_key.key.to_vec());
+    }
+
+    pub fn sender_ratchet(&self) -> Result<Key, SignalError> {
+        let private = self.sender_chain.as_ref()
+            .and_then(|chain| chain.sender_ratchet_key_private.as_deref())
+            .ok_or(SignalError::NoSession)?;
+        let private = private.try_into().map_err(|_| SignalError::InvalidKey("invalid sender ratchet key".into()))?;
+        Ok(Key::from_private(private))
+    }
+
+    pub fn sender_chain_key(&self) -> Result<ChainKey, SignalError> {
+        let chain_key = self.sender_chain.as_ref()
+            .and_then(|chain| chain.chain_key.as_ref())
+            .ok_or(SignalError::NoSession)?;
+        parse_chain_key(chain_key)
+    }
+
+    pub fn set_sender_chain(&mut self, ratchet: &Key, chain_key: &ChainKey) {
+        self.sender_chain = Some(Chain {
+            sender_ratchet_key: Some(serialize_public(ratchet.public.as_bytes())),
+            sender_ratchet_key_private: Some(ratchet.private.to_bytes().to_vec()),
+            chain_key: Some(chain_key_structure(chain_key)),
+            message_keys: Vec::new(),
+        });
+    }
+
+    pub fn set_sender_chain_key(&mut self, chain_key: &ChainKey) {
+        if let Some(chain) = self.sender_chain.as_mut() {
+            chain.chain_key = Some(chain_key_structure(chain_key));
+        }
+    }
+
+    pub fn receiver_chain_key(&self, their_ratchet_key: &[u8; 32]) -> Result<Option<ChainKey>, SignalError> {
+        match self.receiver_chain(their_ratchet_key).and_then(|chain| chain.chain_key.as_ref()) {
+            Some(chain_key) => parse_chain_key(chain_key).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn add_receiver_chain(&mut self, their_ratchet_key: &[u8; 32], chain_key: &ChainKey) {
+        self.receiver_chains.push(Chain {
+            sender_ratchet_key: Some(serialize_public(their_ratchet_key)),
+            chain_key: Some(chain_key_structure(chain_key)),
+            ..Default::default()
+        });
+        if self.receiver_chains.len() > constant::SIGNAL_MAX_RECEIVER_CHAINS {
+            self.receiver_chains.remove(0);
+        }
+    }
+
+    pub fn set_receiver_chain_key(&mut self, their_ratchet_key: &[u8; 32], chain_key: &ChainKey) {
+        if let Some(chain) = self.receiver_chain_mut(their_ratchet_key) {
+            chain.chain_key = Some(chain_key_structure(chain_key));
+        }
+    }
+
+    /// Removes and returns keys kept for a message that arrived out of order.
+    pub fn take_message_keys(&mut self, their_ratchet_key: &[u8; 32], counter: u32) -> Option<MessageKeys> {
+        let chain = self.receiver_chain_mut(their_ratchet_key)?;
+        let position = chain.message_keys.iter().position(|keys| keys.index == Some(counter))?;
+        parse_message_keys(&chain.message_keys.remove(position))
+    }
+
+    pub fn store_message_keys(&mut self, their_ratchet_key: &[u8; 32], keys: &MessageKeys) {
+        let Some(chain) = self.receiver_chain_mut(their_ratchet_key) else { return };
+        chain.message_keys.push(MessageKey {
+            index: Some(keys.index),
+            cipher_key: Some(keys.cipher_key.to_vec()),
+            mac_key: Some(keys.mac_key.to_vec()),
+            iv: Some(keys.iv.to_vec()),
+        });
+        if chain.message_keys.len() > constant::SIGNAL_MAX_MESSAGE_KEYS {
+            chain.message_keys.remove(0);
+        }
+    }
+
+    /// Marks the session as started by us, so outgoing messages carry the
+    /// pre-key details until the other side answers.
+    pub fn set_unacknowledged_pre_key(&mut self, pre_key_id: Option<u32>, signed_pre_key_id: u32, base_key: &[u8; 32]) {
+        self.pending_pre_key = Some(PendingPreKey {
+            pre_key_id,
+            signed_pre_key_id: Some(signed_pre_key_id as i32),
+            base_key: Some(serialize_public(base_key)),
+        });
+    }
+
+    fn receiver_chain(&self, their_ratchet_key: &[u8; 32]) -> Option<&Chain> {
+        let key = serialize_public(their_ratchet_key);
+        self.receiver_chains.iter().find(|chain| chain.sender_ratchet_key.as_deref() == Some(&key[..]))
+    }
+
+    fn receiver_chain_mut(&mut self, their_ratchet_key: &[u8; 32]) -> Option<&mut Chain> {
+        let key = serialize_public(their_ratchet_key);
+        self.receiver_chains.iter_mut().find(|chain| chain.sender_ratchet_key.as_deref() == Some(&key[..]))
+    }
+}
+
+impl RecordStructure {
+    /// Makes `session` current, keeping the old one around for messages
+    /// still in flight under it.
+    pub fn promote_state(&mut self, session: SessionStructure) {
+        self.archive_current_state();
+        self.current_session = Some(session);
+    }
+
+    pub fn archive_current_state(&mut self) {
+        if let Some(current) = self.current_session.take() {
+            self.previous_sessions.insert(0, current);
+            self.previous_sessions.truncate(constant::SIGNAL_MAX_ARCHIVED_SESSIONS);
+        }
+    }
+
+    pub fn has_session_with_base_key(&self, base_key: &[u8; 32]) -> bool {
+        let base_key = serialize_public(base_key);
+        self.current_session.iter()
+            .chain(self.previous_sessions.iter())
+            .any(|session| session.session_version() == constant::SIGNAL_VERSION as u32 && session.alice_base_key() == &base_key[..])
+    }
+}
+
+fn chain_key_structure(chain_key: &ChainKey) -> ChainKeyStructure {
+    ChainKeyStructure {
+        index: Some(chain_key.index),
+        key: Some(chain_key.key.to_vec()),
+    }
+}
+
+fn parse_chain_key(chain_key: &ChainKeyStructure) -> Result<ChainKey, SignalError> {
+    let key = chain_key.key().try_into().map_err(|_| SignalError::InvalidKey("invalid chain key".into()))?;
+    Ok(ChainKey { key, index: chain_key.index() })
+}
+
+fn parse_message_keys(keys: &MessageKey) -> Option<MessageKeys> {
+    Some(MessageKeys {
+        cipher_key: keys.cipher_key().try_into().ok()?,
+        mac_key: keys.mac_key().try_into().ok()?,
+        iv: keys.iv().try_into().ok()?,
+        index: keys.index(),
+    })
+}
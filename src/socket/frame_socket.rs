@@ -1,3 +1,5 @@
+use std::fmt;
+
 use crate::constant;
 use crate::utils::key::Key;
 
@@ -7,9 +9,25 @@ pub enum FrameSocketState {
     Authenticated,
 }
 
+#[derive(Debug)]
+pub enum FrameError {
+    TooLarge { size: usize, max: usize },
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooLarge { size, max } => write!(f, "Frame too large got {}, max {}", size, max),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
 pub struct FrameSocket {
     pub key: Key,
     pub state: FrameSocketState,
+    incoming: Vec<u8>,
 }
 
 impl FrameSocket {
@@ -18,19 +36,38 @@ impl FrameSocket {
         Self {
             key,
             state: FrameSocketState::Handshake,
+            incoming: Vec::new(),
         }
     }
 
-    pub fn process_data(&self, data: Vec<u8>) -> Vec<u8> {
-        data[constant::FRAME_LENGTH_SIZE..].to_vec()
+    /// Splits what arrived so far into whole frames, keeping a partial one for later.
+    /// The 3 byte length prefix already caps a frame at [`constant::FRAME_MAX_SIZE`].
+    pub fn process_data(&mut self, data: Vec<u8>) -> Vec<Vec<u8>> {
+        self.incoming.extend_from_slice(&data);
+
+        let mut frames = Vec::new();
+        while self.incoming.len() >= constant::FRAME_LENGTH_SIZE {
+            let length = ((self.incoming[0] as usize) << 16)
+                | ((self.incoming[1] as usize) << 8)
+                | self.incoming[2] as usize;
+
+            let end = constant::FRAME_LENGTH_SIZE + length;
+            if self.incoming.len() < end {
+                break;
+            }
+
+            frames.push(self.incoming[constant::FRAME_LENGTH_SIZE..end].to_vec());
+            self.incoming.drain(..end);
+        }
+        frames
     }
 
-    pub fn make_frame(&self, data: Vec<u8>) -> Vec<u8> {
+    pub fn make_frame(&self, data: Vec<u8>) -> Result<Vec<u8>, FrameError> {
         let data_length = data.len();
         let header_length = 4;
 
-        if data_length >= constant::FRAME_MAX_SIZE {
-            panic!("Frame too large got {}, max {}", data_length, constant::FRAME_MAX_SIZE);
+        if data_length > constant::FRAME_MAX_SIZE {
+            return Err(FrameError::TooLarge { size: data_length, max: constant::FRAME_MAX_SIZE });
         }
 
         let mut frame = Vec::with_capacity(header_length + constant::FRAME_LENGTH_SIZE + data_length);
@@ -43,6 +80,53 @@ impl FrameSocket {
         frame.push(data_length as u8);
         frame.extend_from_slice(&data);
 
+        Ok(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::constant;
+
+    use super::{FrameError, FrameSocket, FrameSocketState};
+
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![(payload.len() >> 16) as u8, (payload.len() >> 8) as u8, payload.len() as u8];
+        frame.extend_from_slice(payload);
         frame
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn reassembles_partial_frames() {
+        let mut fs = FrameSocket::new();
+        let data = frame(&[7; 300]);
+
+        assert!(fs.process_data(data[..2].to_vec()).is_empty());
+        assert!(fs.process_data(data[2..100].to_vec()).is_empty());
+        assert_eq!(fs.process_data(data[100..].to_vec()), vec![vec![7; 300]]);
+    }
+
+    #[test]
+    fn splits_coalesced_frames() {
+        let mut fs = FrameSocket::new();
+        let mut data = [frame(b"first"), frame(b"second"), frame(b"third")].concat();
+        let tail = data.split_off(data.len() - 2);
+
+        assert_eq!(fs.process_data(data), vec![b"first".to_vec(), b"second".to_vec()]);
+        assert_eq!(fs.process_data(tail), vec![b"third".to_vec()]);
+    }
+
+    #[test]
+    fn refuses_frames_the_length_prefix_cannot_hold() {
+        let mut fs = FrameSocket::new();
+        fs.state = FrameSocketState::Authenticated;
+
+        let frame = fs.make_frame(vec![0; constant::FRAME_MAX_SIZE]).unwrap();
+        assert_eq!(frame[..3], [0xff, 0xff, 0xff]);
+        assert_eq!(fs.process_data(frame), vec![vec![0; constant::FRAME_MAX_SIZE]]);
+        assert!(matches!(
+            fs.make_frame(vec![0; constant::FRAME_MAX_SIZE + 1]),
+            Err(FrameError::TooLarge { size, .. }) if size == constant::FRAME_MAX_SIZE + 1
+        ));
+    }
+}
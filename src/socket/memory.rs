@@ -0,0 +1,121 @@
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use tokio::sync::mpsc;
+
+use crate::socket::transport::{Transport, TransportError, TransportHalves, TransportReceiver, TransportSender};
+
+pub struct MemoryTransport {
+    listener: mpsc::UnboundedSender<MemoryConnection>,
+}
+
+pub struct MemoryListener {
+    incoming: mpsc::UnboundedReceiver<MemoryConnection>,
+}
+
+pub struct MemoryConnection {
+    pub sender: MemorySender,
+    pub receiver: MemoryReceiver,
+}
+
+pub struct MemorySender {
+    tx: Option<mpsc::UnboundedSender<Vec<u8>>>,
+}
+
+pub struct MemoryReceiver {
+    rx: mpsc::UnboundedReceiver<Vec<u8>>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> (Self, MemoryListener) {
+        let (listener, incoming) = mpsc::unbounded_channel();
+        (Self { listener }, MemoryListener { incoming })
+    }
+}
+
+impl MemoryListener {
+    pub async fn accept(&mut self) -> Option<MemoryConnection> {
+        self.incoming.recv().await
+    }
+}
+
+impl MemoryConnection {
+    fn pair() -> (MemoryConnection, MemoryConnection) {
+        let (client_tx, server_rx) = mpsc::unbounded_channel();
+        let (server_tx, client_rx) = mpsc::unbounded_channel();
+
+        (
+            MemoryConnection { sender: MemorySender { tx: Some(client_tx) }, receiver: MemoryReceiver { rx: client_rx } },
+            MemoryConnection { sender: MemorySender { tx: Some(server_tx) }, receiver: MemoryReceiver { rx: server_rx } },
+        )
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn connect(&self) -> BoxFuture<'_, Result<TransportHalves, TransportError>> {
+        async move {
+            let (client, server) = MemoryConnection::pair();
+            self.listener.send(server).map_err(|_| TransportError::Connect("listener is gone".into()))?;
+
+            Ok((
+                Box::new(client.sender) as Box<dyn TransportSender>,
+                Box::new(client.receiver) as Box<dyn TransportReceiver>,
+            ))
+        }.boxed()
+    }
+}
+
+impl TransportSender for MemorySender {
+    fn send(&mut self, data: Vec<u8>) -> BoxFuture<'_, Result<(), TransportError>> {
+        async move {
+            let tx = self.tx.as_ref().ok_or(TransportError::Closed)?;
+            tx.send(data).map_err(|_| TransportError::Closed)
+        }.boxed()
+    }
+
+    fn close(&mut self) -> BoxFuture<'_, Result<(), TransportError>> {
+        async move {
+            self.tx = None;
+            Ok(())
+        }.boxed()
+    }
+}
+
+impl TransportReceiver for MemoryReceiver {
+    fn receive(&mut self) -> BoxFuture<'_, Option<Result<Vec<u8>, TransportError>>> {
+        async move {
+            self.rx.recv().await.map(Ok)
+        }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::socket::transport::{Transport, TransportReceiver, TransportSender};
+
+    use super::MemoryTransport;
+
+    #[tokio::test]
+    async fn delivers_frames_in_both_directions() {
+        let (transport, mut listener) = MemoryTransport::new();
+        let (mut client_write, mut client_read) = transport.connect().await.unwrap();
+        let mut server = listener.accept().await.unwrap();
+
+        client_write.send(vec![1, 2, 3]).await.unwrap();
+        server.sender.send(vec![4, 5]).await.unwrap();
+
+        assert_eq!(server.receiver.receive().await.unwrap().unwrap(), vec![1, 2, 3]);
+        assert_eq!(client_read.receive().await.unwrap().unwrap(), vec![4, 5]);
+    }
+
+    #[tokio::test]
+    async fn close_ends_the_peer_stream() {
+        let (transport, mut listener) = MemoryTransport::new();
+        let (mut client_write, _client_read) = transport.connect().await.unwrap();
+        let mut server = listener.accept().await.unwrap();
+
+        client_write.close().await.unwrap();
+
+        assert!(server.receiver.receive().await.is_none());
+        assert!(client_write.send(vec![1]).await.is_err());
+    }
+}
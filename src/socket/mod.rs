@@ -1,2 +1,6 @@
 pub mod frame_socket;
-pub mod noise_socket;
\ No newline at end of file
+pub mod noise_socket;
+pub mod transport;
+pub mod websocket;
+#[cfg(test)]
+pub mod memory;
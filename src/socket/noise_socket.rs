@@ -1,6 +1,5 @@
 use aes_gcm::{aead::Aead, Aes256Gcm, Nonce};
 use aes_gcm::aead::Payload;
-use paris::error;
 
 pub struct NoiseSocket {
     pub write_key: Aes256Gcm,
@@ -19,25 +18,16 @@ impl NoiseSocket {
         }
     }
 
-    pub fn receive_encrypted_frame(&mut self, ciphertext: &[u8]) -> Vec<u8> {
+    pub fn receive_encrypted_frame(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, aes_gcm::Error> {
         let counter = self.read_counter;
         self.read_counter += 1;
 
         let iv = Self::generate_iv(counter);
         let nonce = Nonce::from_slice(&iv);
-
-        match self.read_key.decrypt(nonce, Payload {
+        self.read_key.decrypt(nonce, Payload {
             msg: ciphertext,
             aad: b"",
-        }) {
-            Ok(plaintext) => {
-                plaintext
-            }
-            Err(e) => {
-                error!("Failed to decrypt frame: {:?}", e);
-                panic!("Failed to decrypt frame");
-            }
-        }
+        })
     }
 
     pub fn make_frame(&mut self, plaintext: Vec<u8>) -> Vec<u8> {
@@ -56,4 +46,22 @@ impl NoiseSocket {
         iv[11] = counter as u8;
         iv
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::utils::gcm;
+
+    use super::NoiseSocket;
+
+    #[test]
+    fn rejects_tampered_frames_and_keeps_reading() {
+        let mut sender = NoiseSocket::new(gcm::prepare(vec![1; 32]), gcm::prepare(vec![2; 32]));
+        let mut receiver = NoiseSocket::new(gcm::prepare(vec![2; 32]), gcm::prepare(vec![1; 32]));
+
+        let mut tampered = sender.make_frame(b"first".to_vec());
+        tampered[0] ^= 1;
+        assert!(receiver.receive_encrypted_frame(&tampered).is_err());
+        let frame = sender.make_frame(b"second".to_vec());
+        assert_eq!(receiver.receive_encrypted_frame(&frame).unwrap(), b"second");
+    }
+}
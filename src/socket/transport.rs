@@ -0,0 +1,40 @@
+use std::fmt;
+
+use futures_util::future::BoxFuture;
+
+#[derive(Debug)]
+pub enum TransportError {
+    Connect(String),
+    Send(String),
+    Receive(String),
+    Closed,
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::Connect(reason) => write!(f, "failed to connect: {}", reason),
+            TransportError::Send(reason) => write!(f, "failed to send frame: {}", reason),
+            TransportError::Receive(reason) => write!(f, "failed to receive frame: {}", reason),
+            TransportError::Closed => write!(f, "transport is closed"),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {}
+
+pub type TransportHalves = (Box<dyn TransportSender>, Box<dyn TransportReceiver>);
+
+pub trait Transport: Send + Sync {
+    fn connect(&self) -> BoxFuture<'_, Result<TransportHalves, TransportError>>;
+}
+
+pub trait TransportSender: Send {
+    fn send(&mut self, data: Vec<u8>) -> BoxFuture<'_, Result<(), TransportError>>;
+
+    fn close(&mut self) -> BoxFuture<'_, Result<(), TransportError>>;
+}
+
+pub trait TransportReceiver: Send {
+    fn receive(&mut self) -> BoxFuture<'_, Option<Result<Vec<u8>, TransportError>>>;
+}
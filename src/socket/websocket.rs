@@ -0,0 +1,86 @@
+use futures_util::future::BoxFuture;
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{FutureExt, SinkExt, StreamExt};
+use paris::info;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::{ClientRequestBuilder, Message};
+use tokio_tungstenite::{connect_async, WebSocketStream};
+
+use crate::constant;
+use crate::socket::transport::{Transport, TransportError, TransportHalves, TransportReceiver, TransportSender};
+
+pub struct WebSocketTransport {
+    pub url: String,
+    pub origin: String,
+}
+
+pub struct WebSocketSender<S> {
+    write: SplitSink<WebSocketStream<S>, Message>,
+}
+
+pub struct WebSocketReceiver<S> {
+    read: SplitStream<WebSocketStream<S>>,
+}
+
+pub fn split_stream<S>(stream: WebSocketStream<S>) -> TransportHalves
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (write, read) = stream.split();
+    (Box::new(WebSocketSender { write }), Box::new(WebSocketReceiver { read }))
+}
+
+impl Default for WebSocketTransport {
+    fn default() -> Self {
+        Self {
+            url: constant::WS_URL.to_string(),
+            origin: constant::ORIGIN.to_string(),
+        }
+    }
+}
+
+impl Transport for WebSocketTransport {
+    fn connect(&self) -> BoxFuture<'_, Result<TransportHalves, TransportError>> {
+        async move {
+            info!("Dialing {}", self.url);
+            let uri = self.url.parse().map_err(|e| TransportError::Connect(format!("{}", e)))?;
+            let request = ClientRequestBuilder::new(uri)
+                .with_header("Origin", self.origin.clone())
+                .into_client_request()
+                .map_err(|e| TransportError::Connect(e.to_string()))?;
+
+            let (ws_stream, _) = connect_async(request).await.map_err(|e| TransportError::Connect(e.to_string()))?;
+            Ok(split_stream(ws_stream))
+        }.boxed()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> TransportSender for WebSocketSender<S> {
+    fn send(&mut self, data: Vec<u8>) -> BoxFuture<'_, Result<(), TransportError>> {
+        async move {
+            self.write.send(Message::binary(data)).await.map_err(|e| TransportError::Send(e.to_string()))
+        }.boxed()
+    }
+
+    fn close(&mut self) -> BoxFuture<'_, Result<(), TransportError>> {
+        async move {
+            self.write.close().await.map_err(|e| TransportError::Send(e.to_string()))
+        }.boxed()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> TransportReceiver for WebSocketReceiver<S> {
+    fn receive(&mut self) -> BoxFuture<'_, Option<Result<Vec<u8>, TransportError>>> {
+        async move {
+            loop {
+                match self.read.next().await? {
+                    Ok(Message::Binary(data)) => return Some(Ok(data.to_vec())),
+                    Ok(Message::Close(_)) => return None,
+                    Ok(_) => continue,
+                    Err(e) => return Some(Err(TransportError::Receive(e.to_string()))),
+                }
+            }
+        }.boxed()
+    }
+}
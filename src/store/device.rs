@@ -0,0 +1,199 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use prost::Message;
+use serde::{Deserialize, Serialize};
+
+use crate::device::Device;
+use crate::proto::whatsapp::AdvSignedDeviceIdentity;
+use crate::store::{self, StoreError};
+use crate::utils::key::{Key, PreKey};
+
+pub trait DeviceStore: Send + Sync {
+    fn load(&self) -> Result<Option<Device>, StoreError>;
+
+    fn save(&self, device: &Device) -> Result<(), StoreError>;
+
+    fn delete(&self) -> Result<(), StoreError>;
+}
+
+pub struct FileDeviceStore {
+    pub path: PathBuf,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct StoredDevice {
+    noise_key: String,
+    identity_key: String,
+    signed_pre_key_id: u32,
+    signed_pre_key: String,
+    signed_pre_key_signature: String,
+    registration_id: u32,
+    adv_secret_key: String,
+    jid: Option<String>,
+    platform: String,
+    push_name: String,
+    #[serde(default)]
+    business_name: String,
+    #[serde(default)]
+    account: Option<String>,
+}
+
+impl FileDeviceStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl DeviceStore for FileDeviceStore {
+    fn load(&self) -> Result<Option<Device>, StoreError> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let stored: StoredDevice = serde_json::from_slice(&data).map_err(|e| StoreError::Corrupt(e.to_string()))?;
+        stored.into_device().map(Some)
+    }
+
+    fn save(&self, device: &Device) -> Result<(), StoreError> {
+        let data = serde_json::to_vec_pretty(&StoredDevice::from_device(device))
+            .map_err(|e| StoreError::Corrupt(e.to_string()))?;
+
+        store::write_private(&self.path, &data)
+    }
+
+    fn delete(&self) -> Result<(), StoreError> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl StoredDevice {
+    fn from_device(device: &Device) -> Self {
+        Self {
+            noise_key: hex::encode(device.noise_key.private.to_bytes()),
+            identity_key: hex::encode(device.identity_key.private.to_bytes()),
+            signed_pre_key_id: device.signed_pre_key.id,
+            signed_pre_key: hex::encode(device.signed_pre_key.key.private.to_bytes()),
+            signed_pre_key_signature: hex::encode(device.signed_pre_key.signature),
+            registration_id: device.registration_id,
+            adv_secret_key: hex::encode(device.adv_secret_key),
+            jid: device.jid.as_ref().map(|jid| jid.to_string()),
+            platform: device.platform.clone(),
+            push_name: device.push_name.clone(),
+            business_name: device.business_name.clone(),
+            account: device.account.as_ref().map(|account| hex::encode(account.encode_to_vec())),
+        }
+    }
+
+    fn into_device(self) -> Result<Device, StoreError> {
+        Ok(Device {
+            noise_key: Key::from_private(decode_hex(&self.noise_key)?),
+            identity_key: Key::from_private(decode_hex(&self.identity_key)?),
+            signed_pre_key: PreKey {
+                key: Key::from_private(decode_hex(&self.signed_pre_key)?),
+                id: self.signed_pre_key_id,
+                signature: decode_hex(&self.signed_pre_key_signature)?,
+            },
+            registration_id: self.registration_id,
+            adv_secret_key: decode_hex(&self.adv_secret_key)?,
+            jid: self.jid.map(|jid| jid.parse()).transpose().map_err(StoreError::Corrupt)?,
+            platform: self.platform,
+            push_name: self.push_name,
+            business_name: self.business_name,
+            account: self.account.map(|account| decode_account(&account)).transpose()?,
+        })
+    }
+}
+
+fn decode_hex<const N: usize>(value: &str) -> Result<[u8; N], StoreError> {
+    let bytes = hex::decode(value).map_err(|e| StoreError::Corrupt(e.to_string()))?;
+    bytes.try_into().map_err(|_| StoreError::Corrupt(format!("expected {} bytes", N)))
+}
+
+fn decode_account(value: &str) -> Result<AdvSignedDeviceIdentity, StoreError> {
+    let bytes = hex::decode(value).map_err(|e| StoreError::Corrupt(e.to_string()))?;
+    AdvSignedDeviceIdentity::decode(&bytes[..]).map_err(|e| StoreError::Corrupt(e.to_string()))
+}
+
+#[cfg(test)]
+pub mod memory {
+    use std::sync::{Arc, Mutex};
+
+    use crate::device::Device;
+    use crate::store::StoreError;
+
+    use super::{DeviceStore, StoredDevice};
+
+    #[derive(Clone, Default)]
+    pub struct MemoryDeviceStore {
+        device: Arc<Mutex<Option<StoredDevice>>>,
+    }
+
+    impl DeviceStore for MemoryDeviceStore {
+        fn load(&self) -> Result<Option<Device>, StoreError> {
+            match self.device.lock().unwrap().as_ref() {
+                Some(stored) => stored.clone().into_device().map(Some),
+                None => Ok(None),
+            }
+        }
+
+        fn save(&self, device: &Device) -> Result<(), StoreError> {
+            *self.device.lock().unwrap() = Some(StoredDevice::from_device(device));
+            Ok(())
+        }
+
+        fn delete(&self) -> Result<(), StoreError> {
+            *self.device.lock().unwrap() = None;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::Device;
+    use crate::proto::whatsapp::AdvSignedDeviceIdentity;
+    use crate::types::jid::JID;
+
+    use super::{DeviceStore, FileDeviceStore};
+
+    #[test]
+    fn file_store_round_trips_device() {
+        let path = std::env::temp_dir().join(format!("whatsrusty-device-{}.json", std::process::id()));
+        let store = FileDeviceStore::new(&path);
+        assert!(store.load().unwrap().is_none());
+
+        let mut device = Device::new();
+        device.jid = Some(JID::new_ad("6281234567890".into(), 0, 12));
+        device.platform = "smba".into();
+        device.push_name = "Rusty".into();
+        device.business_name = "Rusty Inc".into();
+        device.account = Some(AdvSignedDeviceIdentity {
+            details: Some(vec![1, 2, 3]),
+            account_signature_key: Some(vec![4; 32]),
+            ..Default::default()
+        });
+        store.save(&device).unwrap();
+
+        let loaded = store.load().unwrap().unwrap();
+        assert_eq!(loaded.noise_key.public, device.noise_key.public);
+        assert_eq!(loaded.identity_key.public, device.identity_key.public);
+        assert_eq!(loaded.signed_pre_key.key.public, device.signed_pre_key.key.public);
+        assert_eq!(loaded.signed_pre_key.signature, device.signed_pre_key.signature);
+        assert_eq!(loaded.registration_id, device.registration_id);
+        assert_eq!(loaded.adv_secret_key, device.adv_secret_key);
+        assert_eq!(loaded.jid.map(|jid| jid.to_string()), Some("6281234567890:12@s.whatsapp.net".into()));
+        assert_eq!(loaded.platform, "smba");
+        assert_eq!(loaded.push_name, "Rusty");
+        assert_eq!(loaded.business_name, "Rusty Inc");
+        assert_eq!(loaded.account, device.account);
+
+        store.delete().unwrap();
+        assert!(store.load().unwrap().is_none());
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::store::{self, StoreError};
+
+/// The identity keys we trust, keyed by the address of the remote device.
+pub trait IdentityStore: Send + Sync {
+    fn load_identity(&self, address: &str) -> Result<Option<[u8; 32]>, StoreError>;
+
+    fn store_identity(&self, address: &str, identity: &[u8; 32]) -> Result<(), StoreError>;
+
+    fn delete_all_identities(&self) -> Result<(), StoreError>;
+}
+
+/// Keeps every identity in a single json file, hex encoded.
+pub struct FileIdentityStore {
+    pub path: PathBuf,
+}
+
+impl FileIdentityStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    fn load(&self) -> Result<HashMap<String, String>, StoreError> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(e.into()),
+        };
+        serde_json::from_slice(&data).map_err(|e| StoreError::Corrupt(e.to_string()))
+    }
+}
+
+impl IdentityStore for FileIdentityStore {
+    fn load_identity(&self, address: &str) -> Result<Option<[u8; 32]>, StoreError> {
+        let Some(identity) = self.load()?.remove(address) else { return Ok(None) };
+        let identity = hex::decode(identity).map_err(|e| StoreError::Corrupt(e.to_string()))?;
+        identity.try_into().map(Some).map_err(|_| StoreError::Corrupt("expected 32 bytes".into()))
+    }
+
+    fn store_identity(&self, address: &str, identity: &[u8; 32]) -> Result<(), StoreError> {
+        let mut identities = self.load()?;
+        identities.insert(address.to_string(), hex::encode(identity));
+        let data = serde_json::to_vec_pretty(&identities).map_err(|e| StoreError::Corrupt(e.to_string()))?;
+
+        store::write_private(&self.path, &data)
+    }
+
+    fn delete_all_identities(&self) -> Result<(), StoreError> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod memory {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use crate::store::StoreError;
+
+    use super::IdentityStore;
+
+    #[derive(Clone, Default)]
+    pub struct MemoryIdentityStore {
+        identities: Arc<Mutex<HashMap<String, [u8; 32]>>>,
+    }
+
+    impl IdentityStore for MemoryIdentityStore {
+        fn load_identity(&self, address: &str) -> Result<Option<[u8; 32]>, StoreError> {
+            Ok(self.identities.lock().unwrap().get(address).copied())
+        }
+
+        fn store_identity(&self, address: &str, identity: &[u8; 32]) -> Result<(), StoreError> {
+            self.identities.lock().unwrap().insert(address.to_string(), *identity);
+            Ok(())
+        }
+
+        fn delete_all_identities(&self) -> Result<(), StoreError> {
+            self.identities.lock().unwrap().clear();
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileIdentityStore, IdentityStore};
+
+    #[test]
+    fn file_store_round_trips_identities() {
+        let path = std::env::temp_dir().join(format!("whatsrusty-identities-{}.json", std::process::id()));
+        let store = FileIdentityStore::new(&path);
+        assert!(store.load_identity("6281234567890:0").unwrap().is_none());
+
+        store.store_identity("6281234567890:0", &[1; 32]).unwrap();
+        store.store_identity("6281234567890:3", &[2; 32]).unwrap();
+        store.store_identity("6281234567890:0", &[3; 32]).unwrap();
+        assert_eq!(store.load_identity("6281234567890:0").unwrap(), Some([3; 32]));
+        assert_eq!(store.load_identity("6281234567890:3").unwrap(), Some([2; 32]));
+
+        store.delete_all_identities().unwrap();
+        assert!(store.load_identity("6281234567890:3").unwrap().is_none());
+    }
+}
@@ -0,0 +1,90 @@
+use std::fmt;
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::Path;
+
+pub mod device;
+pub mod identity;
+pub mod pre_key;
+pub mod sender_key;
+pub mod session;
+
+#[derive(Debug)]
+pub enum StoreError {
+    Io(io::Error),
+    Corrupt(String),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Io(e) => write!(f, "store I/O error: {}", e),
+            StoreError::Corrupt(reason) => write!(f, "store data is corrupt: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<io::Error> for StoreError {
+    fn from(e: io::Error) -> Self {
+        StoreError::Io(e)
+    }
+}
+
+/// Replaces `path` with `data` through a temporary file. Stores hold private
+/// keys, so only the owner may read what gets written.
+pub(crate) fn write_private(path: &Path, data: &[u8]) -> Result<(), StoreError> {
+    let temp = path.with_extension("tmp");
+    // A leftover file would keep its old permissions
+    match fs::remove_file(&temp) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(&temp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&temp, path)?;
+    Ok(())
+}
+
+/// Creates `path` and its missing parents, only accessible by the owner.
+pub(crate) fn create_private_dir(path: &Path) -> Result<(), StoreError> {
+    let mut builder = DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    builder.mode(0o700);
+    builder.create(path)?;
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::{create_private_dir, write_private};
+
+    #[test]
+    fn keeps_written_files_private() {
+        let dir = std::env::temp_dir().join(format!("whatsrusty-private-{}", std::process::id()));
+        create_private_dir(&dir.join("nested")).unwrap();
+        let path = dir.join("nested").join("key.json");
+        fs::write(path.with_extension("tmp"), b"stale").unwrap();
+        fs::set_permissions(path.with_extension("tmp"), fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_private(&path, b"secret").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"secret");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::metadata(&dir).unwrap().permissions().mode() & 0o777, 0o700);
+        assert_eq!(fs::metadata(dir.join("nested")).unwrap().permissions().mode() & 0o777, 0o700);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,211 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::proto::whatsapp::PreKeyRecordStructure;
+use crate::store::{self, StoreError};
+use crate::utils::key::Key;
+
+/// One-time pre-keys, handed out by the server to whoever starts a session with us.
+pub trait PreKeyStore: Send + Sync {
+    /// Returns `count` pre-keys that weren't uploaded yet, generating new ones when there are too few.
+    fn get_or_gen_pre_keys(&self, count: u32) -> Result<Vec<PreKeyRecordStructure>, StoreError>;
+
+    fn load_pre_key(&self, id: u32) -> Result<Option<PreKeyRecordStructure>, StoreError>;
+
+    /// Pre-keys are single use, so they're removed once a session was built with them.
+    fn remove_pre_key(&self, id: u32) -> Result<(), StoreError>;
+
+    fn mark_pre_keys_uploaded(&self, up_to_id: u32) -> Result<(), StoreError>;
+
+    fn uploaded_pre_key_count(&self) -> Result<u32, StoreError>;
+
+    fn delete_all_pre_keys(&self) -> Result<(), StoreError>;
+}
+
+/// Keeps every pre-key in a single json file.
+pub struct FilePreKeyStore {
+    pub path: PathBuf,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct StoredPreKeys {
+    last_id: u32,
+    uploaded_up_to: u32,
+    keys: Vec<StoredPreKey>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct StoredPreKey {
+    id: u32,
+    private_key: String,
+}
+
+impl FilePreKeyStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    fn load(&self) -> Result<StoredPreKeys, StoreError> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(StoredPreKeys::default()),
+            Err(e) => return Err(e.into()),
+        };
+        serde_json::from_slice(&data).map_err(|e| StoreError::Corrupt(e.to_string()))
+    }
+
+    fn update<T>(&self, f: impl FnOnce(&mut StoredPreKeys) -> T) -> Result<T, StoreError> {
+        let mut stored = self.load()?;
+        let result = f(&mut stored);
+        let data = serde_json::to_vec_pretty(&stored).map_err(|e| StoreError::Corrupt(e.to_string()))?;
+
+        store::write_private(&self.path, &data)?;
+        Ok(result)
+    }
+}
+
+impl PreKeyStore for FilePreKeyStore {
+    fn get_or_gen_pre_keys(&self, count: u32) -> Result<Vec<PreKeyRecordStructure>, StoreError> {
+        self.update(|stored| stored.get_or_gen(count))?.into_iter().map(StoredPreKey::into_record).collect()
+    }
+
+    fn load_pre_key(&self, id: u32) -> Result<Option<PreKeyRecordStructure>, StoreError> {
+        self.load()?.get(id).map(StoredPreKey::into_record).transpose()
+    }
+
+    fn remove_pre_key(&self, id: u32) -> Result<(), StoreError> {
+        self.update(|stored| stored.keys.retain(|key| key.id != id))
+    }
+
+    fn mark_pre_keys_uploaded(&self, up_to_id: u32) -> Result<(), StoreError> {
+        self.update(|stored| stored.uploaded_up_to = stored.uploaded_up_to.max(up_to_id))
+    }
+
+    fn uploaded_pre_key_count(&self) -> Result<u32, StoreError> {
+        Ok(self.load()?.uploaded_count())
+    }
+
+    fn delete_all_pre_keys(&self) -> Result<(), StoreError> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl StoredPreKeys {
+    fn get_or_gen(&mut self, count: u32) -> Vec<StoredPreKey> {
+        let pending = self.keys.iter().filter(|key| key.id > self.uploaded_up_to).count() as u32;
+        for _ in pending..count {
+            self.last_id += 1;
+            self.keys.push(StoredPreKey {
+                id: self.last_id,
+                private_key: hex::encode(Key::new().private.to_bytes()),
+            });
+        }
+        self.keys.iter().filter(|key| key.id > self.uploaded_up_to).take(count as usize).cloned().collect()
+    }
+
+    fn get(&self, id: u32) -> Option<StoredPreKey> {
+        self.keys.iter().find(|key| key.id == id).cloned()
+    }
+
+    fn uploaded_count(&self) -> u32 {
+        self.keys.iter().filter(|key| key.id <= self.uploaded_up_to).count() as u32
+    }
+}
+
+impl StoredPreKey {
+    fn into_record(self) -> Result<PreKeyRecordStructure, StoreError> {
+        let private: [u8; 32] = hex::decode(&self.private_key).map_err(|e| StoreError::Corrupt(e.to_string()))?
+            .try_into().map_err(|_| StoreError::Corrupt("expected 32 bytes".into()))?;
+        let key = Key::from_private(private);
+        Ok(PreKeyRecordStructure {
+            id: Some(self.id),
+            public_key: Some(key.public.to_bytes().to_vec()),
+            private_key: Some(private.to_vec()),
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod memory {
+    use std::sync::{Arc, Mutex};
+
+    use crate::proto::whatsapp::PreKeyRecordStructure;
+    use crate::store::StoreError;
+
+    use super::{PreKeyStore, StoredPreKey, StoredPreKeys};
+
+    #[derive(Clone, Default)]
+    pub struct MemoryPreKeyStore {
+        pre_keys: Arc<Mutex<StoredPreKeys>>,
+    }
+
+    impl PreKeyStore for MemoryPreKeyStore {
+        fn get_or_gen_pre_keys(&self, count: u32) -> Result<Vec<PreKeyRecordStructure>, StoreError> {
+            self.pre_keys.lock().unwrap().get_or_gen(count).into_iter().map(StoredPreKey::into_record).collect()
+        }
+
+        fn load_pre_key(&self, id: u32) -> Result<Option<PreKeyRecordStructure>, StoreError> {
+            self.pre_keys.lock().unwrap().get(id).map(StoredPreKey::into_record).transpose()
+        }
+
+        fn remove_pre_key(&self, id: u32) -> Result<(), StoreError> {
+            self.pre_keys.lock().unwrap().keys.retain(|key| key.id != id);
+            Ok(())
+        }
+
+        fn mark_pre_keys_uploaded(&self, up_to_id: u32) -> Result<(), StoreError> {
+            let mut pre_keys = self.pre_keys.lock().unwrap();
+            pre_keys.uploaded_up_to = pre_keys.uploaded_up_to.max(up_to_id);
+            Ok(())
+        }
+
+        fn uploaded_pre_key_count(&self) -> Result<u32, StoreError> {
+            Ok(self.pre_keys.lock().unwrap().uploaded_count())
+        }
+
+        fn delete_all_pre_keys(&self) -> Result<(), StoreError> {
+            *self.pre_keys.lock().unwrap() = StoredPreKeys::default();
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::key::Key;
+
+    use super::{FilePreKeyStore, PreKeyStore};
+
+    #[test]
+    fn file_store_tracks_uploaded_pre_keys() {
+        let path = std::env::temp_dir().join(format!("whatsrusty-pre-keys-{}.json", std::process::id()));
+        let store = FilePreKeyStore::new(&path);
+        assert_eq!(store.uploaded_pre_key_count().unwrap(), 0);
+
+        let batch = store.get_or_gen_pre_keys(3).unwrap();
+        assert_eq!(batch.iter().map(|key| key.id()).collect::<Vec<_>>(), vec![1, 2, 3]);
+        let key = Key::from_private(batch[1].private_key().try_into().unwrap());
+        assert_eq!(batch[1].public_key(), key.public.as_bytes());
+        // Until they're uploaded the same keys are handed out again
+        assert_eq!(store.get_or_gen_pre_keys(2).unwrap(), batch[..2]);
+
+        store.mark_pre_keys_uploaded(3).unwrap();
+        assert_eq!(store.uploaded_pre_key_count().unwrap(), 3);
+        let next = store.get_or_gen_pre_keys(2).unwrap();
+        assert_eq!(next.iter().map(|key| key.id()).collect::<Vec<_>>(), vec![4, 5]);
+
+        assert_eq!(store.load_pre_key(2).unwrap(), Some(batch[1].clone()));
+        store.remove_pre_key(2).unwrap();
+        assert!(store.load_pre_key(2).unwrap().is_none());
+        assert_eq!(store.uploaded_pre_key_count().unwrap(), 2);
+
+        store.delete_all_pre_keys().unwrap();
+        assert!(store.load_pre_key(1).unwrap().is_none());
+    }
+}
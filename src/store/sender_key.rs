@@ -0,0 +1,186 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use prost::Message;
+
+use crate::proto::whatsapp::SenderKeyRecordStructure;
+use crate::store::{self, StoreError};
+
+/// Group sender keys, keyed by the group and the address of the sending device.
+pub trait SenderKeyStore: Send + Sync {
+    fn load_sender_key(&self, group: &str, sender: &str) -> Result<Option<SenderKeyRecordStructure>, StoreError>;
+
+    fn store_sender_key(&self, group: &str, sender: &str, record: &SenderKeyRecordStructure) -> Result<(), StoreError>;
+
+    fn delete_sender_key(&self, group: &str, sender: &str) -> Result<(), StoreError>;
+
+    /// The devices our own sender key for `group` was handed to.
+    fn load_distributed_devices(&self, group: &str) -> Result<Vec<String>, StoreError>;
+
+    fn store_distributed_devices(&self, group: &str, devices: &[String]) -> Result<(), StoreError>;
+
+    fn delete_all_sender_keys(&self) -> Result<(), StoreError>;
+}
+
+/// Keeps one protobuf encoded record per group and sender in a directory,
+/// next to a json list of the devices that got our own key.
+pub struct FileSenderKeyStore {
+    pub path: PathBuf,
+}
+
+impl FileSenderKeyStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    // Groups and senders come from the network, hex keeps them plain file names
+    fn record_path(&self, group: &str, sender: &str) -> PathBuf {
+        self.path.join(format!("{}_{}.senderkey", hex::encode(group), hex::encode(sender)))
+    }
+
+    fn devices_path(&self, group: &str) -> PathBuf {
+        self.path.join(format!("{}.devices", hex::encode(group)))
+    }
+
+    fn write(&self, path: PathBuf, data: Vec<u8>) -> Result<(), StoreError> {
+        store::create_private_dir(&self.path)?;
+        store::write_private(&path, &data)
+    }
+}
+
+impl SenderKeyStore for FileSenderKeyStore {
+    fn load_sender_key(&self, group: &str, sender: &str) -> Result<Option<SenderKeyRecordStructure>, StoreError> {
+        let data = match fs::read(self.record_path(group, sender)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        SenderKeyRecordStructure::decode(&data[..]).map(Some).map_err(|e| StoreError::Corrupt(e.to_string()))
+    }
+
+    fn store_sender_key(&self, group: &str, sender: &str, record: &SenderKeyRecordStructure) -> Result<(), StoreError> {
+        self.write(self.record_path(group, sender), record.encode_to_vec())
+    }
+
+    fn delete_sender_key(&self, group: &str, sender: &str) -> Result<(), StoreError> {
+        match fs::remove_file(self.record_path(group, sender)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn load_distributed_devices(&self, group: &str) -> Result<Vec<String>, StoreError> {
+        let data = match fs::read(self.devices_path(group)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        serde_json::from_slice(&data).map_err(|e| StoreError::Corrupt(e.to_string()))
+    }
+
+    fn store_distributed_devices(&self, group: &str, devices: &[String]) -> Result<(), StoreError> {
+        let data = serde_json::to_vec(devices).map_err(|e| StoreError::Corrupt(e.to_string()))?;
+        self.write(self.devices_path(group), data)
+    }
+
+    fn delete_all_sender_keys(&self) -> Result<(), StoreError> {
+        match fs::remove_dir_all(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod memory {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use crate::proto::whatsapp::SenderKeyRecordStructure;
+    use crate::store::StoreError;
+
+    use super::SenderKeyStore;
+
+    #[derive(Clone, Default)]
+    pub struct MemorySenderKeyStore {
+        sender_keys: Arc<Mutex<HashMap<(String, String), SenderKeyRecordStructure>>>,
+        distributed: Arc<Mutex<HashMap<String, Vec<String>>>>,
+    }
+
+    impl SenderKeyStore for MemorySenderKeyStore {
+        fn load_sender_key(&self, group: &str, sender: &str) -> Result<Option<SenderKeyRecordStructure>, StoreError> {
+            Ok(self.sender_keys.lock().unwrap().get(&(group.to_string(), sender.to_string())).cloned())
+        }
+
+        fn store_sender_key(&self, group: &str, sender: &str, record: &SenderKeyRecordStructure) -> Result<(), StoreError> {
+            self.sender_keys.lock().unwrap().insert((group.to_string(), sender.to_string()), record.clone());
+            Ok(())
+        }
+
+        fn delete_sender_key(&self, group: &str, sender: &str) -> Result<(), StoreError> {
+            self.sender_keys.lock().unwrap().remove(&(group.to_string(), sender.to_string()));
+            Ok(())
+        }
+
+        fn load_distributed_devices(&self, group: &str) -> Result<Vec<String>, StoreError> {
+            Ok(self.distributed.lock().unwrap().get(group).cloned().unwrap_or_default())
+        }
+
+        fn store_distributed_devices(&self, group: &str, devices: &[String]) -> Result<(), StoreError> {
+            self.distributed.lock().unwrap().insert(group.to_string(), devices.to_vec());
+            Ok(())
+        }
+
+        fn delete_all_sender_keys(&self) -> Result<(), StoreError> {
+            self.sender_keys.lock().unwrap().clear();
+            self.distributed.lock().unwrap().clear();
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proto::whatsapp::{SenderKeyRecordStructure, SenderKeyStateStructure};
+
+    use super::{FileSenderKeyStore, SenderKeyStore};
+
+    #[test]
+    fn file_store_round_trips_sender_keys() {
+        let path = std::env::temp_dir().join(format!("whatsrusty-sender-keys-{}", std::process::id()));
+        let store = FileSenderKeyStore::new(&path);
+        let group = "120363000000000000@g.us";
+        assert!(store.load_sender_key(group, "6281234567890:3").unwrap().is_none());
+        assert!(store.load_distributed_devices(group).unwrap().is_empty());
+
+        let record = SenderKeyRecordStructure {
+            sender_key_states: vec![SenderKeyStateStructure { sender_key_id: Some(42), ..Default::default() }],
+        };
+        store.store_sender_key(group, "6281234567890:3", &record).unwrap();
+        store.store_distributed_devices(group, &["6281234567890:3".into(), "6289876543210:0".into()]).unwrap();
+        assert_eq!(store.load_sender_key(group, "6281234567890:3").unwrap(), Some(record));
+        assert!(store.load_sender_key(group, "6281234567890:4").unwrap().is_none());
+        assert_eq!(store.load_distributed_devices(group).unwrap(), vec!["6281234567890:3", "6289876543210:0"]);
+
+        store.delete_sender_key(group, "6281234567890:3").unwrap();
+        assert!(store.load_sender_key(group, "6281234567890:3").unwrap().is_none());
+
+        store.delete_all_sender_keys().unwrap();
+        assert!(store.load_distributed_devices(group).unwrap().is_empty());
+    }
+
+    #[test]
+    fn file_store_keeps_hostile_names_inside_its_directory() {
+        let root = std::env::temp_dir().join(format!("whatsrusty-sender-key-names-{}", std::process::id()));
+        let store = FileSenderKeyStore::new(root.join("sender_keys"));
+        store.store_sender_key("../../escaped@g.us", "/tmp/x:1", &SenderKeyRecordStructure::default()).unwrap();
+        store.store_distributed_devices("../escaped", &[]).unwrap();
+
+        let entries: Vec<_> = std::fs::read_dir(&root).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(entries, ["sender_keys"]);
+        assert_eq!(std::fs::read_dir(root.join("sender_keys")).unwrap().count(), 2);
+        assert!(store.load_sender_key("../../escaped@g.us", "/tmp/x:1").unwrap().is_some());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
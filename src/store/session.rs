@@ -0,0 +1,135 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use prost::Message;
+
+use crate::proto::whatsapp::RecordStructure;
+use crate::store::{self, StoreError};
+
+/// Signal session records, keyed by the address of the remote device.
+pub trait SessionStore: Send + Sync {
+    fn load_session(&self, address: &str) -> Result<Option<RecordStructure>, StoreError>;
+
+    fn store_session(&self, address: &str, record: &RecordStructure) -> Result<(), StoreError>;
+
+    fn delete_session(&self, address: &str) -> Result<(), StoreError>;
+
+    fn delete_all_sessions(&self) -> Result<(), StoreError>;
+}
+
+/// Keeps one protobuf encoded record per address in a directory.
+pub struct FileSessionStore {
+    pub path: PathBuf,
+}
+
+impl FileSessionStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    fn record_path(&self, address: &str) -> PathBuf {
+        // Addresses come from the network, hex keeps them a plain file name
+        self.path.join(format!("{}.session", hex::encode(address)))
+    }
+}
+
+impl SessionStore for FileSessionStore {
+    fn load_session(&self, address: &str) -> Result<Option<RecordStructure>, StoreError> {
+        let data = match fs::read(self.record_path(address)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        RecordStructure::decode(&data[..]).map(Some).map_err(|e| StoreError::Corrupt(e.to_string()))
+    }
+
+    fn store_session(&self, address: &str, record: &RecordStructure) -> Result<(), StoreError> {
+        store::create_private_dir(&self.path)?;
+        store::write_private(&self.record_path(address), &record.encode_to_vec())
+    }
+
+    fn delete_session(&self, address: &str) -> Result<(), StoreError> {
+        match fs::remove_file(self.record_path(address)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn delete_all_sessions(&self) -> Result<(), StoreError> {
+        match fs::remove_dir_all(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod memory {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use crate::proto::whatsapp::RecordStructure;
+    use crate::store::StoreError;
+
+    use super::SessionStore;
+
+    #[derive(Clone, Default)]
+    pub struct MemorySessionStore {
+        sessions: Arc<Mutex<HashMap<String, RecordStructure>>>,
+    }
+
+    impl SessionStore for MemorySessionStore {
+        fn load_session(&self, address: &str) -> Result<Option<RecordStructure>, StoreError> {
+            Ok(self.sessions.lock().unwrap().get(address).cloned())
+        }
+
+        fn store_session(&self, address: &str, record: &RecordStructure) -> Result<(), StoreError> {
+            self.sessions.lock().unwrap().insert(address.to_string(), record.clone());
+            Ok(())
+        }
+
+        fn delete_session(&self, address: &str) -> Result<(), StoreError> {
+            self.sessions.lock().unwrap().remove(address);
+            Ok(())
+        }
+
+        fn delete_all_sessions(&self) -> Result<(), StoreError> {
+            self.sessions.lock().unwrap().clear();
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proto::whatsapp::{RecordStructure, SessionStructure};
+
+    use super::{FileSessionStore, SessionStore};
+
+    #[test]
+    fn file_store_round_trips_sessions() {
+        let path = std::env::temp_dir().join(format!("whatsrusty-sessions-{}", std::process::id()));
+        let store = FileSessionStore::new(&path);
+        assert!(store.load_session("6281234567890:12").unwrap().is_none());
+
+        let record = RecordStructure {
+            current_session: Some(SessionStructure {
+                session_version: Some(3),
+                remote_registration_id: Some(42),
+                ..Default::default()
+            }),
+            previous_sessions: vec![SessionStructure::default()],
+        };
+        store.store_session("6281234567890:12", &record).unwrap();
+        store.store_session("6281234567890:0", &RecordStructure::default()).unwrap();
+        assert_eq!(store.load_session("6281234567890:12").unwrap(), Some(record));
+
+        store.delete_session("6281234567890:12").unwrap();
+        assert!(store.load_session("6281234567890:12").unwrap().is_none());
+        assert!(store.load_session("6281234567890:0").unwrap().is_some());
+
+        store.delete_all_sessions().unwrap();
+        assert!(store.load_session("6281234567890:0").unwrap().is_none());
+    }
+}
@@ -0,0 +1,371 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use ed25519_dalek::{Signer, SigningKey};
+use hmac::{Hmac, Mac};
+use prost::Message;
+use sha2::Sha256;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+use crate::client::Config;
+use crate::constant;
+use crate::proto::whatsapp::cert_chain::noise_certificate::Details;
+use crate::proto::whatsapp::cert_chain::NoiseCertificate;
+use crate::proto::whatsapp::handshake_message::ServerHello;
+use crate::proto::whatsapp::{AdvDeviceIdentity, AdvSignedDeviceIdentity, AdvSignedDeviceIdentityHmac, CertChain, ClientPayload, HandshakeMessage};
+use crate::socket::frame_socket::{FrameSocket, FrameSocketState};
+use crate::socket::noise_socket::NoiseSocket;
+use crate::socket::transport::{TransportHalves, TransportReceiver, TransportSender};
+use crate::socket::websocket::{split_stream, WebSocketTransport};
+use crate::store::device::memory::MemoryDeviceStore;
+use crate::store::identity::memory::MemoryIdentityStore;
+use crate::store::pre_key::memory::MemoryPreKeyStore;
+use crate::store::sender_key::memory::MemorySenderKeyStore;
+use crate::store::session::memory::MemorySessionStore;
+use crate::types::jid::{self, JID};
+use crate::utils::decoder::{BinaryDecoder, Node, Value};
+use crate::utils::encoder::BinaryEncoder;
+use crate::utils::gcm;
+use crate::utils::key::{Key, PreKey};
+use crate::utils::noise_handshake::NoiseHandShake;
+
+pub struct MockServer {
+    pub addr: SocketAddr,
+    pub static_key: Key,
+    pub root_key: SigningKey,
+    connections: mpsc::UnboundedReceiver<TransportHalves>,
+}
+
+pub struct MockConnection {
+    write: Box<dyn TransportSender>,
+    read: Box<dyn TransportReceiver>,
+    fs: FrameSocket,
+    ns: NoiseSocket,
+    pending: Vec<Vec<u8>>,
+    pub client_payload: ClientPayload,
+    pub client_static: [u8; 32],
+}
+
+impl MockServer {
+    pub async fn start() -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, connections) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let Ok(ws_stream) = tokio_tungstenite::accept_async(stream).await else { continue };
+                if sender.send(split_stream(ws_stream)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        MockServer { addr, static_key: Key::new(), root_key: SigningKey::from_bytes(&[1; 32]), connections }
+    }
+
+    pub fn config(&self) -> Config {
+        self.config_with_store(MemoryDeviceStore::default())
+    }
+
+    pub fn config_with_store(&self, store: MemoryDeviceStore) -> Config {
+        Config {
+            transport: Box::new(WebSocketTransport {
+                url: format!("ws://{}", self.addr),
+                origin: constant::ORIGIN.to_string(),
+            }),
+            store: Box::new(store),
+            session_store: Box::new(MemorySessionStore::default()),
+            pre_key_store: Box::new(MemoryPreKeyStore::default()),
+            sender_key_store: Box::new(MemorySenderKeyStore::default()),
+            identity_store: Box::new(MemoryIdentityStore::default()),
+            cert_root_key: account_public(&self.root_key),
+            ..Default::default()
+        }
+    }
+
+    pub async fn accept(&mut self) -> MockConnection {
+        let certificate = cert_chain(&self.root_key, &SigningKey::from_bytes(&[2; 32]), self.static_key.public.as_bytes());
+        self.accept_with_certificate(certificate).await.expect("Client disconnected")
+    }
+
+    /// Hands `certificate` to the client as is, resolving to `None` when
+    /// the client hangs up instead of finishing the handshake.
+    pub async fn accept_with_certificate(&mut self, certificate: Vec<u8>) -> Option<MockConnection> {
+        let (write, read) = self.connections.recv().await.expect("Mock server stopped");
+        MockConnection::handshake(write, read, &self.static_key, certificate).await
+    }
+}
+
+impl MockConnection {
+    pub async fn handshake(
+        write: Box<dyn TransportSender>,
+        mut read: Box<dyn TransportReceiver>,
+        static_key: &Key,
+        certificate: Vec<u8>,
+    ) -> Option<MockConnection> {
+        let mut first = read.receive().await.expect("Client disconnected").unwrap();
+        assert_eq!(first[..constant::CONN_HEADER.len()], constant::CONN_HEADER, "Missing connection header");
+        first.drain(..constant::CONN_HEADER.len());
+
+        let mut fs = FrameSocket::new();
+        fs.state = FrameSocketState::Authenticated;
+        let mut pending = fs.process_data(first);
+        let mut conn = MockConnection {
+            write,
+            read,
+            fs,
+            ns: NoiseSocket::new(gcm::prepare(vec![0; 32]), gcm::prepare(vec![0; 32])),
+            pending: Vec::new(),
+            client_payload: ClientPayload::default(),
+            client_static: [0; 32],
+        };
+        let hello = match pending.is_empty() {
+            true => conn.receive_frame().await.expect("Client disconnected"),
+            false => pending.remove(0),
+        };
+        conn.pending = pending;
+
+        let client_ephemeral = HandshakeMessage::decode(&hello[..]).unwrap()
+            .client_hello.unwrap()
+            .ephemeral.unwrap();
+        let client_ephemeral: [u8; 32] = client_ephemeral.try_into().unwrap();
+        let ephemeral = conn.fs.key.public.to_bytes();
+
+        let mut nhs = NoiseHandShake::default();
+        nhs.start(constant::CONN_HEADER.to_vec());
+        nhs.authenticate(&client_ephemeral.to_vec());
+        nhs.authenticate(&ephemeral.to_vec());
+        nhs.mix_shared_secret(conn.fs.key.private.to_bytes(), client_ephemeral);
+        let encrypted_static = nhs.encrypt(static_key.public.as_bytes());
+        nhs.mix_shared_secret(static_key.private.to_bytes(), client_ephemeral);
+        let encrypted_certificate = nhs.encrypt(&certificate);
+
+        let server_hello = HandshakeMessage {
+            server_hello: Some(ServerHello {
+                ephemeral: Some(ephemeral.to_vec()),
+                r#static: Some(encrypted_static),
+                payload: Some(encrypted_certificate),
+            }),
+            ..Default::default()
+        };
+        conn.send_frame(server_hello.encode_to_vec()).await;
+
+        let finish = conn.receive_frame().await?;
+        let client_finish = HandshakeMessage::decode(&finish[..]).unwrap().client_finish.unwrap();
+        let client_static: [u8; 32] = nhs.decrypt(&client_finish.r#static.unwrap()).unwrap().try_into().unwrap();
+        nhs.mix_shared_secret(conn.fs.key.private.to_bytes(), client_static);
+        let payload = nhs.decrypt(&client_finish.payload.unwrap()).unwrap();
+
+        let (client_write, client_read) = nhs.extract_and_expand(None);
+        conn.ns = NoiseSocket::new(gcm::prepare(client_read), gcm::prepare(client_write));
+        conn.client_payload = ClientPayload::decode(&payload[..]).unwrap();
+        conn.client_static = client_static;
+        Some(conn)
+    }
+
+    async fn send_frame(&mut self, data: Vec<u8>) {
+        let frame = self.fs.make_frame(data).unwrap();
+        self.write.send(frame).await.unwrap();
+    }
+
+    async fn receive_frame(&mut self) -> Option<Vec<u8>> {
+        while self.pending.is_empty() {
+            let Some(Ok(data)) = self.read.receive().await else {
+                // Completes the close handshake the client started
+                let _ = self.write.close().await;
+                return None;
+            };
+            self.pending = self.fs.process_data(data);
+        }
+        Some(self.pending.remove(0))
+    }
+
+    pub async fn close(&mut self) {
+        let _ = self.write.close().await;
+    }
+
+    pub async fn send_node(&mut self, node: &Node) {
+        let data = BinaryEncoder::new().write_node(node).unwrap();
+        let frame = self.ns.make_frame(data);
+        self.send_frame(frame).await;
+    }
+
+    pub async fn receive_node(&mut self) -> Option<Node> {
+        let frame = self.receive_frame().await?;
+        let data = self.ns.receive_encrypted_frame(&frame).expect("Failed to decrypt frame");
+        Some(BinaryDecoder::new(data).unwrap().decode().unwrap())
+    }
+
+    pub async fn expect_node(&mut self, tag: &str) -> Node {
+        loop {
+            let node = self.receive_node().await.expect("Client disconnected");
+            if node.tag == tag {
+                return node;
+            }
+        }
+    }
+
+    pub async fn send_iq_result(&mut self, request: &Node, content: Option<Value>) {
+        let id = request.get_attr("id").unwrap_or("");
+        self.send_node(&node("iq", &[
+            ("id", Value::Str(id.to_string())),
+            ("from", server_jid()),
+            ("type", Value::Str("result".into())),
+        ], content)).await;
+    }
+
+    pub async fn send_iq_error(&mut self, request: &Node, code: u16, text: &str) {
+        let id = request.get_attr("id").unwrap_or("");
+        self.send_node(&node("iq", &[
+            ("id", Value::Str(id.to_string())),
+            ("from", server_jid()),
+            ("type", Value::Str("error".into())),
+        ], Some(Value::List(vec![
+            node("error", &[("code", Value::Str(code.to_string())), ("text", Value::Str(text.into()))], None),
+        ])))).await;
+    }
+
+    pub async fn send_pair_device(&mut self, id: &str, refs: &[&str]) {
+        let refs = refs.iter()
+            .map(|r| node("ref", &[], Some(Value::Bytes(r.as_bytes().to_vec()))))
+            .collect();
+        self.send_node(&node("iq", &[
+            ("id", Value::Str(id.into())),
+            ("from", server_jid()),
+            ("type", Value::Str("set".into())),
+            ("xmlns", Value::Str("md".into())),
+        ], Some(Value::List(vec![node("pair-device", &[], Some(Value::List(refs)))])))).await;
+    }
+
+    pub async fn send_pair_success(&mut self, id: &str, device_identity: Vec<u8>, jid: JID, business_name: &str, platform: &str) {
+        self.send_node(&node("iq", &[
+            ("id", Value::Str(id.into())),
+            ("from", server_jid()),
+            ("type", Value::Str("set".into())),
+            ("xmlns", Value::Str("md".into())),
+        ], Some(Value::List(vec![node("pair-success", &[], Some(Value::List(vec![
+            node("device-identity", &[], Some(Value::Bytes(device_identity))),
+            node("platform", &[("name", Value::Str(platform.into()))], None),
+            node("device", &[("jid", Value::Jid(jid))], None),
+            node("biz", &[("name", Value::Str(business_name.into()))], None),
+        ])))])))).await;
+    }
+
+    pub async fn send_success(&mut self) {
+        self.send_node(&node("success", &[
+            ("t", Value::Str("1700000000".into())),
+            ("props", Value::Str("1".into())),
+            ("abprops", Value::Str("1".into())),
+            ("location", Value::Str("sin".into())),
+        ], None)).await;
+    }
+
+    pub async fn send_failure(&mut self, reason: u16) {
+        self.send_node(&node("failure", &[
+            ("reason", Value::Str(reason.to_string())),
+            ("location", Value::Str("sin".into())),
+        ], None)).await;
+    }
+
+    pub async fn send_stream_error(&mut self, code: &str, conflict: Option<&str>) {
+        let content = conflict.map(|conflict| Value::List(vec![node("conflict", &[("type", Value::Str(conflict.into()))], None)]));
+        self.send_node(&node("stream:error", &[("code", Value::Str(code.into()))], content)).await;
+    }
+}
+
+/// Signs like the phone does: the Montgomery form of `key` is what gets
+/// published, so the Edwards sign bit travels in the signature.
+pub fn account_sign(key: &SigningKey, message: &[u8]) -> [u8; 64] {
+    let mut signature = key.sign(message).to_bytes();
+    signature[63] |= key.verifying_key().to_bytes()[31] & 0x80;
+    signature
+}
+
+pub fn account_public(key: &SigningKey) -> [u8; 32] {
+    key.verifying_key().to_montgomery().to_bytes()
+}
+
+/// Builds a chain where `root_key` signs the intermediate certificate and
+/// `intermediate_key` signs the leaf holding `static_public`.
+pub fn cert_chain(root_key: &SigningKey, intermediate_key: &SigningKey, static_public: &[u8; 32]) -> Vec<u8> {
+    let intermediate = Details {
+        serial: Some(1),
+        issuer_serial: Some(constant::CERT_ISSUER_SERIAL),
+        key: Some(account_public(intermediate_key).to_vec()),
+        ..Default::default()
+    }.encode_to_vec();
+    let leaf = Details {
+        serial: Some(2),
+        issuer_serial: Some(1),
+        key: Some(static_public.to_vec()),
+        ..Default::default()
+    }.encode_to_vec();
+    CertChain {
+        leaf: Some(NoiseCertificate { signature: Some(account_sign(intermediate_key, &leaf).to_vec()), details: Some(leaf) }),
+        intermediate: Some(NoiseCertificate { signature: Some(account_sign(root_key, &intermediate).to_vec()), details: Some(intermediate) }),
+    }.encode_to_vec()
+}
+
+pub fn signed_device_identity(account_key: &SigningKey, identity_public: &[u8; 32], adv_secret_key: &[u8; 32], key_index: u32) -> Vec<u8> {
+    let details = AdvDeviceIdentity {
+        raw_id: Some(1),
+        timestamp: Some(1700000000),
+        key_index: Some(key_index),
+        ..Default::default()
+    }.encode_to_vec();
+    let message = [&constant::ADV_PREFIX_ACCOUNT_SIGNATURE[..], &details, identity_public].concat();
+    let identity = AdvSignedDeviceIdentity {
+        details: Some(details),
+        account_signature_key: Some(account_public(account_key).to_vec()),
+        account_signature: Some(account_sign(account_key, &message).to_vec()),
+        device_signature: None,
+    }.encode_to_vec();
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(adv_secret_key).unwrap();
+    mac.update(&identity);
+    AdvSignedDeviceIdentityHmac {
+        hmac: Some(mac.finalize().into_bytes().to_vec()),
+        details: Some(identity),
+        account_type: None,
+    }.encode_to_vec()
+}
+
+pub fn server_jid() -> Value {
+    Value::Jid(JID::new(None, None, None, None, Some(jid::DEFAULT_USER_SERVER.into())))
+}
+
+pub fn node(tag: &str, attributes: &[(&str, Value)], content: Option<Value>) -> Node {
+    let attributes = attributes.iter()
+        .map(|(key, value)| (key.to_string(), value.clone()))
+        .collect::<HashMap<_, _>>();
+    Node::new(tag.to_string(), attributes, content)
+}
+
+pub fn pre_key_bundle_node(jid: &JID, registration_id: u32, identity: &Key, signed_pre_key: &PreKey) -> Node {
+    node("user", &[("jid", Value::Jid(jid.clone()))], Some(Value::List(vec![
+        node("registration", &[], Some(Value::Bytes(registration_id.to_be_bytes().to_vec()))),
+        node("type", &[], Some(Value::Bytes(vec![constant::SIGNAL_DJB_TYPE]))),
+        node("identity", &[], Some(Value::Bytes(identity.public.to_bytes().to_vec()))),
+        node("skey", &[], Some(Value::List(vec![
+            node("id", &[], Some(Value::Bytes(signed_pre_key.id.to_be_bytes()[1..].to_vec()))),
+            node("value", &[], Some(Value::Bytes(signed_pre_key.key.public.to_bytes().to_vec()))),
+            node("signature", &[], Some(Value::Bytes(signed_pre_key.signature.to_vec()))),
+        ]))),
+    ])))
+}
+
+pub fn child_bytes(node: &Node, tag: &str) -> Vec<u8> {
+    match node.get_child(tag).and_then(|child| child.content.as_ref()) {
+        Some(Value::Bytes(bytes)) => bytes.clone(),
+        other => panic!("expected bytes in <{}>, got {:?}", tag, other),
+    }
+}
+
+pub fn content_bytes(node: &Node) -> Vec<u8> {
+    match &node.content {
+        Some(Value::Bytes(bytes)) => bytes.clone(),
+        other => panic!("expected bytes in <{}>, got {:?}", node.tag, other),
+    }
+}
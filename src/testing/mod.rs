@@ -0,0 +1,48 @@
+use std::time::Duration;
+
+use tokio::sync::broadcast;
+
+use crate::device::Device;
+use crate::events::Event;
+use crate::request::InfoQuery;
+use crate::signal::cipher::PreKeyBundle;
+use crate::testing::mock_server::node;
+use crate::types::jid::{self, JID};
+use crate::utils::decoder::Value;
+
+pub mod mock_server;
+
+pub async fn next_event(events: &mut broadcast::Receiver<Event>) -> Event {
+    tokio::time::timeout(Duration::from_secs(10), events.recv()).await
+        .expect("No event emitted")
+        .expect("Event stream closed")
+}
+
+pub async fn wait_for(condition: impl Fn() -> bool) {
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while !condition() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }).await.expect("Condition never held");
+}
+
+pub fn device_bundle(device: &Device, pre_key: Option<(u32, [u8; 32])>) -> PreKeyBundle {
+    PreKeyBundle {
+        registration_id: device.registration_id,
+        identity_key: device.identity_key.public.to_bytes(),
+        signed_pre_key_id: device.signed_pre_key.id,
+        signed_pre_key: device.signed_pre_key.key.public.to_bytes(),
+        signed_pre_key_signature: device.signed_pre_key.signature,
+        pre_key,
+    }
+}
+
+pub fn count_query() -> InfoQuery {
+    InfoQuery {
+        namespace: Some("encrypt".into()),
+        r#type: Some("get".into()),
+        to: Some(JID::new(None, None, None, None, Some(jid::DEFAULT_USER_SERVER.into()))),
+        content: Some(Value::List(vec![node("count", &[], None)])),
+        ..Default::default()
+    }
+}
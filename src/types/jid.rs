@@ -1,5 +1,14 @@
+use std::fmt;
+use std::str::FromStr;
 
-#[derive(Debug, Clone)]
+pub const DEFAULT_USER_SERVER: &str = "s.whatsapp.net";
+pub const GROUP_SERVER: &str = "g.us";
+pub const HIDDEN_USER_SERVER: &str = "lid";
+pub const HOSTED_SERVER: &str = "hosted";
+pub const MESSENGER_SERVER: &str = "msgr";
+pub const INTEROP_SERVER: &str = "interop";
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct JID {
     pub user: Option<String>,
     pub raw_agent: Option<u8>,
@@ -12,4 +21,76 @@ impl JID {
     pub fn new(user: Option<String>, raw_agent: Option<u8>, device: Option<u16>, integrator: Option<u16>, server: Option<String>) -> Self {
         Self { user, raw_agent, device, integrator, server }
     }
-}
\ No newline at end of file
+
+    pub fn new_ad(user: String, raw_agent: u8, device: u8) -> Self {
+        let (server, agent) = match raw_agent {
+            0 => (DEFAULT_USER_SERVER, 0),
+            1 => (HIDDEN_USER_SERVER, 0),
+            _ => (HOSTED_SERVER, raw_agent),
+        };
+        Self::new(Some(user), Some(agent), Some(device as u16), None, Some(server.to_string()))
+    }
+
+    /// The address Signal sessions with this device are stored under.
+    pub fn signal_address(&self) -> String {
+        let user = self.user.as_deref().unwrap_or("");
+        let device = self.device.unwrap_or(0);
+        match self.raw_agent.unwrap_or(0) {
+            0 => format!("{}:{}", user, device),
+            agent => format!("{}_{}:{}", user, agent, device),
+        }
+    }
+
+    /// The user this JID belongs to, without the device.
+    pub fn to_non_ad(&self) -> JID {
+        Self::new(self.user.clone(), None, None, self.integrator, self.server.clone())
+    }
+
+    pub fn actual_agent(&self) -> u8 {
+        match self.server.as_deref() {
+            Some(DEFAULT_USER_SERVER) => 0,
+            Some(HIDDEN_USER_SERVER) => 1,
+            _ => self.raw_agent.unwrap_or(0),
+        }
+    }
+}
+
+impl fmt::Display for JID {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let user = self.user.as_deref().unwrap_or("");
+        let server = self.server.as_deref().unwrap_or("");
+        let agent = self.raw_agent.unwrap_or(0);
+        let device = self.device.unwrap_or(0);
+
+        if user.is_empty() {
+            write!(f, "{}", server)
+        } else if agent > 0 {
+            write!(f, "{}.{}:{}@{}", user, agent, device, server)
+        } else if device > 0 {
+            write!(f, "{}:{}@{}", user, device, server)
+        } else {
+            write!(f, "{}@{}", user, server)
+        }
+    }
+}
+
+impl FromStr for JID {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((user, server)) = s.split_once('@') else {
+            return Ok(Self::new(None, None, None, None, Some(s.to_string())));
+        };
+
+        let (user, device) = match user.split_once(':') {
+            Some((user, device)) => (user, Some(device.parse::<u16>().map_err(|e| format!("invalid device in {}: {}", s, e))?)),
+            None => (user, None),
+        };
+        let (user, agent) = match user.split_once('.') {
+            Some((user, agent)) => (user, Some(agent.parse::<u8>().map_err(|e| format!("invalid agent in {}: {}", s, e))?)),
+            None => (user, None),
+        };
+
+        Ok(Self::new(Some(user.to_string()), agent, device, None, Some(server.to_string())))
+    }
+}
@@ -0,0 +1,11 @@
+use aes::Aes256;
+use ::cbc::cipher::block_padding::Pkcs7;
+use ::cbc::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+
+pub fn encrypt(key: &[u8; 32], iv: &[u8; 16], plaintext: &[u8]) -> Vec<u8> {
+    ::cbc::Encryptor::<Aes256>::new(key.into(), iv.into()).encrypt_padded_vec_mut::<Pkcs7>(plaintext)
+}
+
+pub fn decrypt(key: &[u8; 32], iv: &[u8; 16], ciphertext: &[u8]) -> Option<Vec<u8>> {
+    ::cbc::Decryptor::<Aes256>::new(key.into(), iv.into()).decrypt_padded_vec_mut::<Pkcs7>(ciphertext).ok()
+}
@@ -1,13 +1,13 @@
-use std::{collections::HashMap, io::{Cursor, Read}};
+use std::{collections::HashMap, fmt, io::{Cursor, Read}};
 
 use byteorder::{BigEndian, ReadBytesExt};
 use flate2::read::ZlibDecoder;
 
-use crate::{types::jid::JID, utils::token::{DICTIONARY_0, DICTIONARY_3, DOUBLE_BYTE_TOKENS, SINGLE_BYTE_TOKENS}};
+use crate::{constant, types::jid::JID, utils::token::{DICTIONARY_0, DICTIONARY_3, DOUBLE_BYTE_TOKENS, SINGLE_BYTE_TOKENS}};
 
-use super::token::{BINARY_20, BINARY_32, BINARY_8, HEX_8, JID_PAIR, LIST_16, LIST_8, LIST_EMPTY, NIBBLE_8};
+use super::token::{AD_JID, BINARY_20, BINARY_32, BINARY_8, FB_JID, HEX_8, INTEROP_JID, JID_PAIR, LIST_16, LIST_8, LIST_EMPTY, NIBBLE_8};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Null,
     Str(String),
@@ -17,7 +17,7 @@ pub enum Value {
     Node(Box<Node>),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Node {
     pub tag: String,
     pub attributes: HashMap<String, Value>,
@@ -26,95 +26,199 @@ pub struct Node {
 
 pub struct BinaryDecoder {
     reader: Cursor<Vec<u8>>,
+    depth: usize,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum CodecErrorKind {
+    EmptyFrame,
+    UnexpectedEof,
+    EmptyNode,
+    InvalidListToken(u8),
+    InvalidToken(u8),
+    ExpectedString,
+    InvalidNibble(u8),
+    InvalidHex(u8),
+    TooManyBytesToPack(usize),
+    LengthTooLarge(usize),
+    Decompress(String),
+    DecompressedTooLarge(usize),
+    TooDeep(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodecError {
+    pub offset: usize,
+    pub kind: CodecErrorKind,
+}
+
+impl CodecError {
+    pub fn new(offset: usize, kind: CodecErrorKind) -> Self {
+        Self { offset, kind }
+    }
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            CodecErrorKind::EmptyFrame => write!(f, "empty frame"),
+            CodecErrorKind::UnexpectedEof => write!(f, "unexpected end of data at offset {}", self.offset),
+            CodecErrorKind::EmptyNode => write!(f, "cannot decode node with empty body at offset {}", self.offset),
+            CodecErrorKind::InvalidListToken(token) => write!(f, "invalid list token {} at offset {}", token, self.offset),
+            CodecErrorKind::InvalidToken(token) => write!(f, "invalid token {} at offset {}", token, self.offset),
+            CodecErrorKind::ExpectedString => write!(f, "expected string at offset {}", self.offset),
+            CodecErrorKind::InvalidNibble(value) => write!(f, "invalid nibble value {} at offset {}", value, self.offset),
+            CodecErrorKind::InvalidHex(value) => write!(f, "invalid hex value {} at offset {}", value, self.offset),
+            CodecErrorKind::TooManyBytesToPack(length) => write!(f, "too many bytes to pack: {} at offset {}", length, self.offset),
+            CodecErrorKind::LengthTooLarge(length) => write!(f, "length is too large: {} at offset {}", length, self.offset),
+            CodecErrorKind::Decompress(reason) => write!(f, "failed to decompress frame: {}", reason),
+            CodecErrorKind::DecompressedTooLarge(max) => write!(f, "decompressed frame exceeds {} bytes", max),
+            CodecErrorKind::TooDeep(max) => write!(f, "nodes nested deeper than {} at offset {}", max, self.offset),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
 impl Node {
     pub fn new(tag: String, attributes: HashMap<String, Value>, content: Option<Value>) -> Self {
         Node { tag, attributes, content }
     }
+
+    pub fn get_attr(&self, key: &str) -> Option<&str> {
+        match self.attributes.get(key) {
+            Some(Value::Str(s)) => Some(s.as_str()),
+            _ => None,
+        }
+    }
+
+    pub fn get_jid_attr(&self, key: &str) -> Option<&JID> {
+        match self.attributes.get(key) {
+            Some(Value::Jid(jid)) => Some(jid),
+            _ => None,
+        }
+    }
+
+    pub fn children(&self) -> &[Node] {
+        match &self.content {
+            Some(Value::List(nodes)) => nodes,
+            Some(Value::Node(node)) => std::slice::from_ref(node.as_ref()),
+            _ => &[],
+        }
+    }
+
+    pub fn get_child(&self, tag: &str) -> Option<&Node> {
+        self.children().iter().find(|child| child.tag == tag)
+    }
 }
 
 impl BinaryDecoder {
-    pub fn new(buffer: Vec<u8>) -> Self {
-        let token = buffer[0] & 2;
-        let data = if token == 0 {
+    pub fn new(buffer: Vec<u8>) -> Result<Self, CodecError> {
+        Self::with_max_decompressed_size(buffer, constant::MAX_DECOMPRESSED_SIZE)
+    }
+
+    pub fn with_max_decompressed_size(buffer: Vec<u8>, max_size: usize) -> Result<Self, CodecError> {
+        let Some(flag) = buffer.first() else {
+            return Err(CodecError::new(0, CodecErrorKind::EmptyFrame));
+        };
+        let data = if flag & 2 == 0 {
             buffer[1..].to_vec()
         } else {
-            unpack(&buffer[1..])
+            unpack(&buffer[1..], max_size)?
         };
-        Self {
+        Ok(Self {
             reader: Cursor::new(data),
-        }
+            depth: 0,
+        })
     }
 
-    pub fn decode(&mut self) -> Node {
-        let token = self.reader.read_u8().unwrap();
-        let size = self.read_size(token);
+    pub fn decode(&mut self) -> Result<Node, CodecError> {
+        let token = self.read_u8()?;
+        let size = self.read_size(token)?;
         if size == 0 {
-            panic!("Cannot decode with empty body")
+            return Err(self.error(CodecErrorKind::EmptyNode));
         }
 
-        let description = self.read_string();
-        let attrs = self.read_attributes(size);
+        let description = self.read_string()?;
+        let attrs = self.read_attributes(size)?;
 
         let mut node = Node::new(description, attrs, None);
         if size % 2 == 0 {
-            node.content = Some(self.read(false));
+            node.content = Some(self.read(false)?);
         }
-        node
+        Ok(node)
+    }
+
+    fn error(&self, kind: CodecErrorKind) -> CodecError {
+        CodecError::new(self.reader.position() as usize, kind)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, CodecError> {
+        self.reader.read_u8().map_err(|_| self.error(CodecErrorKind::UnexpectedEof))
+    }
+
+    fn read_u16(&mut self) -> Result<u16, CodecError> {
+        self.reader.read_u16::<BigEndian>().map_err(|_| self.error(CodecErrorKind::UnexpectedEof))
     }
 
-    fn read_size(&mut self, token: u8) -> usize {
+    fn read_u32(&mut self) -> Result<u32, CodecError> {
+        self.reader.read_u32::<BigEndian>().map_err(|_| self.error(CodecErrorKind::UnexpectedEof))
+    }
+
+    fn read_size(&mut self, token: u8) -> Result<usize, CodecError> {
         match token {
-            LIST_EMPTY => 0,
-            LIST_8 => self.reader.read_i8().unwrap() as usize,
-            LIST_16 => self.reader.read_i16::<BigEndian>().unwrap() as usize,
-            _ => panic!("Invalid list token"),
+            LIST_EMPTY => Ok(0),
+            LIST_8 => Ok(self.read_u8()? as usize),
+            LIST_16 => Ok(self.read_u16()? as usize),
+            _ => Err(self.error(CodecErrorKind::InvalidListToken(token))),
         }
     }
 
-    fn read_attributes(&mut self, size: usize) -> HashMap<String, Value> {
+    fn read_attributes(&mut self, size: usize) -> Result<HashMap<String, Value>, CodecError> {
         let mut map = HashMap::new();
         for _ in 0..((size - 1) >> 1) {
-            let key = self.read_string();
-            let value = self.read(true);
+            let key = self.read_string()?;
+            let value = self.read(true)?;
             map.insert(key.clone(), value);
         }
-        map
+        Ok(map)
     }
 
-    fn read_string(&mut self) -> String {
-        match self.read(true) {
-            Value::Str(s) => s,
-            Value::Null => "".to_string(),
-            _ => panic!("Expected string"),
+    fn read_string(&mut self) -> Result<String, CodecError> {
+        match self.read(true)? {
+            Value::Str(s) => Ok(s),
+            Value::Null => Ok("".to_string()),
+            _ => Err(self.error(CodecErrorKind::ExpectedString)),
         }
     }
 
-    fn read(&mut self, parse_bytes: bool) -> Value {
-        let tag = self.reader.read_u8().unwrap();
+    fn read(&mut self, parse_bytes: bool) -> Result<Value, CodecError> {
+        let tag = self.read_u8()?;
 
         match tag {
-            LIST_EMPTY => Value::Null,
+            LIST_EMPTY => Ok(Value::Null),
             JID_PAIR => self.read_jid_pair(),
+            AD_JID => self.read_ad_jid(),
+            FB_JID => self.read_fb_jid(),
+            INTEROP_JID => self.read_interop_jid(),
             LIST_8 => {
-                let size = self.reader.read_u8().unwrap() as usize;
+                let size = self.read_u8()? as usize;
                 self.read_list(size)
             }
             LIST_16 => {
-                let size = self.reader.read_u16::<BigEndian>().unwrap() as usize;
+                let size = self.read_u16()? as usize;
                 self.read_list(size)
             }
             BINARY_8 => {
-                let size = self.reader.read_u8().unwrap() as usize;
+                let size = self.read_u8()? as usize;
                 self.read_binary(size, parse_bytes)
             }
             BINARY_20 => {
-                let size = self.read_string20_length();
+                let size = self.read_string20_length()?;
                 self.read_binary(size, parse_bytes)
             }
             BINARY_32 => {
-                let size = self.reader.read_u16::<BigEndian>().unwrap() as usize;
+                let size = self.read_u32()? as usize;
                 self.read_binary(size, parse_bytes)
             }
             NIBBLE_8 => self.read_packed8(tag),
@@ -125,14 +229,14 @@ impl BinaryDecoder {
         }
     }
 
-    fn read_packed8(&mut self, tag: u8) -> Value {
-        let start_byte = self.reader.read_u8().unwrap();
+    fn read_packed8(&mut self, tag: u8) -> Result<Value, CodecError> {
+        let start_byte = self.read_u8()?;
 
         let mut data = Vec::new();
         for _ in 0..(start_byte & 127) {
-            let curr_byte = self.reader.read_u8().unwrap();
-            let lower = self.unpack_byte(tag, (curr_byte & 0xF0) >> 4);
-            let upper = self.unpack_byte(tag, curr_byte & 0x0F);
+            let curr_byte = self.read_u8()?;
+            let lower = self.unpack_byte(tag, (curr_byte & 0xF0) >> 4)?;
+            let upper = self.unpack_byte(tag, curr_byte & 0x0F)?;
             data.push(lower);
             data.push(upper);
         }
@@ -141,87 +245,133 @@ impl BinaryDecoder {
         if start_byte >> 7 != 0 {
             result.pop();
         }
-        Value::Str(result)
+        Ok(Value::Str(result))
     }
 
-    fn unpack_byte(&mut self, tag: u8, value: u8) -> u8 {
+    fn unpack_byte(&mut self, tag: u8, value: u8) -> Result<u8, CodecError> {
         match tag {
             NIBBLE_8 => self.unpack_nibble(value),
             HEX_8 => self.unpack_hex(value),
-            _ => panic!("Invalid tag"),
+            _ => Err(self.error(CodecErrorKind::InvalidToken(tag))),
         }
     }
 
-    fn read_binary(&mut self, size: usize, parse_bytes: bool) -> Value {
+    fn read_binary(&mut self, size: usize, parse_bytes: bool) -> Result<Value, CodecError> {
+        let remaining = self.reader.get_ref().len().saturating_sub(self.reader.position() as usize);
+        if size > remaining {
+            return Err(self.error(CodecErrorKind::UnexpectedEof));
+        }
+
         let mut data = vec![0u8; size];
-        self.reader.read_exact(&mut data).unwrap();
+        self.reader.read_exact(&mut data).map_err(|_| self.error(CodecErrorKind::UnexpectedEof))?;
         if parse_bytes {
-            Value::Str(String::from_utf8_lossy(&data).into_owned())
+            Ok(Value::Str(String::from_utf8_lossy(&data).into_owned()))
         } else {
-            Value::Bytes(data)
+            Ok(Value::Bytes(data))
         }
     }
 
-    fn read_string20_length(&mut self) -> usize {
-        let b1 = self.reader.read_u8().unwrap() as usize;
-        let b2 = self.reader.read_u8().unwrap() as usize;
-        let b3 = self.reader.read_u8().unwrap() as usize;
-        ((b1 & 0x0F) << 16) | (b2 << 8) | b3
+    fn read_string20_length(&mut self) -> Result<usize, CodecError> {
+        let b1 = self.read_u8()? as usize;
+        let b2 = self.read_u8()? as usize;
+        let b3 = self.read_u8()? as usize;
+        Ok(((b1 & 0x0F) << 16) | (b2 << 8) | b3)
     }
 
-    fn read_list(&mut self, size: usize) -> Value {
-        let mut list = Vec::with_capacity(size);
-        for _ in 0..size {
-            list.push(self.decode());
+    fn read_list(&mut self, size: usize) -> Result<Value, CodecError> {
+        // Every level recurses, so a hostile frame could otherwise exhaust the stack
+        if self.depth >= constant::MAX_NODE_DEPTH {
+            return Err(self.error(CodecErrorKind::TooDeep(constant::MAX_NODE_DEPTH)));
         }
-        Value::List(list)
+
+        self.depth += 1;
+        let list = (0..size).map(|_| self.decode()).collect::<Result<Vec<_>, _>>();
+        self.depth -= 1;
+        Ok(Value::List(list?))
     }
 
-    fn read_jid_pair(&mut self) -> Value {
-        let user = self.read_string();
-        let server = self.read_string();
+    fn read_jid_pair(&mut self) -> Result<Value, CodecError> {
+        let user = self.read_string()?;
+        let server = self.read_string()?;
 
-        Value::Jid(JID {
-            user: Some(user),
+        Ok(Value::Jid(JID {
+            user: if user.is_empty() { None } else { Some(user) },
             server: Some(server),
             raw_agent: None,
             device: None,
             integrator: None,
-        })
+        }))
     }
 
-    fn unpack_nibble(&mut self, value: u8) -> u8 {
+    fn read_ad_jid(&mut self) -> Result<Value, CodecError> {
+        let agent = self.read_u8()?;
+        let device = self.read_u8()?;
+        let user = self.read_string()?;
+
+        Ok(Value::Jid(JID::new_ad(user, agent, device)))
+    }
+
+    fn read_fb_jid(&mut self) -> Result<Value, CodecError> {
+        let user = self.read_string()?;
+        let device = self.read_u16()?;
+        let server = self.read_string()?;
+
+        Ok(Value::Jid(JID::new(Some(user), None, Some(device), None, Some(server))))
+    }
+
+    fn read_interop_jid(&mut self) -> Result<Value, CodecError> {
+        let user = self.read_string()?;
+        let device = self.read_u16()?;
+        let integrator = self.read_u16()?;
+        let server = self.read_string()?;
+
+        Ok(Value::Jid(JID::new(Some(user), None, Some(device), Some(integrator), Some(server))))
+    }
+
+    fn unpack_nibble(&mut self, value: u8) -> Result<u8, CodecError> {
         match value {
-            0..=9 => b'0' + value,
-            10 => b'-',
-            11 => b'.',
-            15 => 0,
-            _ => panic!("unpackNibble with value {}", value)
+            0..=9 => Ok(b'0' + value),
+            10 => Ok(b'-'),
+            11 => Ok(b'.'),
+            15 => Ok(0),
+            _ => Err(self.error(CodecErrorKind::InvalidNibble(value)))
         }
     }
 
-    fn unpack_hex(&mut self, value: u8) -> u8 {
+    fn unpack_hex(&mut self, value: u8) -> Result<u8, CodecError> {
         match value {
-            0..=9 => b'0' + value,
-            10..=15 => b'A' + (value - 10),
-            _ => panic!("unpackHex with value {}", value)
+            0..=9 => Ok(b'0' + value),
+            10..=15 => Ok(b'A' + (value - 10)),
+            _ => Err(self.error(CodecErrorKind::InvalidHex(value)))
         }
     }
 
-    fn read_string_from_token(&mut self, tag: u8) -> Value {
+    fn read_string_from_token(&mut self, tag: u8) -> Result<Value, CodecError> {
         if tag < DICTIONARY_0 || tag > DICTIONARY_3 {
-            return Value::Str(SINGLE_BYTE_TOKENS[tag as usize].to_string());
+            return match SINGLE_BYTE_TOKENS.get(tag as usize) {
+                Some(token) => Ok(Value::Str(token.to_string())),
+                None => Err(self.error(CodecErrorKind::InvalidToken(tag))),
+            };
         }
 
-        let i = self.reader.read_u8().unwrap() as usize;
-        Value::Str(DOUBLE_BYTE_TOKENS[tag as usize - DICTIONARY_0 as usize][i].to_string())
+        let i = self.read_u8()? as usize;
+        match DOUBLE_BYTE_TOKENS[tag as usize - DICTIONARY_0 as usize].get(i) {
+            Some(token) => Ok(Value::Str(token.to_string())),
+            None => Err(self.error(CodecErrorKind::InvalidToken(tag))),
+        }
     }
 }
 
 
-pub fn unpack(data: &[u8]) -> Vec<u8> {
-    let mut e = ZlibDecoder::new(data);
-    let mut s = String::new();
-    e.read_to_string(&mut s).unwrap();
-    s.into_bytes()
-}
\ No newline at end of file
+pub fn unpack(data: &[u8], max_size: usize) -> Result<Vec<u8>, CodecError> {
+    let mut inflated = Vec::new();
+    ZlibDecoder::new(data)
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut inflated)
+        .map_err(|err| CodecError::new(0, CodecErrorKind::Decompress(err.to_string())))?;
+
+    if inflated.len() > max_size {
+        return Err(CodecError::new(0, CodecErrorKind::DecompressedTooLarge(max_size)));
+    }
+    Ok(inflated)
+}
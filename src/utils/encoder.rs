@@ -1,26 +1,57 @@
 use std::{collections::HashMap, io::Write};
 
-use byteorder::{BigEndian, LittleEndian, WriteBytesExt};
+use byteorder::{BigEndian, WriteBytesExt};
+use flate2::{write::ZlibEncoder, Compression};
 
-use crate::types::jid::JID;
+use crate::types::jid::{self, JID};
 
-use super::{decoder::{Node, Value}, token};
+use super::{decoder::{CodecError, CodecErrorKind, Node, Value}, token};
 
 pub struct BinaryEncoder {
     buffer: Vec<u8>,
+    compression_threshold: Option<usize>,
 }
 
 impl BinaryEncoder {
     pub fn new() -> Self {
-        BinaryEncoder { buffer: Vec::new() }
+        BinaryEncoder { buffer: Vec::new(), compression_threshold: None }
     }
 
-    pub fn write_node(&mut self, node: &Node) -> Vec<u8> {
+    pub fn with_compression(threshold: usize) -> Self {
+        BinaryEncoder { buffer: Vec::new(), compression_threshold: Some(threshold) }
+    }
+
+    pub fn write_node(&mut self, node: &Node) -> Result<Vec<u8>, CodecError> {
         self.buffer.write_u8(0).unwrap();
+        self.write_inner_node(node)?;
+
+        match self.compression_threshold {
+            Some(threshold) if self.buffer.len() > threshold => Ok(self.compress()),
+            _ => Ok(self.buffer.clone()),
+        }
+    }
+
+    fn compress(&self) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(vec![2], Compression::default());
+        encoder.write_all(&self.buffer[1..]).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        if compressed.len() < self.buffer.len() {
+            compressed
+        } else {
+            self.buffer.clone()
+        }
+    }
+
+    fn error(&self, kind: CodecErrorKind) -> CodecError {
+        CodecError::new(self.buffer.len(), kind)
+    }
+
+    fn write_inner_node(&mut self, node: &Node) -> Result<(), CodecError> {
         if node.tag == "0" {
             self.buffer.write_u8(token::LIST_8).unwrap();
             self.buffer.write_u8(token::LIST_EMPTY).unwrap();
-            return self.buffer.clone();
+            return Ok(());
         }
 
         let has_content = match node.content {
@@ -28,15 +59,14 @@ impl BinaryEncoder {
             None => 0,
         };
 
-        self.write_list_start(2 * self.count_attributes(&node.attributes) + 1 + has_content);
-        self.write_string(node.tag.clone());
-        self.write_attributes(&node.attributes);
+        self.write_list_start(2 * self.count_attributes(&node.attributes) + 1 + has_content)?;
+        self.write_string(node.tag.clone())?;
+        self.write_attributes(&node.attributes)?;
 
         if let Some(content) = &node.content {
-            self.write(content);
+            self.write(content)?;
         }
-
-        self.buffer.clone()
+        Ok(())
     }
     
     fn count_attributes(&self, attributes: &HashMap<String, Value>) -> usize {
@@ -51,7 +81,7 @@ impl BinaryEncoder {
             .count()
     }
 
-    fn write_attributes(&mut self, attributes: &HashMap<String, Value>) {
+    fn write_attributes(&mut self, attributes: &HashMap<String, Value>) -> Result<(), CodecError> {
         for (key, val) in attributes {
             match val {
                 Value::Str(s) if s.is_empty() => continue,
@@ -59,26 +89,42 @@ impl BinaryEncoder {
                 _ => {}
             }
     
-            self.write_string(key.clone());
-            self.write(val);
+            self.write_string(key.clone())?;
+            self.write(val)?;
         }
+        Ok(())
     }
 
-    fn write(&mut self, value: &Value) {
+    fn write(&mut self, value: &Value) -> Result<(), CodecError> {
         match value {
             Value::Str(s) => self.write_string(s.clone()),
-            Value::Jid(jid) => self.write_jid(&jid),
+            Value::Jid(jid) => self.write_jid(jid),
             Value::Bytes(bytes) => self.write_bytes(bytes),
-            _ => panic!("{:?} Not handled", value)
+            Value::List(nodes) => {
+                self.write_list_start(nodes.len())?;
+                for node in nodes {
+                    self.write_inner_node(node)?;
+                }
+                Ok(())
+            }
+            Value::Node(node) => {
+                self.write_list_start(1)?;
+                self.write_inner_node(node)
+            }
+            Value::Null => {
+                self.buffer.write_u8(token::LIST_EMPTY).unwrap();
+                Ok(())
+            }
         }
     }
 
-    fn write_bytes(&mut self, bytes: &[u8]) {
-        self.write_byte_length(bytes.len());
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), CodecError> {
+        self.write_byte_length(bytes.len())?;
         self.buffer.write_all(bytes).unwrap();
+        Ok(())
     }
 
-    fn write_byte_length(&mut self, length: usize) {
+    fn write_byte_length(&mut self, length: usize) -> Result<(), CodecError> {
         if length < 256 {
             self.buffer.write_u8(token::BINARY_8).unwrap();
             self.buffer.write_u8(length as u8).unwrap();
@@ -89,50 +135,51 @@ impl BinaryEncoder {
             self.buffer.write_u8(token::BINARY_32).unwrap();
             self.buffer.write_i32::<BigEndian>(length as i32).unwrap();
         } else {
-            panic!("length is too large: {}", length);
+            return Err(self.error(CodecErrorKind::LengthTooLarge(length)));
         }
+        Ok(())
     }
 
-    fn write_jid(&mut self, jid: &JID) {
+    fn write_jid(&mut self, jid: &JID) -> Result<(), CodecError> {
         let server = jid.server.as_deref().unwrap_or("");
         let user = jid.user.as_deref().unwrap_or("");
         let device = jid.device.unwrap_or(0);
         let integrator = jid.integrator.unwrap_or(0);
-        let raw_agent = jid.raw_agent.unwrap_or(0);
     
-        if (server == "s.whatsapp.net" && device > 0) || 
-           server == "lid" || 
-           server == "hosted" {
+        if (server == jid::DEFAULT_USER_SERVER && device > 0) ||
+           server == jid::HIDDEN_USER_SERVER ||
+           server == jid::HOSTED_SERVER {
             self.buffer.write_u8(token::AD_JID).unwrap();
-            self.buffer.write_u8(raw_agent).unwrap();
+            self.buffer.write_u8(jid.actual_agent()).unwrap();
             self.buffer.write_u8(device as u8).unwrap();
-            self.write_string(user.to_string());
+            self.write_string(user.to_string())?;
         }
-        else if server == "msgr" {
+        else if server == jid::MESSENGER_SERVER {
             self.buffer.write_u8(token::FB_JID).unwrap();
-            self.write_string(user.to_string());
-            self.buffer.write_i16::<LittleEndian>(device as i16).unwrap();
-            self.write_string(server.to_string());
+            self.write_string(user.to_string())?;
+            self.buffer.write_u16::<BigEndian>(device).unwrap();
+            self.write_string(server.to_string())?;
         }
-        else if server == "interop" {
+        else if server == jid::INTEROP_SERVER {
             self.buffer.write_u8(token::INTEROP_JID).unwrap();
-            self.write_string(user.to_string());
-            self.buffer.write_i16::<LittleEndian>(device as i16).unwrap();
-            self.buffer.write_i16::<LittleEndian>(integrator as i16).unwrap();
-            self.write_string(server.to_string());
+            self.write_string(user.to_string())?;
+            self.buffer.write_u16::<BigEndian>(device).unwrap();
+            self.buffer.write_u16::<BigEndian>(integrator).unwrap();
+            self.write_string(server.to_string())?;
         }
         else {
             self.buffer.write_u8(token::JID_PAIR).unwrap();
             if user.is_empty() {
                 self.buffer.write_u8(token::LIST_EMPTY).unwrap();
             } else {
-                self.write_string(user.to_string());
+                self.write_string(user.to_string())?;
             }
-            self.write_string(server.to_string());
+            self.write_string(server.to_string())?;
         }
+        Ok(())
     }
 
-    fn write_string(&mut self, data: String) {
+    fn write_string(&mut self, data: String) -> Result<(), CodecError> {
         match token::SINGLE_BYTE_TOKENS.iter().position(|&s| s == &data) {
             Some(index) => {
                 self.buffer.write_u8(index as u8).unwrap();
@@ -148,17 +195,18 @@ impl BinaryEncoder {
                     }
                     None => {
                         if self.validate_nibble(&data) {
-                            self.write_packed_bytes(&data, token::NIBBLE_8);
+                            self.write_packed_bytes(&data, token::NIBBLE_8)?;
                         } else if self.validate_hex(&data) {
-                            self.write_packed_bytes(&data, token::HEX_8);
+                            self.write_packed_bytes(&data, token::HEX_8)?;
                         } else {
-                            self.write_byte_length(data.len());
+                            self.write_byte_length(data.len())?;
                             self.buffer.write_all(data.as_bytes()).unwrap();
                         }
                     }
                 }
             }
         }
+        Ok(())
     }
 
     fn validate_hex(&self, value: &str) -> bool {
@@ -173,9 +221,9 @@ impl BinaryEncoder {
         true
     }
 
-    fn write_packed_bytes(&mut self, value: &str, data_type: u8) {
+    fn write_packed_bytes(&mut self, value: &str, data_type: u8) -> Result<(), CodecError> {
         if value.len() > 127 {
-            panic!("too many bytes to pack: {}", value.len());
+            return Err(self.error(CodecErrorKind::TooManyBytesToPack(value.len())));
         }
 
         self.buffer.write_u8(data_type).unwrap();
@@ -187,12 +235,12 @@ impl BinaryEncoder {
                 for i in 0..(value.len() / 2) {
                     let first = value.chars().nth(2 * i).unwrap() as u8;
                     let second = value.chars().nth(2 * i + 1).unwrap() as u8;
-                    let packed = (self.pack_nibble(first) << 4) | self.pack_nibble(second);
+                    let packed = (self.pack_nibble(first)? << 4) | self.pack_nibble(second)?;
                     self.buffer.write_u8(packed).unwrap();
                 }
                 if value.len() % 2 != 0 {
                     let last = value.chars().nth(value.len() - 1).unwrap() as u8;
-                    let packed = (self.pack_nibble(last) << 4) | self.pack_nibble(0);
+                    let packed = (self.pack_nibble(last)? << 4) | self.pack_nibble(0)?;
                     self.buffer.write_u8(packed).unwrap();
                 }
             }
@@ -200,18 +248,18 @@ impl BinaryEncoder {
                 for i in 0..(value.len() / 2) {
                     let first = value.chars().nth(2 * i).unwrap() as u8;
                     let second = value.chars().nth(2 * i + 1).unwrap() as u8;
-                    let packed = (self.pack_hex(first) << 4) | self.pack_hex(second);
+                    let packed = (self.pack_hex(first)? << 4) | self.pack_hex(second)?;
                     self.buffer.write_u8(packed).unwrap();
                 }
                 if value.len() % 2 != 0 {
                     let last = value.chars().nth(value.len() - 1).unwrap() as u8;
-                    let packed = (self.pack_hex(last) << 4) | self.pack_hex(0);
+                    let packed = (self.pack_hex(last)? << 4) | self.pack_hex(0)?;
                     self.buffer.write_u8(packed).unwrap();
                 }
             }
-            _ => panic!("Invalid packed bytes type")
+            _ => return Err(self.error(CodecErrorKind::InvalidToken(data_type)))
         }
-        
+        Ok(())
     }
 
     fn validate_nibble(&self, value: &str) -> bool {
@@ -226,34 +274,215 @@ impl BinaryEncoder {
         true
     }
 
-    fn pack_nibble(&self, value: u8) -> u8 {
+    fn pack_nibble(&self, value: u8) -> Result<u8, CodecError> {
         match value {
-            b'-' => 10,
-            b'.' => 11,
-            0 => 15,
-            b'0'..=b'9' => value - b'0',
-            _ => panic!("invalid string to pack as nibble: {} / '{}'", value, value as char)
+            b'-' => Ok(10),
+            b'.' => Ok(11),
+            0 => Ok(15),
+            b'0'..=b'9' => Ok(value - b'0'),
+            _ => Err(self.error(CodecErrorKind::InvalidNibble(value)))
         }
     }
 
-    fn pack_hex(&self, value: u8) -> u8 {
+    fn pack_hex(&self, value: u8) -> Result<u8, CodecError> {
         match value {
-            b'0'..=b'9' => value - b'0',
-            b'A'..=b'F' => 10 + value - b'A',
-            0 => 15,
-            _ => panic!("invalid string to pack as hex: {} / '{}'", value, value as char)
+            b'0'..=b'9' => Ok(value - b'0'),
+            b'A'..=b'F' => Ok(10 + value - b'A'),
+            0 => Ok(15),
+            _ => Err(self.error(CodecErrorKind::InvalidHex(value)))
         }
     }
 
-    fn write_list_start(&mut self, list_size: usize) {
+    fn write_list_start(&mut self, list_size: usize) -> Result<(), CodecError> {
         if list_size == 0 {
             self.buffer.write_u8(token::LIST_EMPTY).unwrap();
         } else if list_size < 256 {
             self.buffer.write_u8(token::LIST_8).unwrap();
-            self.buffer.write_i8(list_size as i8).unwrap();
-        } else {
+            self.buffer.write_u8(list_size as u8).unwrap();
+        } else if list_size <= u16::MAX as usize {
             self.buffer.write_u8(token::LIST_16).unwrap();
-            self.buffer.write_i16::<LittleEndian>(list_size as i16).unwrap();
+            self.buffer.write_u16::<BigEndian>(list_size as u16).unwrap();
+        } else {
+            return Err(self.error(CodecErrorKind::LengthTooLarge(list_size)));
+        }
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::constant;
+    use crate::types::jid::JID;
+    use crate::utils::decoder::{BinaryDecoder, CodecErrorKind, Node, Value};
+
+    use super::{token, BinaryEncoder};
+
+    fn node(tag: &str, attributes: &[(&str, Value)], content: Option<Value>) -> Node {
+        let attributes = attributes.iter()
+            .map(|(key, value)| (key.to_string(), value.clone()))
+            .collect::<HashMap<_, _>>();
+        Node::new(tag.to_string(), attributes, content)
+    }
+
+    fn server_jid() -> Value {
+        Value::Jid(JID::new(None, None, None, None, Some("s.whatsapp.net".into())))
+    }
+
+    fn round_trip(node: &Node) -> Node {
+        let data = BinaryEncoder::new().write_node(node).unwrap();
+        BinaryDecoder::new(data).unwrap().decode().unwrap()
+    }
+
+    #[test]
+    fn round_trips_node_without_content() {
+        let ping = node("iq", &[
+            ("id", Value::Str("12.34-1".into())),
+            ("xmlns", Value::Str("w:p".into())),
+            ("type", Value::Str("get".into())),
+            ("to", server_jid()),
+        ], None);
+
+        assert_eq!(round_trip(&ping), ping);
+    }
+
+    #[test]
+    fn round_trips_usync_query() {
+        let user = JID::new(Some("6281234567890".into()), None, None, None, Some("s.whatsapp.net".into()));
+        let usync = node("iq", &[
+            ("id", Value::Str("12.34-2".into())),
+            ("xmlns", Value::Str("usync".into())),
+            ("type", Value::Str("get".into())),
+            ("to", server_jid()),
+        ], Some(Value::List(vec![
+            node("usync", &[
+                ("sid", Value::Str("12.34-3".into())),
+                ("mode", Value::Str("query".into())),
+                ("last", Value::Str("true".into())),
+                ("index", Value::Str("0".into())),
+                ("context", Value::Str("message".into())),
+            ], Some(Value::List(vec![
+                node("query", &[], Some(Value::List(vec![
+                    node("devices", &[("version", Value::Str("2".into()))], None),
+                ]))),
+                node("list", &[], Some(Value::List(vec![
+                    node("user", &[("jid", Value::Jid(user))], None),
+                ]))),
+            ]))),
+        ])));
+
+        assert_eq!(round_trip(&usync), usync);
+    }
+
+    #[test]
+    fn round_trips_enc_payload() {
+        let message = node("message", &[
+            ("id", Value::Str("3EB0ABCDEF".into())),
+            ("type", Value::Str("text".into())),
+        ], Some(Value::List(vec![
+            node("enc", &[
+                ("v", Value::Str("2".into())),
+                ("type", Value::Str("pkmsg".into())),
+            ], Some(Value::Bytes((0..=255).cycle().take(700).collect()))),
+        ])));
+
+        assert_eq!(round_trip(&message), message);
+    }
+
+    #[test]
+    fn round_trips_long_child_list() {
+        let keys = (0..300)
+            .map(|i| node("key", &[], Some(Value::List(vec![
+                node("id", &[], Some(Value::Bytes(vec![0, (i >> 8) as u8, i as u8]))),
+                node("value", &[], Some(Value::Bytes(vec![i as u8; 32]))),
+            ]))))
+            .collect();
+        let list = node("list", &[], Some(Value::List(keys)));
+
+        assert_eq!(round_trip(&list), list);
+    }
+
+    #[test]
+    fn round_trips_device_jids() {
+        let receipt = node("receipt", &[
+            ("from", Value::Jid(JID::new_ad("6281234567890".into(), 0, 12))),
+            ("participant", Value::Jid(JID::new_ad("123456789012345".into(), 1, 3))),
+            ("recipient", Value::Jid(JID::new_ad("6281234567890".into(), 130, 0))),
+        ], None);
+
+        assert_eq!(round_trip(&receipt), receipt);
+    }
+
+    #[test]
+    fn round_trips_messenger_and_interop_jids() {
+        let messenger = JID::new(Some("100012345678".into()), None, Some(7), None, Some("msgr".into()));
+        let interop = JID::new(Some("5551234".into()), None, Some(2), Some(4), Some("interop".into()));
+        let message = node("message", &[
+            ("from", Value::Jid(messenger)),
+            ("to", Value::Jid(interop)),
+        ], None);
+
+        assert_eq!(round_trip(&message), message);
+    }
+
+    #[test]
+    fn encodes_single_child_as_list() {
+        let child = node("count", &[], None);
+        let parent = node("iq", &[("type", Value::Str("get".into()))], Some(Value::Node(Box::new(child.clone()))));
+
+        let decoded = round_trip(&parent);
+        assert_eq!(decoded.content, Some(Value::List(vec![child])));
+    }
+
+    #[test]
+    fn reports_truncated_frames() {
+        let message = node("message", &[("id", Value::Str("3EB0ABCDEF".into()))], Some(Value::Bytes(vec![1; 64])));
+        let mut data = BinaryEncoder::new().write_node(&message).unwrap();
+        data.truncate(data.len() - 10);
+
+        let error = BinaryDecoder::new(data).unwrap().decode().unwrap_err();
+        assert_eq!(error.kind, CodecErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn round_trips_compressed_binary_payload() {
+        let payload: Vec<u8> = (0..4096).map(|i| (i % 7) as u8 | 0x80).collect();
+        let message = node("message", &[("id", Value::Str("3EB0ABCDEF".into()))], Some(Value::List(vec![
+            node("enc", &[("type", Value::Str("msg".into()))], Some(Value::Bytes(payload))),
+        ])));
+
+        let data = BinaryEncoder::with_compression(256).write_node(&message).unwrap();
+        assert_eq!(data[0] & 2, 2);
+        assert_eq!(BinaryDecoder::new(data).unwrap().decode().unwrap(), message);
+    }
+
+    #[test]
+    fn rejects_oversized_decompressed_frames() {
+        let message = node("message", &[], Some(Value::Bytes(vec![0; 8192])));
+        let data = BinaryEncoder::with_compression(0).write_node(&message).unwrap();
+
+        let error = BinaryDecoder::with_max_decompressed_size(data, 1024).err().unwrap();
+        assert_eq!(error.kind, CodecErrorKind::DecompressedTooLarge(1024));
+    }
+
+    #[test]
+    fn rejects_deeply_nested_frames() {
+        let nest = |depth: usize| (0..depth).fold(node("leaf", &[], None), |child, _| {
+            node("n", &[], Some(Value::List(vec![child])))
+        });
+        let nested = nest(constant::MAX_NODE_DEPTH);
+        assert_eq!(round_trip(&nested), nested);
+
+        let data = BinaryEncoder::new().write_node(&nest(constant::MAX_NODE_DEPTH + 1)).unwrap();
+        let error = BinaryDecoder::new(data).unwrap().decode().unwrap_err();
+        assert_eq!(error.kind, CodecErrorKind::TooDeep(constant::MAX_NODE_DEPTH));
+
+        // A few bytes per level used to be enough to overflow the stack
+        let mut data = vec![0];
+        for _ in 0..100_000 {
+            data.extend([token::LIST_8, 2, token::LIST_8, 1]);
         }
+        let error = BinaryDecoder::new(data).unwrap().decode().unwrap_err();
+        assert_eq!(error.kind, CodecErrorKind::TooDeep(constant::MAX_NODE_DEPTH));
     }
-}
\ No newline at end of file
+}
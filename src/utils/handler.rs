@@ -1,49 +1,226 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
 
 use base64::{engine::general_purpose, Engine};
+use hmac::{Hmac, Mac};
+use paris::{error, info};
+use prost::Message;
+use sha2::Sha256;
 
 use crate::client::Client;
+use crate::constant;
+use crate::events::Event;
+use crate::proto::whatsapp::{AdvDeviceIdentity, AdvEncryptionType, AdvSignedDeviceIdentity, AdvSignedDeviceIdentityHmac};
+use crate::request::{IqError, SendError};
+use crate::store::StoreError;
+use crate::types::jid::{self, JID};
 
 use super::decoder::{Node, Value};
+use super::key;
+
+#[derive(Debug)]
+pub enum PairError {
+    Malformed(String),
+    InvalidHmac,
+    InvalidAccountSignature,
+    Store(StoreError),
+    Send(SendError),
+    Iq(IqError),
+}
+
+impl PairError {
+    fn code(&self) -> (u16, &'static str) {
+        match self {
+            PairError::InvalidHmac | PairError::InvalidAccountSignature => (401, "not-authorized"),
+            _ => (500, "internal-error"),
+        }
+    }
+}
+
+impl fmt::Display for PairError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PairError::Malformed(e) => write!(f, "malformed pair-success: {}", e),
+            PairError::InvalidHmac => write!(f, "device identity hmac mismatch"),
+            PairError::InvalidAccountSignature => write!(f, "invalid account signature"),
+            PairError::Store(e) => write!(f, "failed to store device: {}", e),
+            PairError::Send(e) => write!(f, "failed to send pair-device-sign: {}", e),
+            PairError::Iq(e) => write!(f, "link code request failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PairError {}
 
 impl Client {
     pub async fn handle_qr(&mut self, node: &Node) {
-        let child = match node.content.as_ref() {
-            None => return,
-            Some(Value::List(content)) => content,
-            Some(_) => return
-        };
-        match child[0].tag.as_str() {
+        let Some(child) = node.children().first() else { return };
+        match child.tag.as_str() {
             "pair-device" => {
-                let Value::List(pair_device) = child[0].content.as_ref().unwrap() else { return };
+                let (Some(from), Some(id)) = (node.attributes.get("from"), node.attributes.get("id")) else {
+                    error!("pair-device without from or id: {}", node.to_xml());
+                    return;
+                };
+                let pair_device = child.children();
                 let mut pair_attr = HashMap::new();
 
-                pair_attr.insert("to".to_string(), node.attributes.get("from").unwrap().clone());
-                pair_attr.insert("id".to_string(), node.attributes.get("id").unwrap().clone());
+                pair_attr.insert("to".to_string(), from.clone());
+                pair_attr.insert("id".to_string(), id.clone());
                 pair_attr.insert("type".to_string(), Value::Str("result".to_string()));
 
                 let pair_node = Node::new("iq".to_string(), pair_attr, None);
-                self.send_node_and_get_data(pair_node).await;
+                if let Err(e) = self.send_node(pair_node).await {
+                    error!("Failed to send pair-device response: {}", e);
+                    return;
+                }
 
                 let mut codes = Vec::new();
                 for node in pair_device {
                     if node.tag != "ref" {
                         continue;
                     }
-                    let Value::Bytes(bytes) = node.content.as_ref().unwrap() else { return };
-                    let data = self.make_qr_data(String::from_utf8(bytes.to_vec()).unwrap());
+                    let Some(Value::Bytes(bytes)) = node.content.as_ref() else { continue };
+                    let data = self.make_qr_data(String::from_utf8_lossy(bytes).into_owned());
                     codes.push(data);
                 }
-                if let Some(handle) = &self.handle {
-                    handle.on_qr(&codes[0]);
-                }
+                self.start_qr_rotation(codes);
             }
             "pair-success" => {
-
+                let id = node.get_attr("id").unwrap_or_default().to_string();
+                if let Err(e) = self.handle_pair_success(&id, child).await {
+                    error!("Pairing failed: {}", e);
+                    if !matches!(e, PairError::Send(_)) {
+                        let (code, text) = e.code();
+                        self.send_pair_error(&id, code, text).await;
+                    }
+                    self.dispatch_event(Event::PairError(Arc::new(e)));
+                }
             }
-            _ => {
-                panic!("Unknown node: {}", child[0].tag);
+            _ => error!("Unknown pairing node: {}", child.tag)
+        }
+    }
+
+    fn start_qr_rotation(&mut self, codes: Vec<String>) {
+        if let Some(task) = self.qr_task.take() {
+            task.abort();
+        }
+
+        let client = self.this.clone();
+        let events = self.event_bus.sender();
+        let timeouts = [self.qr_first_timeout, self.qr_timeout];
+        self.qr_task = Some(tokio::spawn(async move {
+            for (i, code) in codes.into_iter().enumerate() {
+                let _ = events.send(Event::Qr { code });
+                tokio::time::sleep(timeouts[i.min(1)]).await;
             }
+
+            let Some(client) = client.upgrade() else { return };
+            let mut client = client.lock().await;
+            info!("QR codes exhausted, closing connection");
+            // Detach first so disconnect doesn't abort this task
+            client.qr_task.take();
+            client.dispatch_event(Event::PairTimeout);
+            client.disconnect().await;
+        }));
+    }
+
+    async fn handle_pair_success(&mut self, id: &str, node: &Node) -> Result<(), PairError> {
+        let Some(Value::Bytes(device_identity)) = node.get_child("device-identity").and_then(|child| child.content.as_ref()) else {
+            return Err(PairError::Malformed("missing device-identity".into()));
+        };
+        let jid = node.get_child("device")
+            .and_then(|child| child.get_jid_attr("jid"))
+            .cloned()
+            .ok_or_else(|| PairError::Malformed("missing device jid".into()))?;
+        let business_name = node.get_child("biz").and_then(|child| child.get_attr("name")).unwrap_or_default().to_string();
+        let platform = node.get_child("platform").and_then(|child| child.get_attr("name")).unwrap_or_default().to_string();
+
+        if let Some(task) = self.qr_task.take() {
+            task.abort();
+        }
+
+        let container = AdvSignedDeviceIdentityHmac::decode(&device_identity[..]).map_err(|e| PairError::Malformed(e.to_string()))?;
+        let is_hosted = container.account_type == Some(AdvEncryptionType::Hosted as i32);
+        let details = container.details.unwrap_or_default();
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.device.adv_secret_key).expect("HMAC accepts any key length");
+        if is_hosted {
+            mac.update(&constant::ADV_HOSTED_PREFIX_ACCOUNT_SIGNATURE);
+        }
+        mac.update(&details);
+        mac.verify_slice(container.hmac.as_deref().unwrap_or_default()).map_err(|_| PairError::InvalidHmac)?;
+
+        let mut identity = AdvSignedDeviceIdentity::decode(&details[..]).map_err(|e| PairError::Malformed(e.to_string()))?;
+        let identity_details = identity.details.clone().unwrap_or_default();
+        let account_signature_key: [u8; 32] = identity.account_signature_key.as_deref()
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(|| PairError::Malformed("invalid account signature key".into()))?;
+        let account_signature: [u8; 64] = identity.account_signature.as_deref()
+            .and_then(|signature| signature.try_into().ok())
+            .ok_or_else(|| PairError::Malformed("invalid account signature".into()))?;
+        let identity_public = self.device.identity_key.public.as_bytes();
+
+        let prefix = match is_hosted {
+            true => constant::ADV_HOSTED_PREFIX_ACCOUNT_SIGNATURE,
+            false => constant::ADV_PREFIX_ACCOUNT_SIGNATURE,
+        };
+        let message = [&prefix[..], &identity_details, identity_public].concat();
+        if !key::verify_signature(&account_signature_key, &message, &account_signature) {
+            return Err(PairError::InvalidAccountSignature);
+        }
+
+        let prefix = match is_hosted {
+            true => constant::ADV_HOSTED_PREFIX_DEVICE_SIGNATURE,
+            false => constant::ADV_PREFIX_DEVICE_SIGNATURE,
+        };
+        let message = [&prefix[..], &identity_details, identity_public, &account_signature_key].concat();
+        identity.device_signature = Some(self.device.identity_key.sign_message(&message).to_vec());
+
+        let key_index = AdvDeviceIdentity::decode(&identity_details[..])
+            .map_err(|e| PairError::Malformed(e.to_string()))?
+            .key_index
+            .unwrap_or(0);
+
+        self.device.jid = Some(jid.clone());
+        self.device.business_name = business_name.clone();
+        self.device.platform = platform.clone();
+        self.device.account = Some(identity.clone());
+        self.store.save(&self.device).map_err(PairError::Store)?;
+
+        let self_signed_identity = AdvSignedDeviceIdentity {
+            account_signature_key: None,
+            ..identity
+        };
+        let mut identity_attr = HashMap::new();
+        identity_attr.insert("key-index".to_string(), Value::Str(key_index.to_string()));
+        let device_identity = Node::new("device-identity".to_string(), identity_attr, Some(Value::Bytes(self_signed_identity.encode_to_vec())));
+        let pair_device_sign = Node::new("pair-device-sign".to_string(), HashMap::new(), Some(Value::List(vec![device_identity])));
+
+        let mut sign_attr = HashMap::new();
+        sign_attr.insert("to".to_string(), Value::Jid(JID::new(None, None, None, None, Some(jid::DEFAULT_USER_SERVER.into()))));
+        sign_attr.insert("id".to_string(), Value::Str(id.to_string()));
+        sign_attr.insert("type".to_string(), Value::Str("result".to_string()));
+        self.send_node(Node::new("iq".to_string(), sign_attr, Some(Value::List(vec![pair_device_sign])))).await.map_err(PairError::Send)?;
+
+        info!("Paired as {} on {}", jid, platform);
+        self.dispatch_event(Event::PairSuccess { jid, business_name, platform });
+        Ok(())
+    }
+
+    async fn send_pair_error(&mut self, id: &str, code: u16, text: &str) {
+        let mut error_attr = HashMap::new();
+        error_attr.insert("code".to_string(), Value::Str(code.to_string()));
+        error_attr.insert("text".to_string(), Value::Str(text.to_string()));
+
+        let mut iq_attr = HashMap::new();
+        iq_attr.insert("to".to_string(), Value::Jid(JID::new(None, None, None, None, Some(jid::DEFAULT_USER_SERVER.into()))));
+        iq_attr.insert("id".to_string(), Value::Str(id.to_string()));
+        iq_attr.insert("type".to_string(), Value::Str("error".to_string()));
+
+        let error_node = Node::new("error".to_string(), error_attr, None);
+        if let Err(e) = self.send_node(Node::new("iq".to_string(), iq_attr, Some(Value::List(vec![error_node])))).await {
+            error!("Failed to send pair error: {}", e);
         }
     }
 
@@ -53,4 +230,131 @@ impl Client {
         let adv = general_purpose::STANDARD.encode(self.device.adv_secret_key);
         format!("{},{},{},{}", data, noise, identity, adv)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use ed25519_dalek::SigningKey;
+    use prost::Message;
+
+    use crate::client::{Client, Config};
+    use crate::constant;
+    use crate::events::Event;
+    use crate::proto::whatsapp::AdvSignedDeviceIdentity;
+    use crate::store::device::{DeviceStore, memory::MemoryDeviceStore};
+    use crate::testing::mock_server::{account_public, MockServer, server_jid, signed_device_identity};
+    use crate::testing::next_event;
+    use crate::types::jid::JID;
+    use crate::utils::decoder::Value;
+    use crate::utils::key::verify_signature;
+
+    #[tokio::test]
+    async fn acknowledges_pair_device_and_emits_qr() {
+        let mut server = MockServer::start().await;
+        let client = Client::new(server.config()).unwrap();
+        let mut events = client.lock().await.subscribe();
+        let (connected, mut conn) = tokio::join!(Client::connect(&client), server.accept());
+        connected.unwrap();
+
+        conn.send_pair_device("pair-1", &["ref-1", "ref-2"]).await;
+
+        let ack = conn.expect_node("iq").await;
+        assert_eq!(ack.get_attr("id"), Some("pair-1"));
+        assert_eq!(ack.get_attr("type"), Some("result"));
+        assert_eq!(ack.attributes.get("to"), Some(&server_jid()));
+        assert!(matches!(next_event(&mut events).await, Event::Qr { code } if code.starts_with("ref-1,")));
+    }
+
+    #[tokio::test]
+    async fn rotates_qr_codes_until_timeout() {
+        let mut server = MockServer::start().await;
+        let config = Config {
+            qr_first_timeout: Duration::from_millis(60),
+            qr_timeout: Duration::from_millis(20),
+            ..server.config()
+        };
+        let client = Client::new(config).unwrap();
+        let mut events = client.lock().await.subscribe();
+        let (connected, mut conn) = tokio::join!(Client::connect(&client), server.accept());
+        connected.unwrap();
+
+        conn.send_pair_device("pair-1", &["ref-1", "ref-2", "ref-3"]).await;
+
+        for expected in ["ref-1,", "ref-2,", "ref-3,"] {
+            assert!(matches!(next_event(&mut events).await, Event::Qr { code } if code.starts_with(expected)));
+        }
+        assert!(matches!(next_event(&mut events).await, Event::PairTimeout));
+        while conn.receive_node().await.is_some() {}
+        assert!(client.lock().await.write.is_none());
+    }
+
+    #[tokio::test]
+    async fn signs_and_stores_pair_success() {
+        let mut server = MockServer::start().await;
+        let store = MemoryDeviceStore::default();
+        let client = Client::new(server.config_with_store(store.clone())).unwrap();
+        let mut events = client.lock().await.subscribe();
+        let (connected, mut conn) = tokio::join!(Client::connect(&client), server.accept());
+        connected.unwrap();
+
+        let account_key = SigningKey::from_bytes(&[7; 32]);
+        let (identity_public, adv_secret_key) = {
+            let client = client.lock().await;
+            (*client.device.identity_key.public.as_bytes(), client.device.adv_secret_key)
+        };
+        let device_identity = signed_device_identity(&account_key, &identity_public, &adv_secret_key, 3);
+        let jid = JID::new_ad("6281234567890".into(), 0, 12);
+        conn.send_pair_success("pair-2", device_identity, jid.clone(), "Rusty Inc", "smba").await;
+
+        let response = conn.expect_node("iq").await;
+        assert_eq!(response.get_attr("id"), Some("pair-2"));
+        assert_eq!(response.get_attr("type"), Some("result"));
+        let signed = response.get_child("pair-device-sign").and_then(|sign| sign.get_child("device-identity")).unwrap();
+        assert_eq!(signed.get_attr("key-index"), Some("3"));
+        let Some(Value::Bytes(signed)) = &signed.content else { panic!("device-identity without content") };
+        let signed = AdvSignedDeviceIdentity::decode(&signed[..]).unwrap();
+        assert!(signed.account_signature_key.is_none());
+        let device_signature: [u8; 64] = signed.device_signature.unwrap().try_into().unwrap();
+        let message = [
+            &constant::ADV_PREFIX_DEVICE_SIGNATURE[..],
+            signed.details.as_deref().unwrap(),
+            &identity_public,
+            &account_public(&account_key),
+        ].concat();
+        assert!(verify_signature(&identity_public, &message, &device_signature));
+
+        assert!(matches!(
+            next_event(&mut events).await,
+            Event::PairSuccess { jid: paired, business_name, platform } if paired == jid && business_name == "Rusty Inc" && platform == "smba"
+        ));
+        let stored = store.load().unwrap().unwrap();
+        assert_eq!(stored.jid.map(|jid| jid.to_string()), Some(jid.to_string()));
+        assert_eq!(stored.business_name, "Rusty Inc");
+        assert_eq!(stored.platform, "smba");
+        assert_eq!(stored.account.and_then(|account| account.account_signature_key), Some(account_public(&account_key).to_vec()));
+    }
+
+    #[tokio::test]
+    async fn rejects_pair_success_with_foreign_secret() {
+        let mut server = MockServer::start().await;
+        let store = MemoryDeviceStore::default();
+        let client = Client::new(server.config_with_store(store.clone())).unwrap();
+        let mut events = client.lock().await.subscribe();
+        let (connected, mut conn) = tokio::join!(Client::connect(&client), server.accept());
+        connected.unwrap();
+
+        let identity_public = *client.lock().await.device.identity_key.public.as_bytes();
+        let device_identity = signed_device_identity(&SigningKey::from_bytes(&[7; 32]), &identity_public, &[9; 32], 1);
+        conn.send_pair_success("pair-3", device_identity, JID::new_ad("6281234567890".into(), 0, 12), "", "smba").await;
+
+        let response = conn.expect_node("iq").await;
+        assert_eq!(response.get_attr("id"), Some("pair-3"));
+        assert_eq!(response.get_attr("type"), Some("error"));
+        assert_eq!(response.get_child("error").and_then(|error| error.get_attr("code")), Some("401"));
+        assert!(matches!(next_event(&mut events).await, Event::PairError(_)));
+        assert!(store.load().unwrap().is_none());
+        assert!(client.lock().await.device.jid.is_none());
+    }
+}
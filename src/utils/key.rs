@@ -1,5 +1,7 @@
-use ed25519_dalek::{Signer, SigningKey};
-use rand_core::OsRng;
+use curve25519_dalek::edwards::EdwardsPoint;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use curve25519_dalek::scalar::{clamp_integer, Scalar};
+use rand_core::{OsRng, RngCore};
 use sha2::{Sha512, Digest};
 use x25519_dalek::{PublicKey, StaticSecret};
 
@@ -26,6 +28,16 @@ impl Key {
         }
     }
 
+    pub fn from_private(private: [u8; 32]) -> Self {
+        let private = StaticSecret::from(private);
+        let public = PublicKey::from(&private);
+
+        Self {
+            public,
+            private,
+        }
+    }
+
     pub fn create_signed_pre_key(&self, key_id: u32) -> PreKey {
         let mut new_key = PreKey::new(key_id);
 
@@ -34,17 +46,60 @@ impl Key {
     }
 
     pub fn sign(&self, key_to_sign: &Key) -> [u8; 64] {
-        let hash = Sha512::digest(self.private.as_bytes());
-        let mut seed = [0u8; 32];
-        seed.copy_from_slice(&hash[..32]);
-        let mut signing_key = SigningKey::from(seed);
-
         let mut pub_key_for_signature = [0u8; 33];
         pub_key_for_signature[0] = 0x05; // DJB_TYPE
         pub_key_for_signature[1..].copy_from_slice(key_to_sign.public.as_bytes());
 
-        signing_key.sign(&pub_key_for_signature).to_bytes()
+        self.sign_message(&pub_key_for_signature)
     }
+
+    /// XEdDSA as libsignal does it, the sign bit of the Edwards form of
+    /// our key travels in the top bit of the signature.
+    pub fn sign_message(&self, message: &[u8]) -> [u8; 64] {
+        let mut random = [0u8; 64];
+        OsRng.fill_bytes(&mut random);
+        self.sign_message_with_random(message, &random)
+    }
+
+    fn sign_message_with_random(&self, message: &[u8], random: &[u8; 64]) -> [u8; 64] {
+        let private = clamp_integer(self.private.to_bytes());
+        let a = Scalar::from_bytes_mod_order(private);
+        let public = EdwardsPoint::mul_base(&a).compress();
+
+        let mut prefix = [0xff; 32];
+        prefix[0] = 0xfe;
+        let r = hash_to_scalar(&[&prefix, &private, message, random]);
+        let cap_r = EdwardsPoint::mul_base(&r).compress();
+        let h = hash_to_scalar(&[cap_r.as_bytes(), public.as_bytes(), message]);
+        let s = h * a + r;
+
+        let mut signature = [0u8; 64];
+        signature[..32].copy_from_slice(cap_r.as_bytes());
+        signature[32..].copy_from_slice(s.as_bytes());
+        signature[63] |= public.as_bytes()[31] & 0x80;
+        signature
+    }
+}
+
+/// Verifies an XEdDSA signature made with a Curve25519 key, the sign bit of
+/// the Edwards form of the key is carried in the top bit of the signature.
+pub fn verify_signature(public_key: &[u8; 32], message: &[u8], signature: &[u8; 64]) -> bool {
+    let Some(public) = MontgomeryPoint(*public_key).to_edwards(signature[63] >> 7) else { return false };
+    let mut s: [u8; 32] = signature[32..].try_into().unwrap();
+    s[31] &= 0x7f;
+    let Some(s) = Option::<Scalar>::from(Scalar::from_canonical_bytes(s)) else { return false };
+
+    let h = hash_to_scalar(&[&signature[..32], public.compress().as_bytes(), message]);
+    let cap_r = EdwardsPoint::vartime_double_scalar_mul_basepoint(&h, &-public, &s);
+    cap_r.compress().as_bytes() == &signature[..32]
+}
+
+fn hash_to_scalar(parts: &[&[u8]]) -> Scalar {
+    let mut hasher = Sha512::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    Scalar::from_bytes_mod_order_wide(&hasher.finalize().into())
 }
 
 impl PreKey {
@@ -55,4 +110,72 @@ impl PreKey {
             signature: [0u8; 64]
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+    use super::{verify_signature, Key};
+
+    // From libsignal's curve25519 tests
+    const ALICE_IDENTITY_PRIVATE: [u8; 32] = [
+        0xc0, 0x97, 0x24, 0x84, 0x12, 0xe5, 0x8b, 0xf0, 0x5d, 0xf4, 0x87, 0x96, 0x82, 0x05, 0x13, 0x27,
+        0x94, 0x17, 0x8e, 0x36, 0x76, 0x37, 0xf5, 0x81, 0x8f, 0x81, 0xe0, 0xe6, 0xce, 0x73, 0xe8, 0x65,
+    ];
+    const ALICE_IDENTITY_PUBLIC: [u8; 32] = [
+        0xab, 0x7e, 0x71, 0x7d, 0x4a, 0x16, 0x3b, 0x7d, 0x9a, 0x1d, 0x80, 0x71, 0xdf, 0xe9, 0xdc, 0xf8,
+        0xcd, 0xcd, 0x1c, 0xea, 0x33, 0x39, 0xb6, 0x35, 0x6b, 0xe8, 0x4d, 0x88, 0x7e, 0x32, 0x2c, 0x64,
+    ];
+    const ALICE_EPHEMERAL_PUBLIC: [u8; 33] = [
+        0x05, 0xed, 0xce, 0x9d, 0x9c, 0x41, 0x5c, 0xa7, 0x8c, 0xb7, 0x25, 0x2e, 0x72, 0xc2, 0xc4, 0xa5,
+        0x54, 0xd3, 0xeb, 0x29, 0x48, 0x5a, 0x0e, 0x1d, 0x50, 0x31, 0x18, 0xd1, 0xa8, 0x2d, 0x99, 0xfb, 0x4a,
+    ];
+    const ALICE_SIGNATURE: [u8; 64] = [
+        0x5d, 0xe8, 0x8c, 0xa9, 0xa8, 0x9b, 0x4a, 0x11, 0x5d, 0xa7, 0x91, 0x09, 0xc6, 0x7c, 0x9c, 0x74,
+        0x64, 0xa3, 0xe4, 0x18, 0x02, 0x74, 0xf1, 0xcb, 0x8c, 0x63, 0xc2, 0x98, 0x4e, 0x28, 0x6d, 0xfb,
+        0xed, 0xe8, 0x2d, 0xeb, 0x9d, 0xcd, 0x9f, 0xae, 0x0b, 0xfb, 0xb8, 0x21, 0x56, 0x9b, 0x3d, 0x90,
+        0x01, 0xbd, 0x81, 0x30, 0xcd, 0x11, 0xd4, 0x86, 0xce, 0xf0, 0x47, 0xbd, 0x60, 0xb8, 0x6e, 0x88,
+    ];
+
+    #[test]
+    fn verifies_libsignal_vector() {
+        assert_eq!(Key::from_private(ALICE_IDENTITY_PRIVATE).public.as_bytes(), &ALICE_IDENTITY_PUBLIC);
+        assert!(verify_signature(&ALICE_IDENTITY_PUBLIC, &ALICE_EPHEMERAL_PUBLIC, &ALICE_SIGNATURE));
+
+        for i in 0..ALICE_SIGNATURE.len() {
+            let mut signature = ALICE_SIGNATURE;
+            signature[i] ^= 0x01;
+            assert!(!verify_signature(&ALICE_IDENTITY_PUBLIC, &ALICE_EPHEMERAL_PUBLIC, &signature));
+        }
+    }
+
+    #[test]
+    fn signs_verifiable_messages() {
+        let key = Key::from_private(ALICE_IDENTITY_PRIVATE);
+        let signature = key.sign_message_with_random(b"message", &[7; 64]);
+        assert_eq!(signature, key.sign_message_with_random(b"message", &[7; 64]));
+        assert_ne!(signature, key.sign_message_with_random(b"message", &[8; 64]));
+        assert!(verify_signature(&ALICE_IDENTITY_PUBLIC, b"message", &signature));
+        assert!(!verify_signature(&ALICE_IDENTITY_PUBLIC, b"massage", &signature));
+        assert!(!verify_signature(Key::new().public.as_bytes(), b"message", &signature));
+
+        // Without the sign bit it is a plain Ed25519 signature by the Edwards key
+        let edwards = curve25519_dalek::montgomery::MontgomeryPoint(ALICE_IDENTITY_PUBLIC)
+            .to_edwards(signature[63] >> 7)
+            .unwrap();
+        let mut ed_signature = signature;
+        ed_signature[63] &= 0x7f;
+        let verifying_key = VerifyingKey::from_bytes(&edwards.compress().to_bytes()).unwrap();
+        assert!(verifying_key.verify(b"message", &Signature::from_bytes(&ed_signature)).is_ok());
+    }
+
+    #[test]
+    fn signs_pre_keys_over_the_djb_encoding() {
+        let identity = Key::new();
+        for _ in 0..8 {
+            let pre_key = identity.create_signed_pre_key(1);
+            let message = [&[0x05][..], pre_key.key.public.as_bytes()].concat();
+            assert!(verify_signature(identity.public.as_bytes(), &message, &pre_key.signature));
+        }
+    }
+}
@@ -1,8 +1,11 @@
 pub mod key;
 pub mod noise_handshake;
 pub mod gcm;
+pub mod aes_cbc;
 pub mod decoder;
 pub mod encoder;
 mod token;
 mod xml;
-mod handler;
\ No newline at end of file
+pub mod handler;
+pub mod pair_code;
+pub mod qr;
\ No newline at end of file
@@ -1,11 +1,51 @@
+use std::fmt;
+use std::time::Duration;
+
 use aes_gcm::{Aes256Gcm, Nonce};
 use aes_gcm::aead::{Aead, Payload};
 use hkdf::Hkdf;
+use prost::Message;
 use sha2::{Digest, Sha256};
 use x25519_dalek::{PublicKey, StaticSecret};
 use crate::constant;
+use crate::proto::whatsapp::cert_chain::noise_certificate::Details;
+use crate::proto::whatsapp::cert_chain::NoiseCertificate;
+use crate::proto::whatsapp::CertChain;
 use crate::socket::noise_socket::NoiseSocket;
+use crate::socket::transport::TransportError;
 use crate::utils::gcm;
+use crate::utils::key;
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    Transport(TransportError),
+    Malformed(String),
+    Decrypt,
+    InvalidCertificate(String),
+    StaticKeyMismatch,
+    Timeout(Duration),
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::Transport(e) => write!(f, "{}", e),
+            HandshakeError::Malformed(e) => write!(f, "malformed server hello: {}", e),
+            HandshakeError::Decrypt => write!(f, "failed to decrypt server hello"),
+            HandshakeError::InvalidCertificate(e) => write!(f, "invalid server certificate: {}", e),
+            HandshakeError::StaticKeyMismatch => write!(f, "server static key does not match its certificate"),
+            HandshakeError::Timeout(timeout) => write!(f, "server didn't finish the handshake within {:?}", timeout),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+impl From<TransportError> for HandshakeError {
+    fn from(e: TransportError) -> Self {
+        HandshakeError::Transport(e)
+    }
+}
 
 #[derive(Default)]
 pub struct NoiseHandShake {
@@ -41,16 +81,16 @@ impl NoiseHandShake {
         self.mix_into_key(shared_secret.as_bytes());
     }
 
-    pub fn decrypt(&mut self, cipher: &[u8]) -> Vec<u8> {
+    pub fn decrypt(&mut self, cipher: &[u8]) -> Result<Vec<u8>, aes_gcm::Error> {
         self.counter += 1;
         let iv = NoiseSocket::generate_iv(self.counter - 1);
         let nonce = Nonce::from_slice(&iv);
         let plaintext = self.key.as_ref().unwrap().decrypt(nonce, Payload {
             msg: cipher,
             aad: &self.hash,
-        }).expect("Failed to decrypt");
+        })?;
         self.authenticate(&cipher.to_vec());
-        plaintext
+        Ok(plaintext)
     }
 
     pub fn encrypt(&mut self, plaintext: &[u8]) -> Vec<u8> {
@@ -87,4 +127,99 @@ impl NoiseHandShake {
         let (write, read) = okm.split_at(32);
         (write.to_vec(), read.to_vec())
     }
-}
\ No newline at end of file
+}
+/// Checks that the root key signed the intermediate certificate, the
+/// intermediate signed the leaf, and the leaf is the key the server used.
+pub fn verify_server_cert(certificate: &[u8], static_key: &[u8], root_key: &[u8; 32]) -> Result<(), HandshakeError> {
+    let chain = CertChain::decode(certificate)
+        .map_err(|e| HandshakeError::InvalidCertificate(e.to_string()))?;
+    let intermediate = verify_certificate(chain.intermediate.as_ref(), root_key, "intermediate")?;
+    if intermediate.issuer_serial() != constant::CERT_ISSUER_SERIAL {
+        return Err(HandshakeError::InvalidCertificate(format!("unexpected intermediate issuer {}", intermediate.issuer_serial())));
+    }
+    let intermediate_key: [u8; 32] = intermediate.key().try_into()
+        .map_err(|_| HandshakeError::InvalidCertificate("invalid intermediate key".into()))?;
+
+    let leaf = verify_certificate(chain.leaf.as_ref(), &intermediate_key, "leaf")?;
+    if leaf.issuer_serial() != intermediate.serial() {
+        return Err(HandshakeError::InvalidCertificate(format!("leaf issuer {} is not intermediate {}", leaf.issuer_serial(), intermediate.serial())));
+    }
+    if leaf.key() != static_key {
+        return Err(HandshakeError::StaticKeyMismatch);
+    }
+    Ok(())
+}
+
+fn verify_certificate(certificate: Option<&NoiseCertificate>, issuer_key: &[u8; 32], name: &str) -> Result<Details, HandshakeError> {
+    let certificate = certificate
+        .ok_or_else(|| HandshakeError::InvalidCertificate(format!("missing {} certificate", name)))?;
+    let details = certificate.details();
+    let signature: &[u8; 64] = certificate.signature().try_into()
+        .map_err(|_| HandshakeError::InvalidCertificate(format!("invalid {} signature length", name)))?;
+    // Signed with XEdDSA, the pinned root key is a Curve25519 key that doesn't decode as an Ed25519 point
+    if !key::verify_signature(issuer_key, details, signature) {
+        return Err(HandshakeError::InvalidCertificate(format!("bad {} signature", name)));
+    }
+    Details::decode(details).map_err(|e| HandshakeError::InvalidCertificate(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use curve25519_dalek::MontgomeryPoint;
+    use ed25519_dalek::{SigningKey, VerifyingKey};
+
+    use crate::client::Client;
+    use crate::connection::ConnectionState;
+    use crate::constant;
+    use crate::testing::mock_server::{account_public, cert_chain, MockServer};
+    use crate::utils::key::Key;
+
+    use super::{HandshakeError, verify_server_cert};
+
+    #[test]
+    fn pinned_root_key_is_a_curve25519_key() {
+        assert!(VerifyingKey::from_bytes(&constant::CERT_ROOT_KEY).is_err());
+        assert!(MontgomeryPoint(constant::CERT_ROOT_KEY).to_edwards(0).is_some());
+    }
+
+    #[test]
+    fn verifies_chains_against_the_curve25519_root_key() {
+        let (root_key, intermediate_key) = (SigningKey::from_bytes(&[1; 32]), SigningKey::from_bytes(&[2; 32]));
+        let static_key = Key::new();
+        let certificate = cert_chain(&root_key, &intermediate_key, static_key.public.as_bytes());
+
+        assert!(verify_server_cert(&certificate, static_key.public.as_bytes(), &account_public(&root_key)).is_ok());
+        assert!(matches!(
+            verify_server_cert(&certificate, static_key.public.as_bytes(), &root_key.verifying_key().to_bytes()),
+            Err(HandshakeError::InvalidCertificate(_))
+        ));
+        assert!(matches!(
+            verify_server_cert(&certificate, Key::new().public.as_bytes(), &account_public(&root_key)),
+            Err(HandshakeError::StaticKeyMismatch)
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_untrusted_server_certificates() {
+        let mut server = MockServer::start().await;
+        let static_public = *server.static_key.public.as_bytes();
+        let intermediate_key = SigningKey::from_bytes(&[2; 32]);
+        let certificates = [
+            (cert_chain(&SigningKey::from_bytes(&[9; 32]), &intermediate_key, &static_public), false),
+            (cert_chain(&server.root_key, &intermediate_key, Key::new().public.as_bytes()), true),
+            (Vec::new(), false),
+        ];
+
+        for (certificate, key_mismatch) in certificates {
+            let client = Client::new(server.config()).unwrap();
+            let (connected, conn) = tokio::join!(Client::connect(&client), server.accept_with_certificate(certificate));
+            assert!(conn.is_none());
+            match connected {
+                Err(HandshakeError::InvalidCertificate(_)) if !key_mismatch => {}
+                Err(HandshakeError::StaticKeyMismatch) if key_mismatch => {}
+                other => panic!("unexpected handshake result: {:?}", other),
+            }
+            assert_eq!(client.lock().await.state, ConnectionState::Disconnected);
+        }
+    }
+}
@@ -0,0 +1,301 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use aes::Aes256;
+use aes_gcm::aead::Aead;
+use aes_gcm::Nonce;
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use hkdf::Hkdf;
+use paris::{error, info};
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+use tokio::sync::Mutex;
+use x25519_dalek::PublicKey;
+
+use crate::client::Client;
+use crate::constant;
+use crate::events::Event;
+use crate::request::InfoQuery;
+use crate::types::jid::{self, JID};
+
+use super::decoder::{Node, Value};
+use super::gcm;
+use super::handler::PairError;
+use super::key::Key;
+
+type Aes256Ctr = ctr::Ctr128BE<Aes256>;
+
+const LINKING_ALPHABET: &[u8; 32] = b"123456789ABCDEFGHJKLMNPQRSTVWXYZ";
+const LINKING_KEY_ITERATIONS: u32 = 2 << 16;
+
+pub struct PhoneLinking {
+    pub jid: JID,
+    pub key: Key,
+    pub code: String,
+    pub pairing_ref: Vec<u8>,
+}
+
+impl Client {
+    /// Requests a link code for `phone`, to be entered on the phone under
+    /// "Link with phone number". Call it once the first QR code was emitted.
+    pub async fn pair_phone(client: &Arc<Mutex<Client>>, phone: &str, show_push_notification: bool) -> Result<String, PairError> {
+        let key = Key::new();
+        let mut random = [0u8; 5];
+        OsRng.fill_bytes(&mut random);
+        let code = encode_linking_code(&random);
+
+        let phone = phone.chars().filter(char::is_ascii_digit).collect::<String>();
+        let jid = JID::new(Some(phone), None, None, None, Some(jid::DEFAULT_USER_SERVER.into()));
+
+        let response = {
+            let mut client = client.lock().await;
+            if let Some(task) = client.qr_task.take() {
+                task.abort();
+            }
+
+            let content = vec![
+                child("link_code_pairing_wrapped_companion_ephemeral_pub", Value::Bytes(wrap_ephemeral_key(&code, key.public.as_bytes()))),
+                child("companion_server_auth_key_pub", Value::Bytes(client.device.noise_key.public.as_bytes().to_vec())),
+                child("companion_platform_id", Value::Str(constant::PAIR_CLIENT_PLATFORM_ID.into())),
+                child("companion_platform_display", Value::Str(constant::PAIR_CLIENT_DISPLAY_NAME.into())),
+                child("link_code_pairing_nonce", Value::Bytes(vec![0])),
+            ];
+            let mut attr = HashMap::new();
+            attr.insert("jid".to_string(), Value::Jid(jid.clone()));
+            attr.insert("stage".to_string(), Value::Str("companion_hello".to_string()));
+            attr.insert("should_show_push_notification".to_string(), Value::Str(show_push_notification.to_string()));
+
+            client.send_iq(link_code_query(Node::new("link_code_companion_reg".to_string(), attr, Some(Value::List(content))))).await
+        };
+        let response = response.await.map_err(PairError::Iq)?;
+
+        let pairing_ref = response.get_child("link_code_companion_reg")
+            .and_then(|reg| child_bytes(reg, "link_code_pairing_ref"))
+            .ok_or_else(|| PairError::Malformed("missing link_code_pairing_ref".into()))?
+            .to_vec();
+
+        let display_code = format!("{}-{}", &code[..4], &code[4..]);
+        let mut client = client.lock().await;
+        client.phone_linking = Some(PhoneLinking { jid, key, code, pairing_ref });
+        info!("Requested link code {}", display_code);
+        client.dispatch_event(Event::PairCode { code: display_code.clone() });
+        Ok(display_code)
+    }
+
+    pub async fn handle_link_code_notification(&mut self, node: &Node) {
+        if let Err(e) = self.finish_phone_pairing(node).await {
+            error!("Phone pairing failed: {}", e);
+            self.dispatch_event(Event::PairError(Arc::new(e)));
+        }
+    }
+
+    async fn finish_phone_pairing(&mut self, node: &Node) -> Result<(), PairError> {
+        let reg = node.get_child("link_code_companion_reg")
+            .ok_or_else(|| PairError::Malformed("missing link_code_companion_reg".into()))?;
+        let pairing_ref = child_bytes(reg, "link_code_pairing_ref")
+            .ok_or_else(|| PairError::Malformed("missing link_code_pairing_ref".into()))?;
+        match &self.phone_linking {
+            None => return Err(PairError::Malformed("no phone pairing in progress".into())),
+            Some(linking) if linking.pairing_ref != pairing_ref => return Err(PairError::Malformed("pairing ref mismatch".into())),
+            Some(_) => {}
+        }
+        let wrapped_primary_ephemeral = child_bytes(reg, "link_code_pairing_wrapped_primary_ephemeral_pub")
+            .ok_or_else(|| PairError::Malformed("missing wrapped primary ephemeral key".into()))?;
+        let primary_identity: [u8; 32] = child_bytes(reg, "primary_identity_pub")
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(|| PairError::Malformed("invalid primary identity key".into()))?;
+
+        let linking = self.phone_linking.take().unwrap();
+        let primary_ephemeral = unwrap_ephemeral_key(&linking.code, wrapped_primary_ephemeral)
+            .ok_or_else(|| PairError::Malformed("invalid wrapped primary ephemeral key".into()))?;
+        let ephemeral_shared = linking.key.private.diffie_hellman(&PublicKey::from(primary_ephemeral));
+
+        let mut adv_secret_random = [0u8; 32];
+        let mut bundle_salt = [0u8; 32];
+        let mut bundle_nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut adv_secret_random);
+        OsRng.fill_bytes(&mut bundle_salt);
+        OsRng.fill_bytes(&mut bundle_nonce);
+
+        let identity_public = self.device.identity_key.public.as_bytes();
+        let bundle_key = hkdf_sha256(ephemeral_shared.as_bytes(), Some(&bundle_salt), b"link_code_pairing_key_bundle_encryption_key");
+        let bundle = [&identity_public[..], &primary_identity, &adv_secret_random].concat();
+        let encrypted_bundle = gcm::prepare(bundle_key.to_vec())
+            .encrypt(Nonce::from_slice(&bundle_nonce), &bundle[..])
+            .map_err(|e| PairError::Malformed(e.to_string()))?;
+        let wrapped_bundle = [&bundle_salt[..], &bundle_nonce, &encrypted_bundle].concat();
+
+        let identity_shared = self.device.identity_key.private.diffie_hellman(&PublicKey::from(primary_identity));
+        let adv_secret_input = [ephemeral_shared.as_bytes(), identity_shared.as_bytes(), &adv_secret_random[..]].concat();
+        self.device.adv_secret_key = hkdf_sha256(&adv_secret_input, None, b"adv_secret");
+
+        let content = vec![
+            child("link_code_pairing_wrapped_key_bundle", Value::Bytes(wrapped_bundle)),
+            child("companion_identity_public", Value::Bytes(identity_public.to_vec())),
+            child("link_code_pairing_ref", Value::Bytes(linking.pairing_ref)),
+        ];
+        let mut attr = HashMap::new();
+        attr.insert("jid".to_string(), Value::Jid(linking.jid));
+        attr.insert("stage".to_string(), Value::Str("companion_finish".to_string()));
+
+        // The answer arrives through the read loop we are running on
+        let response = self.send_iq(link_code_query(Node::new("link_code_companion_reg".to_string(), attr, Some(Value::List(content))))).await;
+        tokio::spawn(async move {
+            if let Err(e) = response.await {
+                error!("Failed to finish phone pairing: {}", e);
+            }
+        });
+        Ok(())
+    }
+}
+
+pub fn encode_linking_code(data: &[u8; 5]) -> String {
+    let bits = data.iter().fold(0u64, |bits, byte| bits << 8 | *byte as u64);
+    (0..8).rev()
+        .map(|i| LINKING_ALPHABET[(bits >> (i * 5) & 0x1f) as usize] as char)
+        .collect()
+}
+
+/// Encrypts an ephemeral public key with the link code, the result is
+/// salt (32) || iv (16) || encrypted key (32).
+pub fn wrap_ephemeral_key(code: &str, public_key: &[u8; 32]) -> Vec<u8> {
+    let mut salt = [0u8; 32];
+    let mut iv = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    OsRng.fill_bytes(&mut iv);
+
+    let mut encrypted = *public_key;
+    link_code_cipher(code, &salt, &iv).apply_keystream(&mut encrypted);
+    [&salt[..], &iv, &encrypted].concat()
+}
+
+pub fn unwrap_ephemeral_key(code: &str, wrapped: &[u8]) -> Option<[u8; 32]> {
+    if wrapped.len() != 80 {
+        return None;
+    }
+    let mut public_key: [u8; 32] = wrapped[48..].try_into().ok()?;
+    link_code_cipher(code, &wrapped[..32], &wrapped[32..48]).apply_keystream(&mut public_key);
+    Some(public_key)
+}
+
+pub fn hkdf_sha256(secret: &[u8], salt: Option<&[u8]>, info: &[u8]) -> [u8; 32] {
+    let mut output = [0u8; 32];
+    Hkdf::<Sha256>::new(salt, secret)
+        .expand(info, &mut output)
+        .expect("32 bytes is a valid HKDF output length");
+    output
+}
+
+fn link_code_cipher(code: &str, salt: &[u8], iv: &[u8]) -> Aes256Ctr {
+    let key = pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(code.as_bytes(), salt, LINKING_KEY_ITERATIONS);
+    Aes256Ctr::new(&key.into(), iv.into())
+}
+
+fn link_code_query(reg: Node) -> InfoQuery {
+    InfoQuery {
+        namespace: Some("md".into()),
+        r#type: Some("set".into()),
+        to: Some(JID::new(None, None, None, None, Some(jid::DEFAULT_USER_SERVER.into()))),
+        content: Some(Value::List(vec![reg])),
+        ..Default::default()
+    }
+}
+
+fn child(tag: &str, content: Value) -> Node {
+    Node::new(tag.to_string(), HashMap::new(), Some(content))
+}
+
+fn child_bytes<'a>(node: &'a Node, tag: &str) -> Option<&'a [u8]> {
+    match node.get_child(tag)?.content.as_ref()? {
+        Value::Bytes(bytes) => Some(bytes),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use aes_gcm::Nonce;
+    use aes_gcm::aead::Aead;
+    use ed25519_dalek::SigningKey;
+    use x25519_dalek::PublicKey;
+
+    use crate::client::Client;
+    use crate::events::Event;
+    use crate::testing::mock_server::{child_bytes, MockServer, node, server_jid, signed_device_identity};
+    use crate::testing::next_event;
+    use crate::types::jid::JID;
+    use crate::utils::decoder::Value;
+    use crate::utils::gcm;
+    use crate::utils::key::Key;
+
+    use super::{hkdf_sha256, unwrap_ephemeral_key, wrap_ephemeral_key};
+
+    #[tokio::test]
+    async fn pairs_with_phone_number() {
+        let mut server = MockServer::start().await;
+        let client = Client::new(server.config()).unwrap();
+        let mut events = client.lock().await.subscribe();
+        let (connected, mut conn) = tokio::join!(Client::connect(&client), server.accept());
+        connected.unwrap();
+        let identity_public = *client.lock().await.device.identity_key.public.as_bytes();
+
+        let pairing = tokio::spawn({
+            let client = Arc::clone(&client);
+            async move { Client::pair_phone(&client, "+62 812-3456-7890", true).await }
+        });
+        let hello = conn.expect_node("iq").await;
+        let reg = hello.get_child("link_code_companion_reg").unwrap();
+        assert_eq!(reg.get_attr("stage"), Some("companion_hello"));
+        assert_eq!(reg.get_jid_attr("jid").map(|jid| jid.to_string()), Some("6281234567890@s.whatsapp.net".into()));
+        let wrapped_companion_ephemeral = child_bytes(reg, "link_code_pairing_wrapped_companion_ephemeral_pub");
+        conn.send_iq_result(&hello, Some(Value::List(vec![node("link_code_companion_reg", &[], Some(Value::List(vec![
+            node("link_code_pairing_ref", &[], Some(Value::Bytes(b"pairing-ref".to_vec()))),
+        ])))]))).await;
+
+        let code = pairing.await.unwrap().unwrap();
+        assert_eq!(code.len(), 9);
+        assert!(matches!(next_event(&mut events).await, Event::PairCode { code: emitted } if emitted == code));
+        let code = code.replace('-', "");
+        let companion_ephemeral = unwrap_ephemeral_key(&code, &wrapped_companion_ephemeral).unwrap();
+
+        let phone_ephemeral = Key::new();
+        let phone_identity = Key::new();
+        conn.send_node(&node("notification", &[
+            ("id", Value::Str("notification-1".into())),
+            ("from", server_jid()),
+            ("type", Value::Str("link_code_companion_reg".into())),
+        ], Some(Value::List(vec![node("link_code_companion_reg", &[], Some(Value::List(vec![
+            node("link_code_pairing_ref", &[], Some(Value::Bytes(b"pairing-ref".to_vec()))),
+            node("link_code_pairing_wrapped_primary_ephemeral_pub", &[], Some(Value::Bytes(wrap_ephemeral_key(&code, phone_ephemeral.public.as_bytes())))),
+            node("primary_identity_pub", &[], Some(Value::Bytes(phone_identity.public.as_bytes().to_vec()))),
+        ])))])))).await;
+
+        let finish = conn.expect_node("iq").await;
+        let reg = finish.get_child("link_code_companion_reg").unwrap();
+        assert_eq!(reg.get_attr("stage"), Some("companion_finish"));
+        assert_eq!(child_bytes(reg, "companion_identity_public"), identity_public);
+        let wrapped_bundle = child_bytes(reg, "link_code_pairing_wrapped_key_bundle");
+        let ephemeral_shared = phone_ephemeral.private.diffie_hellman(&PublicKey::from(companion_ephemeral));
+        let bundle_key = hkdf_sha256(ephemeral_shared.as_bytes(), Some(&wrapped_bundle[..32]), b"link_code_pairing_key_bundle_encryption_key");
+        let bundle = gcm::prepare(bundle_key.to_vec())
+            .decrypt(Nonce::from_slice(&wrapped_bundle[32..44]), &wrapped_bundle[44..])
+            .unwrap();
+        assert_eq!(bundle[..32], identity_public);
+        assert_eq!(&bundle[32..64], phone_identity.public.as_bytes());
+        let identity_shared = phone_identity.private.diffie_hellman(&PublicKey::from(identity_public));
+        let adv_secret_key = hkdf_sha256(&[ephemeral_shared.as_bytes(), identity_shared.as_bytes(), &bundle[64..]].concat(), None, b"adv_secret");
+        conn.send_iq_result(&finish, None).await;
+
+        let account_key = SigningKey::from_bytes(&[7; 32]);
+        let device_identity = signed_device_identity(&account_key, &identity_public, &adv_secret_key, 1);
+        let jid = JID::new_ad("6281234567890".into(), 0, 12);
+        conn.send_pair_success("pair-4", device_identity, jid.clone(), "", "smba").await;
+
+        let response = conn.expect_node("iq").await;
+        assert_eq!(response.get_attr("type"), Some("result"));
+        assert!(response.get_child("pair-device-sign").is_some());
+        assert!(matches!(next_event(&mut events).await, Event::PairSuccess { jid: paired, .. } if paired == jid));
+    }
+}
@@ -0,0 +1,81 @@
+use std::fmt;
+use std::io::Cursor;
+
+use image::{ImageFormat, Luma};
+use qrcode::render::{svg, unicode};
+use qrcode::types::QrError;
+use qrcode::QrCode;
+
+const MODULE_SIZE: u32 = 8;
+
+#[derive(Debug)]
+pub enum QrRenderError {
+    Encode(QrError),
+    Image(String),
+}
+
+impl fmt::Display for QrRenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QrRenderError::Encode(e) => write!(f, "failed to encode QR code: {}", e),
+            QrRenderError::Image(e) => write!(f, "failed to write QR image: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for QrRenderError {}
+
+/// Renders two modules per character cell with Unicode half blocks,
+/// light on dark so it scans on dark terminal themes.
+pub fn render_terminal(data: &str) -> Result<String, QrRenderError> {
+    Ok(encode(data)?
+        .render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        .quiet_zone(true)
+        .build())
+}
+
+pub fn render_svg(data: &str) -> Result<String, QrRenderError> {
+    Ok(encode(data)?
+        .render::<svg::Color>()
+        .module_dimensions(MODULE_SIZE, MODULE_SIZE)
+        .build())
+}
+
+pub fn render_png(data: &str) -> Result<Vec<u8>, QrRenderError> {
+    let image = encode(data)?
+        .render::<Luma<u8>>()
+        .module_dimensions(MODULE_SIZE, MODULE_SIZE)
+        .build();
+
+    let mut png = Cursor::new(Vec::new());
+    image.write_to(&mut png, ImageFormat::Png).map_err(|e| QrRenderError::Image(e.to_string()))?;
+    Ok(png.into_inner())
+}
+
+fn encode(data: &str) -> Result<QrCode, QrRenderError> {
+    QrCode::new(data.as_bytes()).map_err(QrRenderError::Encode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render_png, render_svg, render_terminal};
+
+    const QR: &str = "2@ref,bm9pc2U=,aWRlbnRpdHk=,YWR2";
+
+    #[test]
+    fn renders_terminal_rows_of_equal_width() {
+        let rendered = render_terminal(QR).unwrap();
+        let widths = rendered.lines().map(|line| line.chars().count()).collect::<Vec<_>>();
+        assert!(widths.len() > 10);
+        assert!(widths.iter().all(|width| *width == widths[0]));
+        assert!(rendered.chars().all(|c| matches!(c, ' ' | '█' | '▀' | '▄' | '\n')));
+    }
+
+    #[test]
+    fn renders_svg_and_png() {
+        assert!(render_svg(QR).unwrap().contains("<svg"));
+        assert!(render_png(QR).unwrap().starts_with(b"\x89PNG\r\n\x1a\n"));
+    }
+}
@@ -59,11 +59,7 @@ impl Node {
     fn value_as_string(v: &Value) -> String {
         match v {
             Value::Str(s) => s.clone(),
-            Value::Jid(jid) => {
-                let user = jid.user.as_deref().unwrap_or("");
-                let server = jid.server.as_deref().unwrap_or("");
-                format!("{}@{}", user, server)
-            }
+            Value::Jid(jid) => jid.to_string(),
             Value::Bytes(b) => {
                 if let Ok(s) = std::str::from_utf8(b) {
                     if s.chars().all(|c| c.is_ascii_graphic() || c.is_ascii_whitespace()) {